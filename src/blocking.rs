@@ -0,0 +1,156 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::pending;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::net::TcpStream;
+use tokio::sync::oneshot::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+use crate::{propagate, replica_tasks, BlockRequest, BlockedPop, Propagation, RedisCommand};
+
+lazy_static! {
+    static ref BLOCKED: Mutex<BlockedClients> = Mutex::new(BlockedClients::default());
+}
+
+static NEXT_WAITER_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Default)]
+struct BlockedClients {
+    // Key to the waiters blocked on it, in the order they blocked
+    by_key: HashMap<Vec<u8>, VecDeque<usize>>,
+    waiters: HashMap<usize, Waiter>,
+}
+
+struct Waiter {
+    keys: Vec<Vec<u8>>,
+    operation: BlockedPop,
+    sender: Sender<RedisCommand>,
+}
+
+impl BlockedClients {
+    fn remove(&mut self, id: usize) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in waiter.keys.iter() {
+            if let Some(queue) = self.by_key.get_mut(key) {
+                queue.retain(|waiting| *waiting != id);
+                if queue.is_empty() {
+                    self.by_key.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+}
+
+// A client parked by a blocking command, handle_client waits on it before replying
+#[derive(Debug)]
+pub struct BlockedClient {
+    id: usize,
+    receiver: Receiver<RedisCommand>,
+    deadline: Option<Instant>,
+    timeout_reply: Box<RedisCommand>,
+}
+
+pub async fn block_client(block_request: BlockRequest) -> RedisCommand {
+    let id = NEXT_WAITER_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = channel();
+    let timeout_reply = Box::new(block_request.operation.timeout_reply());
+    let mut blocked = BLOCKED.lock().await;
+    for key in block_request.keys.iter() {
+        let queue = blocked.by_key.entry(key.clone()).or_default();
+        // BLPOP k k waits once on k
+        if !queue.contains(&id) {
+            queue.push_back(id);
+        }
+    }
+    blocked.waiters.insert(id, Waiter {keys: block_request.keys, operation: block_request.operation, sender});
+    RedisCommand::Block(BlockedClient {
+        id,
+        receiver,
+        // A deadline past what Instant can represent is as good as blocking forever
+        deadline: block_request.timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+        timeout_reply,
+    })
+}
+
+// Hands the elements pushed to keys with waiters to the clients blocked the longest.
+// Runs under the propagation lock after every write, the pops reach the replicas right after that write
+pub async fn serve_blocked_clients() {
+    let mut blocked = BLOCKED.lock().await;
+    // A served BLMOVE may feed another key with waiters
+    let mut served_any = true;
+    while served_any {
+        served_any = false;
+        let keys: Vec<Vec<u8>> = blocked.by_key.keys().cloned().collect();
+        for key in keys {
+            let waiting: Vec<usize> = blocked.by_key.get(&key).into_iter().flatten().copied().collect();
+            for id in waiting {
+                let Some(waiter) = blocked.waiters.get(&id) else {
+                    continue;
+                };
+                // The client left, its pop would lose the element
+                if waiter.sender.is_closed() {
+                    blocked.remove(id);
+                    continue;
+                }
+                // Waiters for another type keep waiting, like a BLPOP on a key holding a sorted set
+                let operation = waiter.operation.clone();
+                if !operation.can_pop_from(&key).await {
+                    continue;
+                }
+                let mut propagation = Propagation::default();
+                let Some(redis_command) = operation.pop(&key, &mut propagation).await else {
+                    continue;
+                };
+                propagate(replica_tasks(Some(&redis_command), propagation, &[])).await;
+                if let Some(waiter) = blocked.remove(id) {
+                    let _ = waiter.sender.send(redis_command);
+                }
+                served_any = true;
+            }
+        }
+    }
+}
+
+// Waits for the blocked client to be served. None if the client closed the connection meanwhile
+pub async fn wait_until_served(blocked_client: BlockedClient, stream: &TcpStream) -> Option<RedisCommand> {
+    let BlockedClient {id, mut receiver, deadline, timeout_reply} = blocked_client;
+    let timed_out = tokio::select! {
+        served = &mut receiver => return served.ok(),
+        _ = sleep_until_deadline(deadline) => true,
+        _ = client_closed(stream) => false,
+    };
+    BLOCKED.lock().await.remove(id);
+    // Served right before it was removed
+    match receiver.try_recv() {
+        Ok(served) if timed_out => Some(served),
+        _ if timed_out => Some(*timeout_reply),
+        _ => None,
+    }
+}
+
+async fn sleep_until_deadline(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => pending().await,
+    }
+}
+
+async fn client_closed(stream: &TcpStream) {
+    let mut byte = [0; 1];
+    match stream.peek(&mut byte).await {
+        Ok(0) | Err(_) => (),
+        // Pipelined commands wait for the reply, closing can't be noticed past them
+        Ok(_) => pending().await,
+    }
+}
+
+// A master turned replica can't pop for its clients anymore, they are released with an error
+pub async fn unblock_all_clients() {
+    let mut blocked = BLOCKED.lock().await;
+    blocked.by_key.clear();
+    for (_, waiter) in blocked.waiters.drain() {
+        let _ = waiter.sender.send(RedisCommand::Error(String::from("UNBLOCKED force unblock from blocking operation, instance state changed (master -> replica?)")));
+    }
+}
@@ -0,0 +1,226 @@
+use std::vec::IntoIter;
+
+use crate::{glob_match, parse_vec_u8, RedisCommand, RespDatatype};
+
+// Reply and argument helpers shared by the data type commands
+pub const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+pub const SYNTAX_ERROR: &str = "ERR syntax error";
+
+pub fn wrong_arguments(command: &[u8]) -> Option<RedisCommand> {
+    Some(RedisCommand::Error(format!("ERR wrong number of arguments for '{}' command", String::from_utf8_lossy(command).to_ascii_lowercase())))
+}
+
+pub fn error(message: impl ToString) -> Option<RedisCommand> {
+    Some(RedisCommand::Error(message.to_string()))
+}
+
+pub fn integer(integer: usize) -> Option<RedisCommand> {
+    Some(RedisCommand::RespDatatype(RespDatatype::Integer(integer as i64)))
+}
+
+pub fn bulk_or_null(element: Option<Vec<u8>>) -> Option<RedisCommand> {
+    match element {
+        Some(element) => Some(RedisCommand::BulkString(element)),
+        None => Some(RedisCommand::NullBulkString),
+    }
+}
+
+pub fn array(elements: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    Some(RedisCommand::RespDatatype(RespDatatype::Array(elements.into_iter().map(RespDatatype::BulkString).collect())))
+}
+
+pub fn bulk_arguments(array_iterator: IntoIter<RespDatatype>) -> Vec<Vec<u8>> {
+    array_iterator
+        .filter_map(|argument| match argument {
+            RespDatatype::BulkString(argument) => Some(argument),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn parse_integer(argument: &[u8]) -> Result<i64, &'static str> {
+    parse_vec_u8::<i64>(argument.to_vec()).map_err(|_| NOT_AN_INTEGER)
+}
+
+// Members a negative HRANDFIELD, SRANDMEMBER or ZRANDMEMBER count may pick, their reply is built whole under the database lock
+const MAX_RANDOM_REPEATS: u64 = 1 << 24;
+
+// The count of HRANDFIELD and friends. Like redis, counts past LONG_MAX / 2 either way are out of range
+pub fn parse_random_count(argument: &[u8]) -> Result<i64, &'static str> {
+    let count = parse_integer(argument)?;
+    if count.unsigned_abs() > i64::MAX as u64 / 2 || (count < 0 && count.unsigned_abs() > MAX_RANDOM_REPEATS) {
+        return Err("ERR value is out of range");
+    }
+    Ok(count)
+}
+
+// Resolves an inclusive start..stop range the way LRANGE, LTRIM and ZRANGE do, None if it selects nothing
+pub fn index_range(start: i64, stop: i64, length: usize) -> Option<(usize, usize)> {
+    let length = length as i64;
+    let start = if start < 0 {(start + length).max(0)} else {start};
+    let stop = if stop < 0 {stop + length} else {stop.min(length - 1)};
+    if start > stop || start >= length {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+pub struct ScanOptions<'a> {
+    pub pattern: Option<&'a [u8]>,
+    pub count: usize,
+    // HSCAN's NOVALUES, only the field names are returned
+    pub no_values: bool,
+}
+
+// [MATCH pattern] [COUNT count], plus NOVALUES where the command takes it
+pub fn parse_scan_options(mut options: &[Vec<u8>], allow_no_values: bool) -> Result<ScanOptions<'_>, &'static str> {
+    let mut scan_options = ScanOptions {pattern: None, count: 10, no_values: false};
+    while !options.is_empty() {
+        options = match options {
+            [option, rest @ ..] if allow_no_values && option.eq_ignore_ascii_case(b"NOVALUES") => {
+                scan_options.no_values = true;
+                rest
+            },
+            [option, pattern, rest @ ..] if option.eq_ignore_ascii_case(b"MATCH") => {
+                scan_options.pattern = Some(pattern);
+                rest
+            },
+            [option, count, rest @ ..] if option.eq_ignore_ascii_case(b"COUNT") => {
+                scan_options.count = match parse_integer(count)? {
+                    count if count >= 1 => count as usize,
+                    _ => return Err(SYNTAX_ERROR),
+                };
+                rest
+            },
+            _ => return Err(SYNTAX_ERROR),
+        };
+    }
+    Ok(scan_options)
+}
+
+pub fn parse_cursor(cursor: &[u8]) -> Result<u64, &'static str> {
+    String::from_utf8_lossy(cursor).parse::<u64>().map_err(|_| "ERR invalid cursor")
+}
+
+// FNV-1a of the member, never 0 since that cursor ends the scan
+fn scan_position(member: &[u8]) -> u64 {
+    let hash = member.iter().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3));
+    hash.max(1)
+}
+
+// One page of a *SCAN over a collection, with the cursor to continue from, 0 once done.
+// The cursor is the position of the next member when members are ordered by a hash of their name,
+// so members present for the whole scan are returned once however the collection changes in between.
+// Only the page is sorted, the members left for later pages are just split off
+pub fn scan_page<'a, T>(members: impl Iterator<Item = (&'a [u8], T)>, cursor: u64, scan_options: &ScanOptions) -> (u64, Vec<(&'a [u8], T)>) {
+    let mut members: Vec<(u64, &[u8], T)> = members
+        .map(|(member, item)| (scan_position(member), member, item))
+        .filter(|(position, _, _)| *position >= cursor)
+        .collect();
+    let count = scan_options.count;
+    let next_cursor = if members.len() > count {
+        members.select_nth_unstable_by_key(count, |(position, _, _)| *position);
+        let boundary = members[count].0;
+        if members[..count].iter().any(|(position, _, _)| *position == boundary) {
+            // Members sharing a position are returned together, the cursor can't point between them
+            let next_cursor = members[count..].iter().map(|(position, _, _)| *position).filter(|position| *position > boundary).min();
+            members.retain(|(position, _, _)| *position <= boundary);
+            next_cursor.unwrap_or(0)
+        } else {
+            members.truncate(count);
+            boundary
+        }
+    } else {
+        0
+    };
+    members.sort_unstable_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    let page = members.into_iter()
+        .filter(|(_, member, _)| scan_options.pattern.is_none_or(|pattern| glob_match(pattern, member)))
+        .map(|(_, member, item)| (member, item))
+        .collect();
+    (next_cursor, page)
+}
+
+pub fn scan_reply(next_cursor: u64, elements: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    Some(RedisCommand::RespDatatype(RespDatatype::Array(vec![
+        RespDatatype::BulkString(next_cursor.to_string().into_bytes()),
+        RespDatatype::Array(elements.into_iter().map(RespDatatype::BulkString).collect()),
+    ])))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn scan_all(members: &[Vec<u8>], count: usize, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        let scan_options = ScanOptions {pattern, count, no_values: false};
+        let mut cursor = 0;
+        let mut scanned = Vec::new();
+        loop {
+            let (next_cursor, page) = scan_page(members.iter().map(|member| (&member[..], ())), cursor, &scan_options);
+            scanned.extend(page.into_iter().map(|(member, _)| member.to_vec()));
+            if next_cursor == 0 {
+                return scanned;
+            }
+            cursor = next_cursor;
+        }
+    }
+
+    #[test]
+    fn scan_page_returns_every_member_once() {
+        let members: Vec<Vec<u8>> = (0..100).map(|i| format!("member:{i}").into_bytes()).collect();
+        let scanned = scan_all(&members, 7, None);
+        assert_eq!(scanned.len(), members.len());
+        assert_eq!(scanned.into_iter().collect::<HashSet<_>>(), members.into_iter().collect());
+    }
+
+    #[test]
+    fn scan_page_filters_with_the_pattern() {
+        let members: Vec<Vec<u8>> = (0..30).map(|i| format!("{}:{i}", if i % 3 == 0 {"a"} else {"b"}).into_bytes()).collect();
+        let scanned = scan_all(&members, 4, Some(b"a:*"));
+        assert_eq!(scanned.len(), 10);
+        assert!(scanned.iter().all(|member| member.starts_with(b"a:")));
+    }
+
+    #[test]
+    fn scan_page_keeps_its_place_when_members_change() {
+        let mut members: Vec<Vec<u8>> = (0..50).map(|i| format!("{i}").into_bytes()).collect();
+        let scan_options = ScanOptions {pattern: None, count: 10, no_values: false};
+        let (cursor, first_page) = scan_page(members.iter().map(|member| (&member[..], ())), 0, &scan_options);
+        let first_page: Vec<Vec<u8>> = first_page.into_iter().map(|(member, _)| member.to_vec()).collect();
+        // Removing scanned members and adding new ones doesn't bring scanned members back
+        members.retain(|member| !first_page.contains(member));
+        members.extend((50..80).map(|i| format!("{i}").into_bytes()));
+        let (_, second_page) = scan_page(members.iter().map(|member| (&member[..], ())), cursor, &scan_options);
+        assert!(second_page.iter().all(|(member, _)| !first_page.iter().any(|scanned| scanned == member)));
+    }
+
+    #[test]
+    fn scan_page_returns_members_sharing_a_position_together() {
+        let members: Vec<Vec<u8>> = (0..20).map(|i| format!("{i}").into_bytes()).collect();
+        // Every member shares its position with its copy, a page never ends between the two
+        let doubled: Vec<&[u8]> = members.iter().flat_map(|member| [&member[..], &member[..]]).collect();
+        let scan_options = ScanOptions {pattern: None, count: 3, no_values: false};
+        let (mut cursor, mut scanned) = (0, Vec::new());
+        loop {
+            let (next_cursor, page) = scan_page(doubled.iter().map(|member| (*member, ())), cursor, &scan_options);
+            assert_eq!(page.len() % 2, 0);
+            scanned.extend(page.into_iter().map(|(member, _)| member.to_vec()));
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        assert_eq!(scanned.len(), doubled.len());
+    }
+
+    #[test]
+    fn scan_page_of_an_empty_collection() {
+        let scan_options = ScanOptions {pattern: None, count: 10, no_values: false};
+        let (cursor, page) = scan_page(std::iter::empty::<(&[u8], ())>(), 0, &scan_options);
+        assert_eq!(cursor, 0);
+        assert!(page.is_empty());
+    }
+}
@@ -1,9 +1,11 @@
 use std::vec::IntoIter;
 use format_bytes::format_bytes;
-use tokio::time::Instant;
+use std::time::Duration;
+use tokio::sync::MutexGuard;
+use tokio::time::{sleep, Instant};
 
-use crate::resp_handler::RespDatatype;
-use crate::{database::*, parse_vec_u8, push_to_replicas, show, wait_to_replicas, ReplicaTask};
+use crate::resp_handler::{serialize, RespDatatype};
+use crate::{abort_failover, block_client, execute_hash_command, execute_list_command, execute_set_command, execute_sorted_set_command, serve_blocked_clients, BlockRequest, BlockedClient, interpret_publish, interpret_subscribe, publish, backlog_info, bgsave, command_flags, failover_state_name, start_failover, wait_for_writes_unpaused, writes_paused, FailoverRequest, get_bool_config, has_enough_good_replicas, is_master_link_up, CMD_STALE, replicas_info, database::*, is_write_command, last_save, lock_propagation, master_link_info, master_link_role, master_repl_offset, replicas_role, parse_vec_u8, persistence_info, promote_to_master, push_to_replicas, replicate_from, save, set_tunable_config, show, unix_time_millis, wait_to_replicas, ReplicaTask};
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -14,47 +16,282 @@ pub enum RedisCommand {
     SimpleString(Vec<u8>),
     BulkString(Vec<u8>),
     FullResync(Vec<u8>, Vec<u8>),
-    ReplconfOk1,
-    ReplconfOk2,
+    Continue(Vec<u8>),
+    Psync(Vec<u8>, Vec<u8>),
+    ReplconfOk1(String),
+    ReplconfOk2(Vec<Vec<u8>>),
     ReplconfAck(Vec<u8>),
     Config(Vec<u8>, Vec<u8>),
+    // Switches the connection to subscribed mode
+    Subscribe(Vec<Vec<u8>>),
+    // A blocking command found nothing to pop
+    WouldBlock(BlockRequest),
+    // The client is parked until it is served or times out
+    Block(BlockedClient),
+    // The replies of the commands queued by MULTI
+    Exec(Vec<RedisCommand>),
     RespDatatype(RespDatatype),
     NullBulkString,
 }
 
+// What a write command sends to the replicas, like redis' alsoPropagate
+#[derive(Default)]
+pub struct Propagation {
+    // None propagates the command exactly as it was received
+    rewritten: Option<Vec<Vec<Vec<u8>>>>,
+}
+
+impl Propagation {
+    // Replicates argv instead of the received command, for non-deterministic commands
+    pub fn rewrite(&mut self, argv: Vec<Vec<u8>>) {
+        self.rewritten.get_or_insert_with(Vec::new).push(argv);
+    }
+
+    // Nothing is replicated, e.g. for a write that didn't change the keyspace
+    pub fn suppress(&mut self) {
+        self.rewritten.get_or_insert_with(Vec::new);
+    }
+
+    fn into_replica_tasks(self, buf: &[u8]) -> Vec<ReplicaTask> {
+        match self.rewritten {
+            Some(commands) => commands.into_iter()
+                .map(|argv| ReplicaTask::new(serialize_command(argv)))
+                .collect(),
+            None => vec![ReplicaTask::new(buf.to_vec())],
+        }
+    }
+}
+
+pub fn serialize_command(argv: Vec<Vec<u8>>) -> Vec<u8> {
+    serialize(&RespDatatype::Array(argv.into_iter().map(RespDatatype::BulkString).collect()))
+}
+
+fn split_command(resp_object: RespDatatype) -> Option<(Vec<u8>, IntoIter<RespDatatype>)> {
+    let RespDatatype::Array(array) = resp_object else {
+        return None;
+    };
+    let mut array_iterator = array.into_iter();
+    match array_iterator.next() {
+        Some(RespDatatype::BulkString(bulk_string)) => Some((bulk_string.to_ascii_uppercase(), array_iterator)),
+        _ => None,
+    }
+}
+
+// Writes are applied and queued for the replicas atomically, so they reach the replicas in execution order.
+// The pause is checked under the lock, so a failover sees the final offset once it holds it
+async fn lock_writes() -> MutexGuard<'static, ()> {
+    loop {
+        let propagation_guard = lock_propagation().await;
+        if !writes_paused() {
+            return propagation_guard;
+        }
+        drop(propagation_guard);
+        wait_for_writes_unpaused().await;
+    }
+}
+
+// What a write that ran sends to the replicas, nothing when it failed
+pub fn replica_tasks(redis_command: Option<&RedisCommand>, propagation: Propagation, buf: &[u8]) -> Vec<ReplicaTask> {
+    match redis_command {
+        None | Some(RedisCommand::Error(_)) => Vec::new(),
+        Some(_) => propagation.into_replica_tasks(buf),
+    }
+}
+
+// Writes accepted by a writable replica stay local, its sub-replicas follow the master's stream
+pub async fn propagate(replica_tasks: Vec<ReplicaTask>) {
+    let expired = take_expired_by_writes().await;
+    if get_config(b"role").await.as_deref() != Some(b"master") {
+        return;
+    }
+    // The replicas drop the expired keys the writes found before applying them
+    let deletes = expired.into_iter().map(|key| ReplicaTask::new(serialize_command(vec![b"DEL".to_vec(), key])));
+    push_to_replicas(deletes.chain(replica_tasks).collect()).await;
+}
+
 pub async fn interpret(resp_object: RespDatatype, buf: &Vec<u8>) -> Option<RedisCommand> {
-    match resp_object {
-        RespDatatype::Array(array) => {
-            let mut array_iterator = array.into_iter();
-            let command = match array_iterator.next() {
-                Some(RespDatatype::BulkString(bulk_string)) => bulk_string.to_ascii_uppercase(),
-                _ => return None,
-            };
-            match &command[..] {
-                b"PING" => Some(RedisCommand::Pong),
-                b"ECHO" => 
-                    match array_iterator.next() {
-                        Some(RespDatatype::BulkString(message)) => 
-                        Some(RedisCommand::BulkString(message)),
-                        _ => Some(RedisCommand::NullBulkString),
-                    },
-                b"SET" => {
-                    let command = interpret_set(array_iterator).await;
-                    if let Some(_) = command {
-                        push_to_replicas(ReplicaTask::new(buf.clone())).await;
-                    }
-                    command
-                },
-                b"GET" => interpret_get(array_iterator).await,
-                b"INFO" => interpret_info(array_iterator).await,
-                b"REPLCONF" => interpret_replconf(array_iterator).await,
-                b"PSYNC" => interpret_psync(array_iterator).await,
-                b"WAIT" => interpret_wait(array_iterator).await,
-                b"CONFIG" => interpret_config(array_iterator).await,
-                _ => return make_error_command(format!("Unknown command received {:?}", show(&command))),
-            }
+    let (command, array_iterator) = split_command(resp_object)?;
+    if !is_write_command(&command) {
+        if let Some(rejection) = check_replication_restrictions(&command).await {
+            return Some(rejection);
+        }
+        return execute_command(&command, array_iterator, &mut Propagation::default()).await;
+    }
+    let _propagation_guard = lock_writes().await;
+    // Checked after the pause, a failover may have turned this server into a replica
+    if let Some(rejection) = check_replication_restrictions(&command).await {
+        return Some(rejection);
+    }
+    let mut propagation = Propagation::default();
+    let redis_command = match execute_command(&command, array_iterator, &mut propagation).await {
+        // Nothing changed yet, the pop is propagated once the client is served
+        Some(RedisCommand::WouldBlock(block_request)) => {
+            propagation.suppress();
+            Some(block_client(block_request).await)
         },
-        _ => return None,
+        redis_command => redis_command,
+    };
+    propagate(replica_tasks(redis_command.as_ref(), propagation, buf)).await;
+    serve_blocked_clients().await;
+    redis_command
+}
+
+// Runs the commands queued by MULTI back to back, no other write can interleave with them
+pub async fn execute_transaction(queued: Vec<(RespDatatype, Vec<u8>)>) -> Option<RedisCommand> {
+    let has_writes = queued.iter().any(|(resp_object, _)| match resp_object {
+        RespDatatype::Array(array) => matches!(array.first(), Some(RespDatatype::BulkString(command)) if is_write_command(command)),
+        _ => false,
+    });
+    // A read-only transaction isn't held back by a paused failover
+    let _propagation_guard = if has_writes {lock_writes().await} else {lock_propagation().await};
+    let mut replies = Vec::with_capacity(queued.len());
+    let mut replica_tasks_queued = Vec::new();
+    for (resp_object, buf) in queued {
+        let Some((command, array_iterator)) = split_command(resp_object) else {
+            continue;
+        };
+        if let Some(rejection) = check_replication_restrictions(&command).await {
+            replies.push(rejection);
+            continue;
+        }
+        let mut propagation = Propagation::default();
+        let redis_command = match execute_command(&command, array_iterator, &mut propagation).await {
+            // Blocking commands don't block inside a transaction
+            Some(RedisCommand::WouldBlock(block_request)) => {
+                propagation.suppress();
+                Some(block_request.operation.timeout_reply())
+            },
+            redis_command => redis_command,
+        };
+        if is_write_command(&command) {
+            replica_tasks_queued.extend(replica_tasks(redis_command.as_ref(), propagation, &buf));
+        }
+        replies.push(redis_command.unwrap_or(RedisCommand::NullBulkString));
+    }
+    // The replicas apply the writes as one transaction too
+    if replica_tasks_queued.len() > 1 {
+        replica_tasks_queued.insert(0, ReplicaTask::new(serialize_command(vec![b"MULTI".to_vec()])));
+        replica_tasks_queued.push(ReplicaTask::new(serialize_command(vec![b"EXEC".to_vec()])));
+    }
+    propagate(replica_tasks_queued).await;
+    serve_blocked_clients().await;
+    Some(RedisCommand::Exec(replies))
+}
+
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+// Only the master expires keys, replicas apply the DEL it propagates so both drop the key at the same offset
+pub async fn active_expire_cycle() {
+    loop {
+        sleep(ACTIVE_EXPIRE_INTERVAL).await;
+        let _propagation_guard = lock_propagation().await;
+        // A failover waits for the replicas to reach a fixed offset
+        if writes_paused() || get_config(b"role").await.as_deref() != Some(b"master") {
+            continue;
+        }
+        let expired = active_expire(unix_time_millis()).await;
+        let mut replica_tasks: Vec<ReplicaTask> = expired.keys.into_iter()
+            .map(|key| ReplicaTask::new(serialize_command(vec![b"DEL".to_vec(), key])))
+            .collect();
+        // The replicas drop the last field of a hash with the key, as the master did
+        for (key, fields) in expired.fields {
+            let argv = [vec![b"HDEL".to_vec(), key], fields].concat();
+            replica_tasks.push(ReplicaTask::new(serialize_command(argv)));
+        }
+        push_to_replicas(replica_tasks).await;
+    }
+}
+
+// Clients can't diverge a replica from its master, may not read from it while the link is down,
+// and can't write to a master that doesn't reach enough replicas
+async fn check_replication_restrictions(command: &[u8]) -> Option<RedisCommand> {
+    if get_config(b"role").await.as_deref() != Some(b"slave") {
+        // An isolated master stops taking writes it couldn't replicate
+        if is_write_command(command) && !has_enough_good_replicas().await {
+            return make_error_command("NOREPLICAS Not enough good replicas to write.");
+        }
+        return None;
+    }
+    if is_write_command(command) && get_bool_config(b"replica-read-only").await {
+        return make_error_command("READONLY You can't write against a read only replica.");
+    }
+    if !is_master_link_up() && command_flags(command) & CMD_STALE == 0 && !get_bool_config(b"replica-serve-stale-data").await {
+        return make_error_command("MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.");
+    }
+    None
+}
+
+// Runs a command against this server without replicating it
+pub async fn execute_command(command: &[u8], mut array_iterator: IntoIter<RespDatatype>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    match command {
+        b"PING" => Some(RedisCommand::Pong),
+        b"ECHO" => 
+            match array_iterator.next() {
+                Some(RespDatatype::BulkString(message)) => 
+                Some(RedisCommand::BulkString(message)),
+                _ => Some(RedisCommand::NullBulkString),
+            },
+        b"SELECT" => interpret_select(array_iterator).await,
+        b"SET" => interpret_set(array_iterator, propagation).await,
+        b"DEL" => interpret_del(array_iterator, propagation).await,
+        b"GET" => interpret_get(array_iterator).await,
+        b"INFO" => interpret_info(array_iterator).await,
+        b"REPLCONF" => interpret_replconf(array_iterator).await,
+        b"PSYNC" => interpret_psync(array_iterator).await,
+        b"WAIT" => interpret_wait(array_iterator).await,
+        b"CONFIG" => interpret_config(array_iterator).await,
+        b"SAVE" => interpret_save().await,
+        b"BGSAVE" => interpret_bgsave().await,
+        b"REPLICAOF" | b"SLAVEOF" => interpret_replicaof(array_iterator).await,
+        b"ROLE" => interpret_role().await,
+        b"FAILOVER" => interpret_failover(array_iterator).await,
+        b"LASTSAVE" => Some(RedisCommand::RespDatatype(RespDatatype::Integer(last_save().await as i64))),
+        b"LPUSH" | b"RPUSH" | b"LPOP" | b"RPOP" | b"LRANGE" | b"LLEN" | b"LINDEX" | b"LSET" | b"LREM" | b"LTRIM" | b"LINSERT" | b"LMOVE"
+        | b"BLPOP" | b"BRPOP" | b"BLMOVE" | b"LMPOP" | b"BLMPOP" => {
+            execute_list_command(command, array_iterator, propagation).await
+        },
+        b"HSET" | b"HMSET" | b"HSETNX" | b"HGET" | b"HMGET" | b"HDEL" | b"HLEN" | b"HEXISTS" | b"HSTRLEN" | b"HKEYS" | b"HVALS" | b"HGETALL"
+        | b"HINCRBY" | b"HINCRBYFLOAT" | b"HSCAN" | b"HRANDFIELD" | b"HEXPIRE" | b"HPEXPIRE" | b"HEXPIREAT" | b"HPEXPIREAT"
+        | b"HTTL" | b"HPTTL" | b"HEXPIRETIME" | b"HPEXPIRETIME" | b"HPERSIST" => {
+            execute_hash_command(command, array_iterator, propagation).await
+        },
+        b"SADD" | b"SREM" | b"SISMEMBER" | b"SMISMEMBER" | b"SMEMBERS" | b"SCARD" | b"SINTER" | b"SUNION" | b"SDIFF"
+        | b"SINTERSTORE" | b"SUNIONSTORE" | b"SDIFFSTORE" | b"SINTERCARD" | b"SRANDMEMBER" | b"SPOP" | b"SMOVE" | b"SSCAN" => {
+            execute_set_command(command, array_iterator, propagation).await
+        },
+        b"ZADD" | b"ZINCRBY" | b"ZREM" | b"ZREMRANGEBYRANK" | b"ZREMRANGEBYSCORE" | b"ZREMRANGEBYLEX" | b"ZCARD" | b"ZSCORE" | b"ZRANGE"
+        | b"ZRANK" | b"ZREVRANK" | b"ZCOUNT" | b"ZLEXCOUNT" | b"ZUNIONSTORE" | b"ZINTERSTORE" | b"ZPOPMIN" | b"ZPOPMAX"
+        | b"BZPOPMIN" | b"BZPOPMAX" | b"ZRANDMEMBER" => {
+            execute_sorted_set_command(command, array_iterator, propagation).await
+        },
+        b"OBJECT" => interpret_object(array_iterator).await,
+        b"SUBSCRIBE" => interpret_subscribe(array_iterator),
+        // Outside subscribed mode there is nothing to unsubscribe from
+        b"UNSUBSCRIBE" => Some(RedisCommand::RespDatatype(RespDatatype::Array(vec![
+            RespDatatype::BulkString(b"unsubscribe".to_vec()),
+            RespDatatype::NullBulkString,
+            RespDatatype::Integer(0),
+        ]))),
+        b"PUBLISH" => match interpret_publish(array_iterator) {
+            Ok((channel, message)) => Some(RedisCommand::RespDatatype(RespDatatype::Integer(publish(&channel, &message).await as i64))),
+            Err(e) => make_error_command(e),
+        },
+        _ => make_error_command(format!("Unknown command received {:?}", show(command))),
+    }
+}
+
+// Only OBJECT ENCODING, the other subcommands need bookkeeping this server doesn't do
+async fn interpret_object(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
+    let subcommand = match array_iterator.next() {
+        Some(RespDatatype::BulkString(subcommand)) => subcommand,
+        _ => return make_error_command("ERR wrong number of arguments for 'object' command"),
+    };
+    match (subcommand.to_ascii_uppercase().as_slice(), array_iterator.next(), array_iterator.next()) {
+        (b"ENCODING", Some(RespDatatype::BulkString(key)), None) => match object_encoding(&key).await {
+            Some(encoding) => Some(RedisCommand::BulkString(encoding.as_bytes().to_vec())),
+            None => Some(RedisCommand::NullBulkString),
+        },
+        _ => make_error_command(format!("ERR unknown subcommand or wrong number of arguments for '{}'. Try OBJECT HELP.", String::from_utf8_lossy(&subcommand))),
     }
 }
 
@@ -64,12 +301,13 @@ async fn interpret_get(mut array_iterator: IntoIter<RespDatatype>) -> Option<Red
         _ => return make_error_command("Expected key after GET"),
     };
     match get_value(&key).await {
-        Some(value) => Some(RedisCommand::BulkString(value)),
-        None => Some(RedisCommand::NullBulkString),
+        Ok(Some(value)) => Some(RedisCommand::BulkString(value)),
+        Ok(None) => Some(RedisCommand::NullBulkString),
+        Err(e) => make_error_command(e),
     }
 }
 
-async fn interpret_set(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
+async fn interpret_set(mut array_iterator: IntoIter<RespDatatype>, propagation: &mut Propagation) -> Option<RedisCommand> {
     let key = match array_iterator.next() {
         Some(RespDatatype::BulkString(key)) => key,
         _ => return None,
@@ -78,72 +316,119 @@ async fn interpret_set(mut array_iterator: IntoIter<RespDatatype>) -> Option<Red
         Some(RespDatatype::BulkString(value)) => value,
         _ => return None,
     };
-    let mut expiry: Option<u64> = None;
+    let mut expires_at: Option<u64> = None;
+    let mut relative_expiry = false;
     while let Some(argument) = array_iterator.next() {
         match argument {
             RespDatatype::BulkString(argument) => {
-                match &argument.to_ascii_uppercase()[..] {
-                    b"PX" => {
-                        expiry = match array_iterator.next() {
-                            Some(RespDatatype::Integer(integer)) => {
-                                if integer >= 0 {
-                                    Some(integer.try_into().unwrap())
-                                } else {
-                                    return make_error_command("Integer argument for PX cannott be negative");
-                                }
-                            },
-                            Some(RespDatatype::BulkString(bulk_string)) => {
-                                let bulk_string = match String::from_utf8(bulk_string) {
-                                    Ok(bulk_string) => bulk_string,
-                                    Err(_) => return make_error_command("Invalid argument given for PX")
-                                };
-                                match bulk_string.parse() {
-                                    Ok(res) => Some(res),
-                                    Err(_) => return make_error_command("Invalid argument given for PX")
-                                }
-                            }
-                            None => return make_error_command("No integer argument given for PX"),
-                            _ => return make_error_command("Invalid argument given for PX"),
-                        }
-                    },
-                    _ => (),
+                let argument = argument.to_ascii_uppercase();
+                let multiplier = match &argument[..] {
+                    b"EX" | b"EXAT" => 1000,
+                    b"PX" | b"PXAT" => 1,
+                    _ => continue,
+                };
+                let amount = match parse_integer_argument(array_iterator.next()) {
+                    Some(amount) if amount > 0 => (amount as u64).checked_mul(multiplier),
+                    Some(_) => None,
+                    None => return make_error_command(format!("Invalid argument given for {}", show(&argument))),
+                };
+                relative_expiry = argument == b"EX" || argument == b"PX";
+                let expiry = match amount {
+                    Some(amount) if relative_expiry => unix_time_millis().checked_add(amount),
+                    amount => amount,
+                };
+                // Like redis an expiry time that doesn't fit is rejected rather than wrapped
+                match expiry {
+                    Some(expiry) => expires_at = Some(expiry),
+                    None => return make_error_command("ERR invalid expire time in 'set' command"),
                 }
             },
             _ => (),
         }
     }
-    if let Some(expiry) = expiry {set_value_expiry(&key, &value, expiry).await} else {set_value(&key, &value).await}; 
+    match expires_at {
+        Some(expires_at) => {
+            set_value_expires_at(&key, &value, expires_at).await;
+            // Replicas must expire the key at the same moment as the master
+            if relative_expiry {
+                propagation.rewrite(vec![b"SET".to_vec(), key, value, b"PXAT".to_vec(), expires_at.to_string().into_bytes()]);
+            }
+        },
+        None => set_value(&key, &value).await,
+    }
     return Some(RedisCommand::Ok);
 }
 
+async fn interpret_del(array_iterator: IntoIter<RespDatatype>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let mut deleted = 0;
+    for key in array_iterator {
+        match key {
+            RespDatatype::BulkString(key) => if delete_value(&key).await {deleted += 1},
+            _ => return make_error_command("Invalid key given for DEL"),
+        }
+    }
+    if deleted == 0 {
+        propagation.suppress();
+    }
+    Some(RedisCommand::RespDatatype(RespDatatype::Integer(deleted)))
+}
+
+async fn interpret_select(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
+    // There is a single keyspace, so only database 0 exists
+    match parse_integer_argument(array_iterator.next()) {
+        Some(0) => Some(RedisCommand::Ok),
+        Some(_) => make_error_command("ERR DB index is out of range"),
+        None => make_error_command("ERR value is not an integer or out of range"),
+    }
+}
+
+fn parse_integer_argument(argument: Option<RespDatatype>) -> Option<i64> {
+    match argument {
+        Some(RespDatatype::Integer(integer)) => Some(integer),
+        Some(RespDatatype::BulkString(bulk_string)) => parse_vec_u8::<i64>(bulk_string).ok(),
+        _ => None,
+    }
+}
+
 async fn interpret_info(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
     let arg = match array_iterator.next() {
         Some(RespDatatype::BulkString(arg)) => arg,
+        None => b"default".to_vec(),
         _ => return None,
     };
-    match &arg[..] {
-        b"replication" => {
-            let role = match get_config(b"role").await {
-                Some(role) => role,
-                None => return make_error_command("Error happened in the Redis. For some reason this server does not have a role.")
-            };
-            let master_replid = match get_config(b"master_replid").await {
-                Some(master_replid) => master_replid,
-                None => return make_error_command("Error happened in the Redis. For some reason this server does not have a role.")
-            };
-            let master_repl_offset = match get_config(b"master_repl_offset").await {
-                Some(master_repl_offset) => master_repl_offset,
-                None => return make_error_command("Error happened in the Redis. For some reason this server does not have a role.")
-            };
-            Some(RedisCommand::BulkString(
-                format_bytes!(b"role:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\n",
-                role, master_replid, master_repl_offset
-            )))
+    match &arg.to_ascii_lowercase()[..] {
+        b"persistence" => Some(RedisCommand::BulkString(persistence_section().await)),
+        b"replication" => match replication_section().await {
+            Some(section) => Some(RedisCommand::BulkString(section)),
+            None => make_error_command("Error happened in the Redis. For some reason this server does not have a role."),
         },
+        // Sections are separated by an empty line
+        b"default" | b"all" | b"everything" => Some(RedisCommand::BulkString(
+            [persistence_section().await, b"\r\n".to_vec(), replication_section().await.unwrap_or_default()].concat()
+        )),
         arg => make_error_command(format!("Unknown argument for INFO {arg:?}")),
     }
 }
 
+async fn persistence_section() -> Vec<u8> {
+    [b"# Persistence\r\n".to_vec(), persistence_info().await].concat()
+}
+
+async fn replication_section() -> Option<Vec<u8>> {
+    let role = get_config(b"role").await?;
+    let master_replid = get_config(b"master_replid").await?;
+    let master_replid2 = get_config(b"master_replid2").await.unwrap_or(vec![b'0'; 40]);
+    let second_repl_offset = get_config(b"second_repl_offset").await.unwrap_or(b"-1".to_vec());
+    let master_repl_offset = master_repl_offset().await.to_string().into_bytes();
+    let master_link_info = if role == b"slave" {master_link_info().await} else {Vec::new()};
+    let replicas_info = replicas_info().await;
+    let failover_state = failover_state_name().as_bytes();
+    let backlog_info = backlog_info().await;
+    Some(format_bytes!(b"# Replication\r\nrole:{}\r\n{}{}master_failover_state:{}\r\nmaster_replid:{}\r\nmaster_replid2:{}\r\nmaster_repl_offset:{}\r\nsecond_repl_offset:{}\r\n{}",
+        role, master_link_info, replicas_info, failover_state, master_replid, master_replid2, master_repl_offset, second_repl_offset, backlog_info
+    ))
+}
+
 #[allow(unused)]
 async fn interpret_replconf(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
     while let Some(argument) = array_iterator.next() {
@@ -165,13 +450,18 @@ async fn interpret_replconf(mut array_iterator: IntoIter<RespDatatype>) -> Optio
                             },
                             _ => return make_error_command("Invalid argument for port"),
                         };
-                        return Some(RedisCommand::ReplconfOk1);
+                        return Some(RedisCommand::ReplconfOk1(port));
                     },
                     b"capa" => {
-                        let capa = match array_iterator.next() {
-                            _ => (),
-                        };
-                        return Some(RedisCommand::ReplconfOk2);
+                        let mut capabilities = Vec::new();
+                        if let Some(RespDatatype::BulkString(capability)) = array_iterator.next() {
+                            capabilities.push(capability.to_ascii_lowercase());
+                        }
+                        // Further capabilities come as more "capa <capability>" pairs
+                        while let (Some(RespDatatype::BulkString(_)), Some(RespDatatype::BulkString(capability))) = (array_iterator.next(), array_iterator.next()) {
+                            capabilities.push(capability.to_ascii_lowercase());
+                        }
+                        return Some(RedisCommand::ReplconfOk2(capabilities));
                     },
                     b"getack" => {
                         let getack_arg = match array_iterator.next() {
@@ -201,14 +491,21 @@ async fn interpret_psync(mut array_iterator: IntoIter<RespDatatype>) -> Option<R
         Some(RespDatatype::BulkString(repl_offset)) => repl_offset,
         _ => return make_error_command("No repl_offset argument for PSYNC command given.")
     };
-    if &repl_id[..] == b"?" && &repl_offset[..] == b"-1" {
-        let repl_id = get_config(b"master_replid").await.unwrap();
-        let repl_offset = get_config(b"master_repl_offset").await.unwrap();
-        let empty_file_payload = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2")
-        .unwrap();
-        return Some(RedisCommand::FullResync(format_bytes!(b"FULLRESYNC {} {}", repl_id, repl_offset), empty_file_payload));
-    }
-    todo!();
+    // PSYNC <replid> <offset> FAILOVER comes from our master handing its role over to us
+    if let Some(RespDatatype::BulkString(option)) = array_iterator.next() {
+        if !option.eq_ignore_ascii_case(b"FAILOVER") {
+            return make_error_command("ERR syntax error");
+        }
+        if get_config(b"role").await.as_deref() != Some(b"slave") {
+            return make_error_command("ERR PSYNC FAILOVER can't be sent to a master.");
+        }
+        if get_config(b"master_replid").await.as_ref() != Some(&repl_id) {
+            return make_error_command("ERR PSYNC FAILOVER replid must match my replid.");
+        }
+        promote_to_master().await;
+    }
+    // The snapshot is taken and sent once the connection is handed over to handle_replica
+    Some(RedisCommand::Psync(repl_id, repl_offset))
 }
 
 #[allow(unused)]
@@ -252,6 +549,17 @@ async fn interpret_config(mut array_iterator: IntoIter<RespDatatype>) -> Option<
                         _ => return make_error_command("Invalid argument for config get command given."),
                     }
                 },
+                b"SET" => {
+                    match (array_iterator.next(), array_iterator.next()) {
+                        (Some(RespDatatype::BulkString(name)), Some(RespDatatype::BulkString(value))) => {
+                            match set_tunable_config(&name, &value).await {
+                                Ok(()) => Some(RedisCommand::Ok),
+                                Err(e) => make_error_command(format!("ERR {e}")),
+                            }
+                        },
+                        _ => make_error_command("Invalid argument for config set command given."),
+                    }
+                },
                 _ => return make_error_command("Invalid argument for config command given."),
             }
         },
@@ -259,6 +567,96 @@ async fn interpret_config(mut array_iterator: IntoIter<RespDatatype>) -> Option<
     }
 }
 
+async fn interpret_failover(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
+    let mut request = FailoverRequest {target: None, timeout: None, force: false};
+    let mut abort = false;
+    while let Some(argument) = array_iterator.next() {
+        let argument = match argument {
+            RespDatatype::BulkString(argument) => argument.to_ascii_uppercase(),
+            _ => return make_error_command("ERR syntax error"),
+        };
+        match &argument[..] {
+            b"TO" if request.target.is_none() => {
+                match (array_iterator.next(), parse_integer_argument(array_iterator.next())) {
+                    (Some(RespDatatype::BulkString(host)), Some(port)) if (1..=65535).contains(&port) => {
+                        request.target = Some((String::from_utf8_lossy(&host).to_string(), port.to_string()));
+                    },
+                    _ => return make_error_command("ERR syntax error"),
+                }
+            },
+            b"TIMEOUT" if request.timeout.is_none() => {
+                match parse_integer_argument(array_iterator.next()) {
+                    Some(timeout) if timeout > 0 => request.timeout = Some(timeout as u64),
+                    Some(_) => return make_error_command("ERR FAILOVER timeout must be greater than 0"),
+                    None => return make_error_command("ERR value is not an integer or out of range"),
+                }
+            },
+            b"FORCE" if !request.force => request.force = true,
+            b"ABORT" if !abort => abort = true,
+            _ => return make_error_command("ERR syntax error"),
+        }
+    }
+    let result = if abort {
+        if request.target.is_some() || request.timeout.is_some() || request.force {
+            return make_error_command("ERR syntax error");
+        }
+        abort_failover().await
+    } else {
+        start_failover(request).await
+    };
+    match result {
+        Ok(()) => Some(RedisCommand::Ok),
+        Err(e) => make_error_command(format!("ERR {e}")),
+    }
+}
+
+async fn interpret_role() -> Option<RedisCommand> {
+    if get_config(b"role").await.as_deref() == Some(b"slave") {
+        return Some(RedisCommand::RespDatatype(RespDatatype::Array(master_link_role().await)));
+    }
+    Some(RedisCommand::RespDatatype(RespDatatype::Array(vec![
+        RespDatatype::BulkString(b"master".to_vec()),
+        RespDatatype::Integer(master_repl_offset().await as i64),
+        RespDatatype::Array(replicas_role().await),
+    ])))
+}
+
+async fn interpret_replicaof(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
+    let (host, port) = match (array_iterator.next(), array_iterator.next(), array_iterator.next()) {
+        (Some(RespDatatype::BulkString(host)), Some(RespDatatype::BulkString(port)), None) => (host, port),
+        _ => return make_error_command("ERR wrong number of arguments for 'replicaof' command"),
+    };
+    if host.eq_ignore_ascii_case(b"NO") && port.eq_ignore_ascii_case(b"ONE") {
+        promote_to_master().await;
+        return Some(RedisCommand::Ok);
+    }
+    let port = match parse_vec_u8::<u16>(port) {
+        Ok(port) if port != 0 => port.to_string(),
+        _ => return make_error_command("ERR Invalid master port"),
+    };
+    let host = String::from_utf8_lossy(&host).to_string();
+    let current_master = (get_config(b"master_host").await, get_config(b"master_port").await);
+    if get_config(b"role").await.as_deref() == Some(b"slave") && current_master == (Some(host.clone().into_bytes()), Some(port.clone().into_bytes())) {
+        return Some(RedisCommand::SimpleString(b"OK Already connected to specified master".to_vec()));
+    }
+    replicate_from(host, port).await;
+    Some(RedisCommand::Ok)
+}
+
+async fn interpret_save() -> Option<RedisCommand> {
+    match save().await {
+        Ok(()) => Some(RedisCommand::Ok),
+        Err(e) => make_error_command(format!("ERR {e}")),
+    }
+}
+
+async fn interpret_bgsave() -> Option<RedisCommand> {
+    match bgsave().await {
+        Ok(()) => Some(RedisCommand::SimpleString(b"Background saving started".to_vec())),
+        Err(e) => make_error_command(format!("ERR {e}")),
+    }
+}
+
 #[inline]
 fn make_error_command<T: ToString>(string: T) -> Option<RedisCommand> {
     Some(RedisCommand::Error(string.to_string()))
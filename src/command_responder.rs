@@ -1,4 +1,6 @@
 use crate::{command_interpreter::RedisCommand, RespStreamHandler};
+use format_bytes::format_bytes;
+
 use crate::resp_handler::{serialize, RespDatatype};
 
 pub const PONG_STRING: &[u8] = b"+PONG\r\n";
@@ -24,7 +26,7 @@ fn formulate_response(redis_command: &RedisCommand) -> Option<Vec<Vec<u8>>> {
         RedisCommand::Pong => {
             Some(vec![PONG_STRING.to_vec()])
         },
-        RedisCommand::Ok | RedisCommand::ReplconfOk1 | RedisCommand::ReplconfOk2 => {
+        RedisCommand::Ok | RedisCommand::ReplconfOk1(_) | RedisCommand::ReplconfOk2(_) => {
             Some(vec![OK_STRING.to_vec()])
         },
         RedisCommand::BulkString(message) => {
@@ -48,7 +50,19 @@ fn formulate_response(redis_command: &RedisCommand) -> Option<Vec<Vec<u8>>> {
                 serialize(&RespDatatype::RDBFile(rdb_file.to_owned()))
             ])
         },
-        RedisCommand::ReplconfAck(_) => None,
+        RedisCommand::ReplconfAck(_) | RedisCommand::Psync(_, _) | RedisCommand::Continue(_) => None,
+        // Confirmed by the subscriber loop, along with the messages
+        RedisCommand::Subscribe(_) => None,
+        // Answered once the client is served or times out
+        RedisCommand::WouldBlock(_) | RedisCommand::Block(_) => None,
+        RedisCommand::Exec(replies) => {
+            let mut response = format_bytes!(b"*{}\r\n", replies.len().to_string().as_bytes());
+            for reply in replies {
+                let reply = formulate_response(reply).map(|parts| parts.concat()).unwrap_or(NULL_BULK_STRING.to_vec());
+                response.extend(reply);
+            }
+            Some(vec![response])
+        },
         RedisCommand::Config(name, value) => {
             Some(vec![serialize(&RespDatatype::Array(vec![RespDatatype::BulkString(name.to_owned()), RespDatatype::BulkString(value.to_owned())]))])
         },
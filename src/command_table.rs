@@ -0,0 +1,132 @@
+// The command modifies the keyspace, it is propagated to replicas after it succeeds
+pub const CMD_WRITE: u32 = 1 << 0;
+// The command only reads the keyspace
+pub const CMD_READONLY: u32 = 1 << 1;
+// Administrative and replication commands
+pub const CMD_ADMIN: u32 = 1 << 2;
+// Allowed on a replica with a broken master link even with replica-serve-stale-data no
+pub const CMD_STALE: u32 = 1 << 3;
+// Refused inside MULTI, the command takes over the connection
+pub const CMD_NO_MULTI: u32 = 1 << 4;
+
+const COMMAND_TABLE: &[(&[u8], u32)] = &[
+    (b"PING", CMD_STALE),
+    (b"ECHO", 0),
+    (b"SELECT", CMD_STALE),
+    (b"GET", CMD_READONLY),
+    (b"SET", CMD_WRITE),
+    (b"DEL", CMD_WRITE),
+    (b"INFO", CMD_STALE),
+    (b"WAIT", 0),
+    (b"LASTSAVE", CMD_STALE),
+    (b"ROLE", CMD_STALE),
+    (b"CONFIG", CMD_ADMIN | CMD_STALE),
+    (b"SAVE", CMD_ADMIN),
+    (b"BGSAVE", CMD_ADMIN),
+    (b"REPLCONF", CMD_ADMIN | CMD_STALE | CMD_NO_MULTI),
+    (b"PSYNC", CMD_ADMIN | CMD_STALE | CMD_NO_MULTI),
+    (b"REPLICAOF", CMD_ADMIN | CMD_STALE),
+    (b"SLAVEOF", CMD_ADMIN | CMD_STALE),
+    (b"FAILOVER", CMD_ADMIN | CMD_STALE),
+    (b"SUBSCRIBE", CMD_STALE | CMD_NO_MULTI),
+    (b"UNSUBSCRIBE", CMD_STALE),
+    (b"PUBLISH", CMD_STALE),
+    (b"LPUSH", CMD_WRITE),
+    (b"RPUSH", CMD_WRITE),
+    (b"LPOP", CMD_WRITE),
+    (b"RPOP", CMD_WRITE),
+    (b"LRANGE", CMD_READONLY),
+    (b"LLEN", CMD_READONLY),
+    (b"LINDEX", CMD_READONLY),
+    (b"LSET", CMD_WRITE),
+    (b"LREM", CMD_WRITE),
+    (b"LTRIM", CMD_WRITE),
+    (b"LINSERT", CMD_WRITE),
+    (b"LMOVE", CMD_WRITE),
+    (b"BLPOP", CMD_WRITE),
+    (b"BRPOP", CMD_WRITE),
+    (b"BLMOVE", CMD_WRITE),
+    (b"LMPOP", CMD_WRITE),
+    (b"BLMPOP", CMD_WRITE),
+    (b"HSET", CMD_WRITE),
+    (b"HMSET", CMD_WRITE),
+    (b"HSETNX", CMD_WRITE),
+    (b"HGET", CMD_READONLY),
+    (b"HMGET", CMD_READONLY),
+    (b"HDEL", CMD_WRITE),
+    (b"HLEN", CMD_READONLY),
+    (b"HEXISTS", CMD_READONLY),
+    (b"HSTRLEN", CMD_READONLY),
+    (b"HKEYS", CMD_READONLY),
+    (b"HVALS", CMD_READONLY),
+    (b"HGETALL", CMD_READONLY),
+    (b"HINCRBY", CMD_WRITE),
+    (b"HINCRBYFLOAT", CMD_WRITE),
+    (b"HSCAN", CMD_READONLY),
+    (b"HRANDFIELD", CMD_READONLY),
+    (b"HEXPIRE", CMD_WRITE),
+    (b"HPEXPIRE", CMD_WRITE),
+    (b"HEXPIREAT", CMD_WRITE),
+    (b"HPEXPIREAT", CMD_WRITE),
+    (b"HTTL", CMD_READONLY),
+    (b"HPTTL", CMD_READONLY),
+    (b"HEXPIRETIME", CMD_READONLY),
+    (b"HPEXPIRETIME", CMD_READONLY),
+    (b"HPERSIST", CMD_WRITE),
+    (b"SADD", CMD_WRITE),
+    (b"SREM", CMD_WRITE),
+    (b"SISMEMBER", CMD_READONLY),
+    (b"SMISMEMBER", CMD_READONLY),
+    (b"SMEMBERS", CMD_READONLY),
+    (b"SCARD", CMD_READONLY),
+    (b"SINTER", CMD_READONLY),
+    (b"SUNION", CMD_READONLY),
+    (b"SDIFF", CMD_READONLY),
+    (b"SINTERSTORE", CMD_WRITE),
+    (b"SUNIONSTORE", CMD_WRITE),
+    (b"SDIFFSTORE", CMD_WRITE),
+    (b"SINTERCARD", CMD_READONLY),
+    (b"SRANDMEMBER", CMD_READONLY),
+    (b"SPOP", CMD_WRITE),
+    (b"SMOVE", CMD_WRITE),
+    (b"SSCAN", CMD_READONLY),
+    (b"OBJECT", CMD_READONLY),
+    (b"ZADD", CMD_WRITE),
+    (b"ZINCRBY", CMD_WRITE),
+    (b"ZREM", CMD_WRITE),
+    (b"ZREMRANGEBYRANK", CMD_WRITE),
+    (b"ZREMRANGEBYSCORE", CMD_WRITE),
+    (b"ZREMRANGEBYLEX", CMD_WRITE),
+    (b"ZCARD", CMD_READONLY),
+    (b"ZSCORE", CMD_READONLY),
+    (b"ZRANGE", CMD_READONLY),
+    (b"ZRANK", CMD_READONLY),
+    (b"ZREVRANK", CMD_READONLY),
+    (b"ZCOUNT", CMD_READONLY),
+    (b"ZLEXCOUNT", CMD_READONLY),
+    (b"ZUNIONSTORE", CMD_WRITE),
+    (b"ZINTERSTORE", CMD_WRITE),
+    (b"ZPOPMIN", CMD_WRITE),
+    (b"ZPOPMAX", CMD_WRITE),
+    (b"BZPOPMIN", CMD_WRITE),
+    (b"BZPOPMAX", CMD_WRITE),
+    (b"ZRANDMEMBER", CMD_READONLY),
+    (b"MULTI", CMD_STALE),
+    (b"EXEC", CMD_STALE),
+    (b"DISCARD", CMD_STALE),
+];
+
+pub fn is_known_command(command: &[u8]) -> bool {
+    COMMAND_TABLE.iter().any(|(name, _)| name.eq_ignore_ascii_case(command))
+}
+
+pub fn command_flags(command: &[u8]) -> u32 {
+    COMMAND_TABLE.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(command))
+        .map(|(_, flags)| *flags)
+        .unwrap_or(0)
+}
+
+pub fn is_write_command(command: &[u8]) -> bool {
+    command_flags(command) & CMD_WRITE != 0
+}
@@ -0,0 +1,112 @@
+use crate::{get_config, parse_memory, parse_vec_u8, resize_backlog, set_config, set_replica_output_buffer_limit, CONFIG};
+
+// Parameters that can be changed with CONFIG SET or passed as "--<name> <value>"
+const TUNABLE_CONFIGS: &[(&str, &str)] = &[
+    ("repl-backlog-size", "1048576"),
+    ("client-output-buffer-limit", "normal 0 0 0 replica 268435456 67108864 60 pubsub 33554432 8388608 60"),
+    ("replica-read-only", "yes"),
+    ("replica-serve-stale-data", "yes"),
+    ("min-replicas-to-write", "0"),
+    ("min-replicas-max-lag", "10"),
+    ("repl-diskless-sync", "yes"),
+    ("repl-diskless-sync-delay", "5"),
+];
+
+const CLIENT_CLASSES: &[&str] = &["normal", "replica", "pubsub"];
+
+const REPL_BACKLOG_MIN_SIZE: u64 = 16 * 1024;
+
+pub fn is_tunable_config(name: &str) -> bool {
+    TUNABLE_CONFIGS.iter().any(|(tunable, _)| *tunable == name)
+}
+
+pub async fn init_tunable_configs() {
+    let mut config = CONFIG.lock().await;
+    for (name, default) in TUNABLE_CONFIGS {
+        config.entry(name.as_bytes().to_vec()).or_insert(default.as_bytes().to_vec());
+    }
+}
+
+// Validates the value, stores it and applies it to the running server
+pub async fn set_tunable_config(name: &[u8], value: &[u8]) -> Result<(), String> {
+    let name = String::from_utf8_lossy(name).to_ascii_lowercase();
+    if !is_tunable_config(&name) {
+        return Err(format!("Unknown option or number of arguments for CONFIG SET - '{name}'"));
+    }
+    let invalid = || format!("Invalid argument '{}' for CONFIG SET '{name}'", String::from_utf8_lossy(value));
+    match name.as_str() {
+        "repl-backlog-size" => {
+            let size = parse_memory(value).ok_or_else(invalid)?.max(REPL_BACKLOG_MIN_SIZE);
+            set_config(name.as_bytes(), size.to_string().as_bytes()).await;
+            resize_backlog(size as usize).await;
+        },
+        "client-output-buffer-limit" => {
+            let limits = parse_client_output_buffer_limit(value).ok_or_else(invalid)?;
+            let current = get_config(name.as_bytes()).await.unwrap_or_default();
+            let mut merged = parse_client_output_buffer_limit(&current).unwrap_or_default();
+            for (class, hard, soft, soft_seconds) in limits {
+                merged.retain(|(merged_class, _, _, _)| *merged_class != class);
+                merged.push((class, hard, soft, soft_seconds));
+            }
+            merged.sort_by_key(|(class, _, _, _)| CLIENT_CLASSES.iter().position(|known| *known == class));
+            if let Some((_, hard, soft, soft_seconds)) = merged.iter().find(|(class, _, _, _)| class == "replica") {
+                set_replica_output_buffer_limit(*hard as usize, *soft as usize, *soft_seconds).await;
+            }
+            let merged = merged.iter()
+                .map(|(class, hard, soft, soft_seconds)| format!("{class} {hard} {soft} {soft_seconds}"))
+                .collect::<Vec<String>>()
+                .join(" ");
+            set_config(name.as_bytes(), merged.as_bytes()).await;
+        },
+        "replica-read-only" | "replica-serve-stale-data" | "repl-diskless-sync" => {
+            let value = parse_bool(value).ok_or_else(invalid)?;
+            set_config(name.as_bytes(), if value {b"yes"} else {b"no"}).await;
+        },
+        "min-replicas-to-write" | "min-replicas-max-lag" | "repl-diskless-sync-delay" => {
+            let value = parse_vec_u8::<u64>(value.to_vec()).map_err(|_| invalid())?;
+            set_config(name.as_bytes(), value.to_string().as_bytes()).await;
+        },
+        _ => set_config(name.as_bytes(), value).await,
+    }
+    Ok(())
+}
+
+pub async fn get_bool_config(name: &[u8]) -> bool {
+    get_config(name).await.and_then(|value| parse_bool(&value)).unwrap_or(false)
+}
+
+pub async fn get_u64_config(name: &[u8]) -> u64 {
+    parse_vec_u8::<u64>(get_config(name).await.unwrap_or_default()).unwrap_or(0)
+}
+
+fn parse_bool(value: &[u8]) -> Option<bool> {
+    match &value.to_ascii_lowercase()[..] {
+        b"yes" => Some(true),
+        b"no" => Some(false),
+        _ => None,
+    }
+}
+
+// "<class> <hard limit> <soft limit> <soft seconds>" groups, "slave" is an alias of "replica"
+fn parse_client_output_buffer_limit(value: &[u8]) -> Option<Vec<(String, u64, u64, u64)>> {
+    let value = String::from_utf8_lossy(value).to_ascii_lowercase();
+    let words: Vec<&str> = value.split_whitespace().collect();
+    if words.is_empty() || !words.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut limits = Vec::new();
+    for group in words.chunks(4) {
+        let class = match group[0] {
+            "slave" => "replica",
+            class if CLIENT_CLASSES.contains(&class) => class,
+            _ => return None,
+        };
+        limits.push((
+            class.to_string(),
+            parse_memory(group[1].as_bytes())?,
+            parse_memory(group[2].as_bytes())?,
+            group[3].parse::<u64>().ok()?,
+        ));
+    }
+    Some(limits)
+}
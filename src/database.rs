@@ -1,25 +1,270 @@
-use std::{collections::HashMap, time::Duration};
-use tokio::{sync::Mutex, time::sleep};
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+use rand::seq::index::sample;
+use tokio::sync::Mutex;
+
+use crate::{unix_time_millis, SortedSet};
 
 lazy_static! {
-    static ref DATABASE: Mutex<HashMap<Vec<u8>, Vec<u8>>> = Mutex::new(HashMap::new());
+    static ref DATABASE: Mutex<HashMap<Vec<u8>, Entry>> = Mutex::new(HashMap::new());
     pub static ref CONFIG: Mutex<HashMap<Vec<u8>, Vec<u8>>> = Mutex::new(HashMap::new());
+    // Always locked after DATABASE
+    static ref VOLATILE_KEYS: Mutex<VolatileKeys> = Mutex::new(VolatileKeys::default());
+    // Expired keys a write deleted on the master, their DELs go to the replicas ahead of the write. Locked after DATABASE
+    static ref EXPIRED_BY_WRITES: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+// Keys with a TTL or with hash fields that have one, what the active expiry cycle samples like redis' expires dict.
+// A key that lost its TTL stays until a sample finds it
+#[derive(Default)]
+struct VolatileKeys {
+    keys: Vec<Vec<u8>>,
+    positions: HashMap<Vec<u8>, usize>,
+}
+
+impl VolatileKeys {
+    fn insert(&mut self, key: &[u8]) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_vec(), self.keys.len());
+            self.keys.push(key.to_vec());
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        let Some(position) = self.positions.remove(key) else {
+            return;
+        };
+        self.keys.swap_remove(position);
+        if let Some(moved) = self.keys.get(position) {
+            self.positions.insert(moved.clone(), position);
+        }
+    }
+
+    // Up to amount distinct keys picked at random
+    fn sample(&self, amount: usize) -> Vec<Vec<u8>> {
+        let amount = amount.min(self.keys.len());
+        sample(&mut rand::thread_rng(), self.keys.len(), amount).into_iter().map(|index| self.keys[index].clone()).collect()
+    }
+}
+
+// Keys sampled per round of the active expiry cycle, and the time a cycle may take, like redis' defaults
+const ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP: usize = 20;
+const ACTIVE_EXPIRE_CYCLE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+// Amount of changes to the keyspace, used to report changes since the last save
+static DIRTY: AtomicU64 = AtomicU64::new(0);
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(Hash),
+    Set(RedisSet),
+    SortedSet(SortedSet),
+}
+
+pub type Hash = HashMap<Vec<u8>, HashField>;
+
+#[derive(Debug, Clone)]
+pub struct HashField {
+    pub value: Vec<u8>,
+    // Absolute unix time in milliseconds, set by HEXPIRE and friends
+    pub expires_at: Option<u64>,
+}
+
+impl HashField {
+    pub fn new(value: Vec<u8>) -> Self {
+        HashField {value, expires_at: None}
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+// Limits of the compact set encodings, redis' set-max-intset-entries, set-max-listpack-entries and set-max-listpack-value
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+const SET_MAX_LISTPACK_ENTRIES: usize = 128;
+const SET_MAX_LISTPACK_VALUE: usize = 64;
+
+// Small sets are kept in the compact encodings redis uses, a set is converted once it outgrows them and never back
+#[derive(Debug, Clone)]
+pub enum RedisSet {
+    // Sorted, for sets holding only integers
+    IntSet(Vec<i64>),
+    // Insertion ordered
+    Listpack(Vec<Vec<u8>>),
+    HashTable(HashSet<Vec<u8>>),
+}
+
+// The integer a member is stored as in an intset, only for its canonical decimal form
+fn set_integer(member: &[u8]) -> Option<i64> {
+    let integer = std::str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+    (integer.to_string().as_bytes() == member).then_some(integer)
 }
 
-pub async fn get_value(key: &[u8]) -> Option<Vec<u8>> {
+impl RedisSet {
+    // The encoding a new set starts with, picked for its first member
+    fn for_member(member: &[u8]) -> Self {
+        match set_integer(member) {
+            Some(_) => RedisSet::IntSet(Vec::new()),
+            None if member.len() <= SET_MAX_LISTPACK_VALUE => RedisSet::Listpack(Vec::new()),
+            None => RedisSet::HashTable(HashSet::new()),
+        }
+    }
+
+    pub fn from_members(members: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        let mut members = members.into_iter().peekable();
+        let mut set = match members.peek() {
+            Some(member) => RedisSet::for_member(member),
+            None => RedisSet::IntSet(Vec::new()),
+        };
+        for member in members {
+            set.insert(member);
+        }
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            RedisSet::IntSet(integers) => integers.len(),
+            RedisSet::Listpack(members) => members.len(),
+            RedisSet::HashTable(members) => members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            RedisSet::IntSet(integers) => set_integer(member).is_some_and(|integer| integers.binary_search(&integer).is_ok()),
+            RedisSet::Listpack(members) => members.iter().any(|existing| existing == member),
+            RedisSet::HashTable(members) => members.contains(member),
+        }
+    }
+
+    pub fn members(&self) -> Vec<Vec<u8>> {
+        match self {
+            RedisSet::IntSet(integers) => integers.iter().map(|integer| integer.to_string().into_bytes()).collect(),
+            RedisSet::Listpack(members) => members.clone(),
+            RedisSet::HashTable(members) => members.iter().cloned().collect(),
+        }
+    }
+
+    // Returns whether the member was added
+    pub fn insert(&mut self, member: Vec<u8>) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+        let fits_listpack = |length: usize, member: &[u8]| length < SET_MAX_LISTPACK_ENTRIES && member.len() <= SET_MAX_LISTPACK_VALUE;
+        match self {
+            RedisSet::IntSet(integers) => match set_integer(&member) {
+                Some(integer) if integers.len() < SET_MAX_INTSET_ENTRIES => {
+                    let position = integers.binary_search(&integer).unwrap_or_else(|position| position);
+                    integers.insert(position, integer);
+                    return true;
+                },
+                _ if fits_listpack(integers.len(), &member) => *self = RedisSet::Listpack(self.members()),
+                _ => *self = RedisSet::HashTable(self.members().into_iter().collect()),
+            },
+            RedisSet::Listpack(members) if !fits_listpack(members.len(), &member) => {
+                *self = RedisSet::HashTable(members.drain(..).collect());
+            },
+            _ => (),
+        }
+        match self {
+            RedisSet::Listpack(members) => members.push(member),
+            RedisSet::HashTable(members) => {
+                members.insert(member);
+            },
+            RedisSet::IntSet(_) => unreachable!("integers were inserted above"),
+        }
+        true
+    }
+
+    // Returns whether the member was there
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            RedisSet::IntSet(integers) => match set_integer(member).map(|integer| integers.binary_search(&integer)) {
+                Some(Ok(position)) => {
+                    integers.remove(position);
+                    true
+                },
+                _ => false,
+            },
+            RedisSet::Listpack(members) => match members.iter().position(|existing| existing == member) {
+                Some(position) => {
+                    members.remove(position);
+                    true
+                },
+                None => false,
+            },
+            RedisSet::HashTable(members) => members.remove(member),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            RedisSet::IntSet(_) => "intset",
+            RedisSet::Listpack(_) => "listpack",
+            RedisSet::HashTable(_) => "hashtable",
+        }
+    }
+}
+
+// The fields the hash commands see, expired ones wait for the expiry cycle like expired keys do
+pub fn live_fields(hash: &Hash, now: u64) -> impl Iterator<Item = (&Vec<u8>, &HashField)> {
+    hash.iter().filter(move |(_, field)| !field.is_expired(now))
+}
+
+pub fn live_field<'a>(hash: &'a Hash, field: &[u8], now: u64) -> Option<&'a HashField> {
+    hash.get(field).filter(|field| !field.is_expired(now))
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub value: Value,
+    // Absolute unix time in milliseconds
+    pub expires_at: Option<u64>,
+}
+
+impl Entry {
+    pub fn new(value: Value, expires_at: Option<u64>) -> Self {
+        Entry {value, expires_at}
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+
+    // Whether the key or one of its hash fields expires
+    fn has_ttl(&self) -> bool {
+        self.expires_at.is_some() || matches!(&self.value, Value::Hash(hash) if hash.values().any(|field| field.expires_at.is_some()))
+    }
+}
+
+pub async fn get_value(key: &[u8]) -> Result<Option<Vec<u8>>, String> {
     let database = DATABASE.lock().await;
     match database.get(key) {
-        Some(value) => {
-            return Some(value.to_owned())
+        Some(entry) if !entry.is_expired(unix_time_millis()) => match &entry.value {
+            Value::String(value) => Ok(Some(value.to_owned())),
+            _ => Err(WRONGTYPE.to_string()),
         },
-        None => return None,
+        _ => Ok(None),
     }
 }
 
 pub async fn set_value(key: &[u8], value: &[u8]) {
     let mut database = DATABASE.lock().await;
     let key = key.to_owned();
-    database.insert(key.clone(), value.to_owned());
+    database.insert(key.clone(), Entry::new(Value::String(value.to_owned()), None));
+    DIRTY.fetch_add(1, Ordering::Relaxed);
 }
 
 pub async fn get_config(key: &[u8]) -> Option<Vec<u8>> {
@@ -32,24 +277,425 @@ pub async fn get_config(key: &[u8]) -> Option<Vec<u8>> {
     }
 }
 
-#[allow(dead_code)]
 pub async fn set_config(key: &[u8], value: &[u8]) {
     let mut config = CONFIG.lock().await;
     let key = key.to_owned();
     config.insert(key.clone(), value.to_owned());
 }
 
-pub async fn set_value_expiry(key: &[u8], value: &[u8], expiry: u64) {
+pub async fn set_value_expires_at(key: &[u8], value: &[u8], expires_at: u64) {
     let mut database = DATABASE.lock().await;
     let key = key.to_owned();
-    database.insert(key.clone(), value.to_owned());
-    tokio::spawn(async move{
-        sleep(Duration::from_millis(expiry)).await;
-        delete_value(&key).await;
-    });
+    database.insert(key.clone(), Entry::new(Value::String(value.to_owned()), Some(expires_at)));
+    VOLATILE_KEYS.lock().await.insert(&key);
+    DIRTY.fetch_add(1, Ordering::Relaxed);
+}
+
+// Lets the active expiry cycle find a hash that was given field TTLs
+pub async fn track_volatile_key(key: &[u8]) {
+    VOLATILE_KEYS.lock().await.insert(key);
+}
+
+async fn is_master() -> bool {
+    get_config(b"role").await.as_deref() == Some(b"master")
+}
+
+pub async fn delete_value(key: &[u8]) -> bool {
+    // A replica applies the master's DEL even once the key expired locally, it is how the key goes away there
+    let is_master = is_master().await;
+    let mut database = DATABASE.lock().await;
+    match database.get(key) {
+        // Left for the expiry cycle, which tells the replicas about it
+        Some(entry) if is_master && entry.is_expired(unix_time_millis()) => false,
+        Some(_) => {
+            database.remove(key);
+            DIRTY.fetch_add(1, Ordering::Relaxed);
+            true
+        },
+        None => false,
+    }
+}
+
+// Runs `f` on the list stored at key, None if the key doesn't exist
+pub async fn read_list<T>(key: &[u8], f: impl FnOnce(&VecDeque<Vec<u8>>) -> T) -> Result<Option<T>, String> {
+    let database = DATABASE.lock().await;
+    match database.get(key) {
+        Some(entry) if !entry.is_expired(unix_time_millis()) => match &entry.value {
+            Value::List(list) => Ok(Some(f(list))),
+            _ => Err(WRONGTYPE.to_string()),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Whether a write finds key missing. Like redis' expireIfNeeded the master deletes an expired key first and
+// its DEL is propagated ahead of the write. A replica never expires keys itself, the master's stream decides
+async fn missing_for_write(database: &mut HashMap<Vec<u8>, Entry>, key: &[u8], is_master: bool) -> bool {
+    match database.get(key) {
+        None => true,
+        Some(entry) if is_master && entry.is_expired(unix_time_millis()) => {
+            database.remove(key);
+            DIRTY.fetch_add(1, Ordering::Relaxed);
+            EXPIRED_BY_WRITES.lock().await.push(key.to_vec());
+            true
+        },
+        Some(_) => false,
+    }
+}
+
+// The keys writes deleted since the last call, for their DELs to be propagated
+pub async fn take_expired_by_writes() -> Vec<Vec<u8>> {
+    std::mem::take(&mut *EXPIRED_BY_WRITES.lock().await)
+}
+
+// Runs `f` on the list stored at key, which is created first when `create` is set.
+// Like in redis lists are never stored empty, the key goes away with the last element
+pub async fn modify_list<T>(key: &[u8], create: bool, f: impl FnOnce(&mut VecDeque<Vec<u8>>) -> T) -> Result<Option<T>, String> {
+    let is_master = is_master().await;
+    let mut database = DATABASE.lock().await;
+    if missing_for_write(&mut database, key, is_master).await {
+        if !create {
+            return Ok(None);
+        }
+        database.insert(key.to_vec(), Entry::new(Value::List(VecDeque::new()), None));
+    }
+    let list = match database.get_mut(key).map(|entry| &mut entry.value) {
+        Some(Value::List(list)) => list,
+        _ => return Err(WRONGTYPE.to_string()),
+    };
+    let result = f(list);
+    if list.is_empty() {
+        database.remove(key);
+    }
+    DIRTY.fetch_add(1, Ordering::Relaxed);
+    Ok(Some(result))
+}
+
+// Pops an element from one end of source and pushes it to one end of destination, atomically
+pub async fn move_list_element(source: &[u8], destination: &[u8], from_front: bool, to_front: bool) -> Result<Option<Vec<u8>>, String> {
+    let mut database = DATABASE.lock().await;
+    let now = unix_time_millis();
+    match database.get(source) {
+        Some(entry) if !entry.is_expired(now) => if !matches!(entry.value, Value::List(_)) {
+            return Err(WRONGTYPE.to_string());
+        },
+        _ => return Ok(None),
+    }
+    match database.get(destination) {
+        Some(entry) if !entry.is_expired(now) => if !matches!(entry.value, Value::List(_)) {
+            return Err(WRONGTYPE.to_string());
+        },
+        _ => {
+            database.insert(destination.to_vec(), Entry::new(Value::List(VecDeque::new()), None));
+        },
+    }
+    let element = match database.get_mut(source).map(|entry| &mut entry.value) {
+        Some(Value::List(list)) => if from_front {list.pop_front()} else {list.pop_back()},
+        _ => None,
+    };
+    if let (Some(element), Some(Value::List(list))) = (&element, database.get_mut(destination).map(|entry| &mut entry.value)) {
+        if to_front {list.push_front(element.clone())} else {list.push_back(element.clone())}
+    }
+    if matches!(database.get(source).map(|entry| &entry.value), Some(Value::List(list)) if list.is_empty()) {
+        database.remove(source);
+    }
+    DIRTY.fetch_add(1, Ordering::Relaxed);
+    Ok(element)
+}
+
+// Runs `f` on the hash stored at key and the current time, None if the key doesn't exist
+pub async fn read_hash<T>(key: &[u8], f: impl FnOnce(&Hash, u64) -> T) -> Result<Option<T>, String> {
+    let database = DATABASE.lock().await;
+    let now = unix_time_millis();
+    match database.get(key) {
+        Some(entry) if !entry.is_expired(now) => match &entry.value {
+            Value::Hash(hash) => Ok(Some(f(hash, now))),
+            _ => Err(WRONGTYPE.to_string()),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Runs `f` on the hash stored at key and the current time, the hash is created first when `create` is set.
+// The key goes away with the last field
+pub async fn modify_hash<T>(key: &[u8], create: bool, f: impl FnOnce(&mut Hash, u64) -> T) -> Result<Option<T>, String> {
+    let is_master = is_master().await;
+    let mut database = DATABASE.lock().await;
+    let now = unix_time_millis();
+    if missing_for_write(&mut database, key, is_master).await {
+        if !create {
+            return Ok(None);
+        }
+        database.insert(key.to_vec(), Entry::new(Value::Hash(HashMap::new()), None));
+    }
+    let hash = match database.get_mut(key).map(|entry| &mut entry.value) {
+        Some(Value::Hash(hash)) => hash,
+        _ => return Err(WRONGTYPE.to_string()),
+    };
+    let result = f(hash, now);
+    if hash.is_empty() {
+        database.remove(key);
+    }
+    DIRTY.fetch_add(1, Ordering::Relaxed);
+    Ok(Some(result))
+}
+
+// Runs `f` on the set stored at key, None if the key doesn't exist
+pub async fn read_set<T>(key: &[u8], f: impl FnOnce(&RedisSet) -> T) -> Result<Option<T>, String> {
+    let database = DATABASE.lock().await;
+    match database.get(key) {
+        Some(entry) if !entry.is_expired(unix_time_millis()) => match &entry.value {
+            Value::Set(set) => Ok(Some(f(set))),
+            _ => Err(WRONGTYPE.to_string()),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Runs `f` on the set stored at key, which is created first when `create` is set with the encoding for `create`'s member.
+// The key goes away with the last member
+pub async fn modify_set<T>(key: &[u8], create: Option<&[u8]>, f: impl FnOnce(&mut RedisSet) -> T) -> Result<Option<T>, String> {
+    let is_master = is_master().await;
+    let mut database = DATABASE.lock().await;
+    if missing_for_write(&mut database, key, is_master).await {
+        let Some(member) = create else {
+            return Ok(None);
+        };
+        database.insert(key.to_vec(), Entry::new(Value::Set(RedisSet::for_member(member)), None));
+    }
+    let set = match database.get_mut(key).map(|entry| &mut entry.value) {
+        Some(Value::Set(set)) => set,
+        _ => return Err(WRONGTYPE.to_string()),
+    };
+    let result = f(set);
+    if set.is_empty() {
+        database.remove(key);
+    }
+    DIRTY.fetch_add(1, Ordering::Relaxed);
+    Ok(Some(result))
 }
 
-pub async fn delete_value(key: &[u8]) {
+// Runs `f` on the sorted set stored at key, None if the key doesn't exist
+pub async fn read_sorted_set<T>(key: &[u8], f: impl FnOnce(&SortedSet) -> T) -> Result<Option<T>, String> {
+    let database = DATABASE.lock().await;
+    match database.get(key) {
+        Some(entry) if !entry.is_expired(unix_time_millis()) => match &entry.value {
+            Value::SortedSet(sorted_set) => Ok(Some(f(sorted_set))),
+            _ => Err(WRONGTYPE.to_string()),
+        },
+        _ => Ok(None),
+    }
+}
+
+// Runs `f` on the sorted set stored at key, which is created first when `create` is set.
+// The key goes away with the last member
+pub async fn modify_sorted_set<T>(key: &[u8], create: bool, f: impl FnOnce(&mut SortedSet) -> T) -> Result<Option<T>, String> {
+    let is_master = is_master().await;
     let mut database = DATABASE.lock().await;
-    database.remove(key);
-}
\ No newline at end of file
+    if missing_for_write(&mut database, key, is_master).await {
+        if !create {
+            return Ok(None);
+        }
+        database.insert(key.to_vec(), Entry::new(Value::SortedSet(SortedSet::default()), None));
+    }
+    let sorted_set = match database.get_mut(key).map(|entry| &mut entry.value) {
+        Some(Value::SortedSet(sorted_set)) => sorted_set,
+        _ => return Err(WRONGTYPE.to_string()),
+    };
+    let result = f(sorted_set);
+    if sorted_set.is_empty() {
+        database.remove(key);
+    }
+    DIRTY.fetch_add(1, Ordering::Relaxed);
+    Ok(Some(result))
+}
+
+// Stores the result of a *STORE command, replacing whatever key held. An empty result deletes the key
+pub async fn store_value(key: &[u8], value: Option<Value>) {
+    let mut database = DATABASE.lock().await;
+    match value {
+        Some(value) => {
+            database.insert(key.to_vec(), Entry::new(value, None));
+        },
+        None => {
+            database.remove(key);
+        },
+    }
+    DIRTY.fetch_add(1, Ordering::Relaxed);
+}
+
+// OBJECT ENCODING, sets track theirs and the other types report what redis would pick for them
+pub async fn object_encoding(key: &[u8]) -> Option<&'static str> {
+    let database = DATABASE.lock().await;
+    let entry = database.get(key).filter(|entry| !entry.is_expired(unix_time_millis()))?;
+    let encoding = match &entry.value {
+        Value::String(value) if value.len() <= 20 && set_integer(value).is_some() => "int",
+        Value::String(value) if value.len() <= 44 => "embstr",
+        Value::String(_) => "raw",
+        // list-max-listpack-size -2, at most 8kb
+        Value::List(list) if list.iter().map(|element| element.len() + 2).sum::<usize>() <= 8192 => "listpack",
+        Value::List(_) => "quicklist",
+        Value::Hash(hash) if hash.len() > 128 || hash.iter().any(|(name, field)| name.len() > 64 || field.value.len() > 64) => "hashtable",
+        Value::Hash(hash) if hash.values().any(|field| field.expires_at.is_some()) => "listpackex",
+        Value::Hash(_) => "listpack",
+        Value::Set(set) => set.encoding(),
+        Value::SortedSet(sorted_set) => sorted_set.encoding(),
+    };
+    Some(encoding)
+}
+
+// Replaces the whole keyspace, used when loading a snapshot
+pub async fn load_database(entries: HashMap<Vec<u8>, Entry>) {
+    let mut database = DATABASE.lock().await;
+    let mut volatile_keys = VOLATILE_KEYS.lock().await;
+    *volatile_keys = VolatileKeys::default();
+    for (key, entry) in entries.iter() {
+        if entry.has_ttl() {
+            volatile_keys.insert(key);
+        }
+    }
+    *database = entries;
+}
+
+// Point in time copy of the keyspace, along with the dirty counter at that moment
+pub async fn snapshot_database() -> (HashMap<Vec<u8>, Entry>, u64) {
+    let database = DATABASE.lock().await;
+    (database.clone(), DIRTY.load(Ordering::Relaxed))
+}
+
+pub fn changes_since_last_save() -> u64 {
+    DIRTY.load(Ordering::Relaxed)
+}
+
+// Called after a snapshot taken at dirty counter `dirty` was saved.
+// A save that finished in between may have taken some of those changes off already, so the counter stops at 0
+pub fn mark_saved(dirty: u64) {
+    let _ = DIRTY.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |changes| Some(changes.saturating_sub(dirty)));
+}
+
+// What an active expiry cycle removed: whole keys, and hash fields per key
+#[derive(Default)]
+pub struct ExpiredEntries {
+    pub keys: Vec<Vec<u8>>,
+    // Hashes left without fields are removed with their last fields
+    pub fields: Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+}
+
+// Removes what expired by `now` among randomly sampled keys with a TTL, sampling again while more than
+// a quarter of a sample had expired, like redis' activeExpireCycle. Only the master calls this,
+// replicas keep expired keys and fields until the master's DEL or HDEL reaches them
+pub async fn active_expire(now: u64) -> ExpiredEntries {
+    let started = Instant::now();
+    let mut database = DATABASE.lock().await;
+    let mut volatile_keys = VOLATILE_KEYS.lock().await;
+    let mut expired = ExpiredEntries::default();
+    loop {
+        let keys = volatile_keys.sample(ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP);
+        let mut expired_in_sample = 0;
+        for key in keys.iter() {
+            let Some(entry) = database.get_mut(key) else {
+                volatile_keys.remove(key);
+                continue;
+            };
+            if entry.is_expired(now) {
+                database.remove(key);
+                volatile_keys.remove(key);
+                DIRTY.fetch_add(1, Ordering::Relaxed);
+                expired.keys.push(key.clone());
+                expired_in_sample += 1;
+                continue;
+            }
+            if let Value::Hash(hash) = &mut entry.value {
+                let fields: Vec<Vec<u8>> = hash.iter()
+                    .filter(|(_, field)| field.is_expired(now))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                if !fields.is_empty() {
+                    for field in fields.iter() {
+                        hash.remove(field);
+                    }
+                    DIRTY.fetch_add(1, Ordering::Relaxed);
+                    expired.fields.push((key.clone(), fields));
+                    expired_in_sample += 1;
+                }
+            }
+            match database.get(key) {
+                Some(Entry {value: Value::Hash(hash), ..}) if hash.is_empty() => {
+                    database.remove(key);
+                    volatile_keys.remove(key);
+                },
+                Some(entry) if !entry.has_ttl() => volatile_keys.remove(key),
+                _ => (),
+            }
+        }
+        if keys.len() < ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP || expired_in_sample * 4 <= keys.len() || started.elapsed() > ACTIVE_EXPIRE_CYCLE_TIME_LIMIT {
+            return expired;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(range: std::ops::Range<usize>, prefix: &str) -> Vec<Vec<u8>> {
+        range.map(|i| format!("{prefix}{i}").into_bytes()).collect()
+    }
+
+    #[test]
+    fn set_encoding_follows_the_first_member() {
+        assert_eq!(RedisSet::for_member(b"12").encoding(), "intset");
+        // Only the canonical decimal form of an integer goes in an intset
+        assert_eq!(RedisSet::for_member(b"012").encoding(), "listpack");
+        assert_eq!(RedisSet::for_member(b"a").encoding(), "listpack");
+        assert_eq!(RedisSet::for_member(&[b'a'; SET_MAX_LISTPACK_VALUE + 1]).encoding(), "hashtable");
+        assert_eq!(RedisSet::from_members(Vec::new()).encoding(), "intset");
+    }
+
+    #[test]
+    fn intset_stays_sorted_and_converts_on_a_string() {
+        let mut set = RedisSet::from_members(vec![b"3".to_vec(), b"-1".to_vec(), b"2".to_vec()]);
+        assert_eq!(set.encoding(), "intset");
+        assert_eq!(set.members(), vec![b"-1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+        assert!(!set.insert(b"2".to_vec()));
+        assert!(set.insert(b"a".to_vec()));
+        assert_eq!(set.encoding(), "listpack");
+        assert_eq!(set.len(), 4);
+        assert!(set.contains(b"-1") && set.contains(b"a"));
+    }
+
+    #[test]
+    fn intset_converts_past_its_entry_limit() {
+        let mut set = RedisSet::from_members(members(0..SET_MAX_INTSET_ENTRIES, ""));
+        assert_eq!(set.encoding(), "intset");
+        set.insert(SET_MAX_INTSET_ENTRIES.to_string().into_bytes());
+        // Too many members for a listpack too
+        assert_eq!(set.encoding(), "hashtable");
+        assert_eq!(set.len(), SET_MAX_INTSET_ENTRIES + 1);
+    }
+
+    #[test]
+    fn listpack_converts_on_a_long_member_or_past_its_entry_limit() {
+        let mut set = RedisSet::from_members(members(0..SET_MAX_LISTPACK_ENTRIES, "m"));
+        assert_eq!(set.encoding(), "listpack");
+        set.insert(b"one more".to_vec());
+        assert_eq!(set.encoding(), "hashtable");
+
+        let mut set = RedisSet::from_members(members(0..3, "m"));
+        set.insert(vec![b'a'; SET_MAX_LISTPACK_VALUE + 1]);
+        assert_eq!(set.encoding(), "hashtable");
+        assert_eq!(set.len(), 4);
+    }
+
+    #[test]
+    fn removing_members_keeps_the_encoding() {
+        let mut set = RedisSet::from_members(members(0..3, ""));
+        assert!(set.remove(b"1"));
+        assert!(!set.remove(b"1"));
+        assert!(!set.remove(b"a"));
+        assert_eq!(set.encoding(), "intset");
+        let mut set = RedisSet::from_members(members(0..3, "m"));
+        assert!(set.remove(b"m0"));
+        assert_eq!(set.members(), vec![b"m1".to_vec(), b"m2".to_vec()]);
+    }
+}
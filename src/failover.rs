@@ -0,0 +1,136 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_recursion::async_recursion;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
+
+use crate::{failover_to, get_config, replica_state, replicas_role, wait_for_replica_caught_up};
+
+const FAILOVER_STATE_NONE: u8 = 0;
+const FAILOVER_STATE_WAITING_FOR_SYNC: u8 = 1;
+const FAILOVER_STATE_IN_PROGRESS: u8 = 2;
+
+lazy_static! {
+    // Set while a failover runs, notified by FAILOVER ABORT
+    static ref FAILOVER_ABORT: Mutex<Option<Arc<Notify>>> = Mutex::new(None);
+    static ref WRITES_UNPAUSED: Notify = Notify::new();
+}
+
+static FAILOVER_STATE: AtomicU8 = AtomicU8::new(FAILOVER_STATE_NONE);
+// Writes wait while the master hands over its role, so the target can catch up
+static WRITES_PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub struct FailoverRequest {
+    pub target: Option<(String, String)>,
+    pub timeout: Option<u64>,
+    pub force: bool,
+}
+
+pub fn failover_state_name() -> &'static str {
+    match FAILOVER_STATE.load(Ordering::Relaxed) {
+        FAILOVER_STATE_WAITING_FOR_SYNC => "waiting-for-sync",
+        FAILOVER_STATE_IN_PROGRESS => "failover-in-progress",
+        _ => "no-failover",
+    }
+}
+
+pub fn writes_paused() -> bool {
+    WRITES_PAUSED.load(Ordering::Relaxed)
+}
+
+pub async fn wait_for_writes_unpaused() {
+    loop {
+        // Registered before checking, so an unpause in between still wakes this writer
+        let notified = WRITES_UNPAUSED.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        if !writes_paused() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+fn unpause_writes() {
+    WRITES_PAUSED.store(false, Ordering::Relaxed);
+    WRITES_UNPAUSED.notify_waiters();
+}
+
+// Validates the request and starts the failover in the background, like redis it replies before it is done
+pub async fn start_failover(mut request: FailoverRequest) -> Result<(), String> {
+    if get_config(b"role").await.as_deref() != Some(b"master") {
+        return Err(String::from("FAILOVER is not valid when server is a replica."));
+    }
+    if replicas_role().await.is_empty() {
+        return Err(String::from("FAILOVER requires connected replicas."));
+    }
+    if request.force && (request.target.is_none() || request.timeout.is_none()) {
+        return Err(String::from("FAILOVER with force option requires both a timeout and target HOST and IP."));
+    }
+    if let Some((host, port)) = &mut request.target {
+        match replica_state(host, port).await {
+            None => return Err(String::from("FAILOVER target HOST and PORT is not a replica.")),
+            Some((_, state)) if state != "online" => return Err(String::from("FAILOVER target replica is not online.")),
+            // The target is followed by the address it connected from, whatever name it was given by
+            Some((ip, _)) => *host = ip,
+        }
+    }
+    let mut failover_abort = FAILOVER_ABORT.lock().await;
+    if failover_abort.is_some() {
+        return Err(String::from("FAILOVER already in progress."));
+    }
+    let abort = Arc::new(Notify::new());
+    *failover_abort = Some(abort.clone());
+    WRITES_PAUSED.store(true, Ordering::Relaxed);
+    FAILOVER_STATE.store(FAILOVER_STATE_WAITING_FOR_SYNC, Ordering::Relaxed);
+    tokio::spawn(async move {
+        run_failover(request, abort).await;
+        FAILOVER_STATE.store(FAILOVER_STATE_NONE, Ordering::Relaxed);
+        *FAILOVER_ABORT.lock().await = None;
+        unpause_writes();
+    });
+    Ok(())
+}
+
+pub async fn abort_failover() -> Result<(), String> {
+    // The handshake with the target decides the outcome once it started
+    if FAILOVER_STATE.load(Ordering::Relaxed) == FAILOVER_STATE_IN_PROGRESS {
+        return Err(String::from("FAILOVER can't be aborted while the target takes over."));
+    }
+    match FAILOVER_ABORT.lock().await.as_ref() {
+        Some(abort) => {
+            abort.notify_one();
+            Ok(())
+        },
+        None => Err(String::from("No failover in progress.")),
+    }
+}
+
+// Boxed because the handshake with the target goes back through command execution
+#[async_recursion]
+async fn run_failover(request: FailoverRequest, abort: Arc<Notify>) {
+    let deadline = request.timeout.map(|timeout| Instant::now() + Duration::from_millis(timeout));
+    let caught_up = tokio::select! {
+        _ = abort.notified() => {
+            println!("FAILOVER aborted");
+            return;
+        },
+        caught_up = wait_for_replica_caught_up(request.target.clone(), deadline) => caught_up,
+    };
+    let target = match caught_up {
+        Some(target) => target,
+        None if request.force => request.target.unwrap(),
+        None => {
+            println!("FAILOVER timed out before a replica caught up");
+            return;
+        },
+    };
+    FAILOVER_STATE.store(FAILOVER_STATE_IN_PROGRESS, Ordering::Relaxed);
+    let (host, port) = target;
+    println!("FAILOVER handing over to {host}:{port}");
+    if let Err(e) = failover_to(host, port).await {
+        println!("FAILOVER target rejected the handover: {e}");
+    }
+}
@@ -0,0 +1,488 @@
+use std::vec::IntoIter;
+
+use rand::seq::SliceRandom;
+
+use crate::{array, bulk_arguments, bulk_or_null, error, get_config, integer, live_field, live_fields, modify_hash, parse_cursor, parse_integer, parse_random_count, parse_scan_options, read_hash, scan_page, scan_reply, track_volatile_key, unix_time_millis, wrong_arguments, Hash, HashField, Propagation, RedisCommand, RespDatatype, SYNTAX_ERROR};
+
+// Field expiry times past this are refused, like redis' EB_EXPIRE_TIME_MAX
+const MAX_FIELD_EXPIRE_TIME: i64 = (1 << 48) - 1;
+const FIELDS_MISSING: &str = "ERR Mandatory argument FIELDS is missing or not at the right position";
+
+fn integers(integers: Vec<i64>) -> Option<RedisCommand> {
+    Some(RedisCommand::RespDatatype(RespDatatype::Array(integers.into_iter().map(RespDatatype::Integer).collect())))
+}
+
+pub async fn execute_hash_command(command: &[u8], array_iterator: IntoIter<RespDatatype>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let arguments = bulk_arguments(array_iterator);
+    match command {
+        b"HSET" | b"HMSET" => interpret_hset(command, arguments).await,
+        b"HSETNX" => interpret_hsetnx(command, arguments, propagation).await,
+        b"HGET" => interpret_hget(command, arguments).await,
+        b"HMGET" => interpret_hmget(command, arguments).await,
+        b"HDEL" => interpret_hdel(command, arguments, propagation).await,
+        b"HLEN" => interpret_hlen(command, arguments).await,
+        b"HEXISTS" => interpret_hexists(command, arguments).await,
+        b"HSTRLEN" => interpret_hstrlen(command, arguments).await,
+        b"HKEYS" | b"HVALS" | b"HGETALL" => interpret_hgetall(command, arguments).await,
+        b"HINCRBY" => interpret_hincrby(command, arguments).await,
+        b"HINCRBYFLOAT" => interpret_hincrbyfloat(command, arguments, propagation).await,
+        b"HSCAN" => interpret_hscan(command, arguments).await,
+        b"HRANDFIELD" => interpret_hrandfield(command, arguments).await,
+        b"HEXPIRE" | b"HPEXPIRE" | b"HEXPIREAT" | b"HPEXPIREAT" => interpret_hexpire(command, arguments, propagation).await,
+        b"HTTL" | b"HPTTL" | b"HEXPIRETIME" | b"HPEXPIRETIME" => interpret_httl(command, arguments).await,
+        b"HPERSIST" => interpret_hpersist(command, arguments, propagation).await,
+        _ => None,
+    }
+}
+
+async fn interpret_hset(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, pairs) = match arguments.split_first() {
+        Some((key, pairs)) if !pairs.is_empty() && pairs.len() % 2 == 0 => (key, pairs),
+        _ => return wrong_arguments(command),
+    };
+    // Setting a field clears its time to live
+    let added = modify_hash(key, true, |hash, now| {
+        pairs.chunks_exact(2)
+            .filter(|pair| hash.insert(pair[0].clone(), HashField::new(pair[1].clone())).is_none_or(|previous| previous.is_expired(now)))
+            .count()
+    }).await;
+    match added {
+        Ok(_) if command == b"HMSET" => Some(RedisCommand::Ok),
+        Ok(added) => integer(added.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hsetnx(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [key, field, value] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let added = modify_hash(key, true, |hash, now| {
+        if live_field(hash, field, now).is_some() {
+            return false;
+        }
+        hash.insert(field.clone(), HashField::new(value.clone()));
+        true
+    }).await;
+    match added {
+        Ok(Some(true)) => integer(1),
+        Ok(_) => {
+            propagation.suppress();
+            integer(0)
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hget(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, field] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_hash(key, |hash, now| live_field(hash, field, now).map(|field| field.value.clone())).await {
+        Ok(value) => bulk_or_null(value.flatten()),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hmget(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, fields) = match arguments.split_first() {
+        Some((key, fields)) if !fields.is_empty() => (key, fields),
+        _ => return wrong_arguments(command),
+    };
+    let values = read_hash(key, |hash, now| {
+        fields.iter()
+            .map(|field| live_field(hash, field, now).map(|field| field.value.clone()))
+            .collect::<Vec<Option<Vec<u8>>>>()
+    }).await;
+    match values {
+        Ok(values) => {
+            let values = values.unwrap_or_else(|| vec![None; fields.len()]);
+            let values = values.into_iter().map(|value| value.map_or(RespDatatype::NullBulkString, RespDatatype::BulkString)).collect();
+            Some(RedisCommand::RespDatatype(RespDatatype::Array(values)))
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hdel(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, fields) = match arguments.split_first() {
+        Some((key, fields)) if !fields.is_empty() => (key, fields),
+        _ => return wrong_arguments(command),
+    };
+    // Like DEL, a replica applies the master's HDEL even to fields that expired locally
+    let is_master = get_config(b"role").await.as_deref() == Some(b"master");
+    let removed = modify_hash(key, false, |hash, now| {
+        fields.iter()
+            .filter(|field| {
+                let visible = hash.get(*field).is_some_and(|field| !is_master || !field.is_expired(now));
+                visible && hash.remove(*field).is_some()
+            })
+            .count()
+    }).await;
+    match removed {
+        Ok(removed) => {
+            let removed = removed.unwrap_or(0);
+            if removed == 0 {
+                propagation.suppress();
+            }
+            integer(removed)
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hlen(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_hash(key, |hash, now| live_fields(hash, now).count()).await {
+        Ok(length) => integer(length.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hexists(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, field] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_hash(key, |hash, now| live_field(hash, field, now).is_some()).await {
+        Ok(exists) => integer(exists.unwrap_or(false) as usize),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hstrlen(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, field] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_hash(key, |hash, now| live_field(hash, field, now).map_or(0, |field| field.value.len())).await {
+        Ok(length) => integer(length.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hgetall(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let elements = read_hash(key, |hash, now| {
+        let mut elements = Vec::new();
+        for (name, field) in live_fields(hash, now) {
+            if command != b"HVALS" {
+                elements.push(name.clone());
+            }
+            if command != b"HKEYS" {
+                elements.push(field.value.clone());
+            }
+        }
+        elements
+    }).await;
+    match elements {
+        Ok(elements) => array(elements.unwrap_or_default()),
+        Err(e) => error(e),
+    }
+}
+
+// Sets field to `value`, an existing field keeps its time to live
+fn update_field(hash: &mut Hash, field: &[u8], value: Vec<u8>, now: u64) -> Option<u64> {
+    match hash.get_mut(field) {
+        Some(existing) if !existing.is_expired(now) => {
+            existing.value = value;
+            existing.expires_at
+        },
+        _ => {
+            hash.insert(field.to_vec(), HashField::new(value));
+            None
+        },
+    }
+}
+
+async fn interpret_hincrby(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, field, increment] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let increment = match parse_integer(increment) {
+        Ok(increment) => increment,
+        Err(e) => return error(e),
+    };
+    let value = modify_hash(key, true, |hash, now| {
+        let current = match live_field(hash, field, now) {
+            Some(current) => parse_integer(&current.value).map_err(|_| "ERR hash value is not an integer")?,
+            None => 0,
+        };
+        let value = current.checked_add(increment).ok_or("ERR increment or decrement would overflow")?;
+        update_field(hash, field, value.to_string().into_bytes(), now);
+        Ok::<i64, &str>(value)
+    }).await;
+    match value {
+        Ok(Some(Ok(value))) => Some(RedisCommand::RespDatatype(RespDatatype::Integer(value))),
+        Ok(Some(Err(e))) => error(e),
+        Ok(None) => error("ERR no such key"),
+        Err(e) => error(e),
+    }
+}
+
+fn parse_float(argument: &[u8]) -> Option<f64> {
+    String::from_utf8_lossy(argument).parse::<f64>().ok().filter(|float| float.is_finite())
+}
+
+async fn interpret_hincrbyfloat(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [key, field, increment] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let Some(increment) = parse_float(increment) else {
+        return error("ERR value is not a valid float");
+    };
+    let value = modify_hash(key, true, |hash, now| {
+        let current = match live_field(hash, field, now) {
+            Some(current) => parse_float(&current.value).ok_or("ERR hash value is not a float")?,
+            None => 0.0,
+        };
+        let value = current + increment;
+        if !value.is_finite() {
+            return Err("ERR increment would produce NaN or Infinity");
+        }
+        let value = value.to_string().into_bytes();
+        let expires_at = update_field(hash, field, value.clone(), now);
+        Ok((value, expires_at))
+    }).await;
+    match value {
+        Ok(Some(Ok((value, expires_at)))) => {
+            // The replicas store the result rather than redoing the float arithmetic, HSET drops the time to live
+            propagation.rewrite(vec![b"HSET".to_vec(), key.clone(), field.clone(), value.clone()]);
+            if let Some(expires_at) = expires_at {
+                propagation.rewrite(vec![b"HPEXPIREAT".to_vec(), key.clone(), expires_at.to_string().into_bytes(), b"FIELDS".to_vec(), b"1".to_vec(), field.clone()]);
+            }
+            Some(RedisCommand::BulkString(value))
+        },
+        Ok(Some(Err(e))) => error(e),
+        Ok(None) => error("ERR no such key"),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_hscan(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, cursor, options @ ..] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let cursor = match parse_cursor(cursor) {
+        Ok(cursor) => cursor,
+        Err(e) => return error(e),
+    };
+    let scan_options = match parse_scan_options(options, true) {
+        Ok(scan_options) => scan_options,
+        Err(e) => return error(e),
+    };
+    let scanned = read_hash(key, |hash, now| {
+        let fields = live_fields(hash, now).map(|(name, field)| (&name[..], &field.value));
+        let (next_cursor, page) = scan_page(fields, cursor, &scan_options);
+        let mut elements = Vec::new();
+        for (name, value) in page {
+            elements.push(name.to_vec());
+            if !scan_options.no_values {
+                elements.push(value.clone());
+            }
+        }
+        (next_cursor, elements)
+    }).await;
+    match scanned {
+        Ok(scanned) => {
+            let (next_cursor, elements) = scanned.unwrap_or_default();
+            scan_reply(next_cursor, elements)
+        },
+        Err(e) => error(e),
+    }
+}
+
+// HRANDFIELD key [count [WITHVALUES]], a negative count may return the same field more than once
+async fn interpret_hrandfield(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, count, with_values) = match &arguments[..] {
+        [key] => (key, None, false),
+        [key, count] => (key, Some(count), false),
+        [key, count, option] if option.eq_ignore_ascii_case(b"WITHVALUES") => (key, Some(count), true),
+        [_, _, _] => return error(SYNTAX_ERROR),
+        _ => return wrong_arguments(command),
+    };
+    let count = match count.map(|count| parse_random_count(count)) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(e)) => return error(e),
+        None => None,
+    };
+    let picked = read_hash(key, |hash, now| {
+        let fields: Vec<(&Vec<u8>, &HashField)> = live_fields(hash, now).collect();
+        let rng = &mut rand::thread_rng();
+        let picked: Vec<&(&Vec<u8>, &HashField)> = match count {
+            None => fields.choose(rng).into_iter().collect(),
+            Some(count) if count >= 0 => fields.choose_multiple(rng, count as usize).collect(),
+            Some(count) => (0..count.unsigned_abs()).filter_map(|_| fields.choose(rng)).collect(),
+        };
+        picked.into_iter().map(|(name, field)| (name.to_vec(), field.value.clone())).collect::<Vec<(Vec<u8>, Vec<u8>)>>()
+    }).await;
+    let picked = match picked {
+        Ok(picked) => picked.unwrap_or_default(),
+        Err(e) => return error(e),
+    };
+    if count.is_none() {
+        return bulk_or_null(picked.into_iter().next().map(|(name, _)| name));
+    }
+    let mut elements = Vec::new();
+    for (name, value) in picked {
+        elements.push(name);
+        if with_values {
+            elements.push(value);
+        }
+    }
+    array(elements)
+}
+
+// FIELDS numfields field [field ...], how the field expiry commands end
+fn parse_fields(arguments: &[Vec<u8>]) -> Result<&[Vec<u8>], &'static str> {
+    let [keyword, numfields, fields @ ..] = arguments else {
+        return Err(FIELDS_MISSING);
+    };
+    if !keyword.eq_ignore_ascii_case(b"FIELDS") {
+        return Err(FIELDS_MISSING);
+    }
+    match parse_integer(numfields) {
+        Ok(numfields) if numfields <= 0 => Err("ERR Parameter `numFields` should be greater than 0"),
+        Ok(numfields) if numfields as usize == fields.len() => Ok(fields),
+        Ok(_) => Err("ERR The `numfields` parameter must match the number of arguments"),
+        Err(e) => Err(e),
+    }
+}
+
+// Replies per field: -2 no such field, 0 the condition wasn't met, 1 the time was set, 2 the field was deleted as the time passed already
+async fn interpret_hexpire(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [key, time, rest @ ..] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let (condition, rest) = match rest.split_first() {
+        Some((condition, rest)) if !condition.eq_ignore_ascii_case(b"FIELDS") => (Some(condition.to_ascii_uppercase()), rest),
+        _ => (None, rest),
+    };
+    if condition.as_deref().is_some_and(|condition| !matches!(condition, b"NX" | b"XX" | b"GT" | b"LT")) {
+        return error(FIELDS_MISSING);
+    }
+    let fields = match parse_fields(rest) {
+        Ok(fields) => fields,
+        Err(e) => return error(e),
+    };
+    let time = match parse_integer(time) {
+        Ok(time) => time,
+        Err(e) => return error(e),
+    };
+    let unit = if matches!(command, b"HEXPIRE" | b"HEXPIREAT") {1000} else {1};
+    let base = if matches!(command, b"HEXPIRE" | b"HPEXPIRE") {unix_time_millis() as i64} else {0};
+    let expires_at = match time.checked_mul(unit).and_then(|time| time.checked_add(base)) {
+        Some(expires_at) if time >= 0 && expires_at <= MAX_FIELD_EXPIRE_TIME => expires_at as u64,
+        _ => return error(format!("ERR invalid expire time, must be >= 0 and <= {MAX_FIELD_EXPIRE_TIME}")),
+    };
+    let results = modify_hash(key, false, |hash, now| {
+        fields.iter().map(|field| {
+            let current = match live_field(hash, field, now) {
+                Some(current) => current.expires_at,
+                None => return -2,
+            };
+            // A field without a time to live counts as expiring never
+            let allowed = match condition.as_deref() {
+                Some(b"NX") => current.is_none(),
+                Some(b"XX") => current.is_some(),
+                Some(b"GT") => current.is_some_and(|current| expires_at > current),
+                Some(b"LT") => current.is_none_or(|current| expires_at < current),
+                _ => true,
+            };
+            if !allowed {
+                return 0;
+            }
+            if expires_at <= now {
+                hash.remove(field);
+                return 2;
+            }
+            if let Some(field) = hash.get_mut(field) {
+                field.expires_at = Some(expires_at);
+            }
+            1
+        }).collect::<Vec<i64>>()
+    }).await;
+    let results = match results {
+        Ok(results) => results.unwrap_or_else(|| vec![-2; fields.len()]),
+        Err(e) => return error(e),
+    };
+    // The replicas get the absolute time, and the deletions as HDEL
+    let with_result = |wanted: i64| -> Vec<Vec<u8>> {
+        fields.iter().zip(results.iter()).filter(|(_, result)| **result == wanted).map(|(field, _)| field.clone()).collect()
+    };
+    let (set, deleted) = (with_result(1), with_result(2));
+    if set.is_empty() && deleted.is_empty() {
+        propagation.suppress();
+    }
+    if !set.is_empty() {
+        track_volatile_key(key).await;
+        let header = vec![b"HPEXPIREAT".to_vec(), key.clone(), expires_at.to_string().into_bytes(), b"FIELDS".to_vec(), set.len().to_string().into_bytes()];
+        propagation.rewrite([header, set].concat());
+    }
+    if !deleted.is_empty() {
+        propagation.rewrite([vec![b"HDEL".to_vec(), key.clone()], deleted].concat());
+    }
+    integers(results)
+}
+
+// Replies per field: -2 no such field, -1 no time to live, otherwise the time to live or the expiry time
+async fn interpret_httl(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, rest @ ..] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let fields = match parse_fields(rest) {
+        Ok(fields) => fields,
+        Err(e) => return error(e),
+    };
+    let results = read_hash(key, |hash, now| {
+        fields.iter().map(|field| {
+            let expires_at = match live_field(hash, field, now) {
+                Some(HashField {expires_at: Some(expires_at), ..}) => *expires_at,
+                Some(_) => return -1,
+                None => return -2,
+            };
+            let result = match command {
+                b"HTTL" => (expires_at - now).div_ceil(1000),
+                b"HPTTL" => expires_at - now,
+                b"HEXPIRETIME" => expires_at / 1000,
+                _ => expires_at,
+            };
+            result as i64
+        }).collect::<Vec<i64>>()
+    }).await;
+    match results {
+        Ok(results) => integers(results.unwrap_or_else(|| vec![-2; fields.len()])),
+        Err(e) => error(e),
+    }
+}
+
+// Replies per field: -2 no such field, -1 no time to live, 1 the time to live was removed
+async fn interpret_hpersist(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [key, rest @ ..] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let fields = match parse_fields(rest) {
+        Ok(fields) => fields,
+        Err(e) => return error(e),
+    };
+    let results = modify_hash(key, false, |hash, now| {
+        fields.iter().map(|field| match hash.get_mut(field) {
+            Some(field) if field.is_expired(now) => -2,
+            Some(field) => match field.expires_at.take() {
+                Some(_) => 1,
+                None => -1,
+            },
+            None => -2,
+        }).collect::<Vec<i64>>()
+    }).await;
+    let results = match results {
+        Ok(results) => results.unwrap_or_else(|| vec![-2; fields.len()]),
+        Err(e) => return error(e),
+    };
+    if !results.contains(&1) {
+        propagation.suppress();
+    }
+    integers(results)
+}
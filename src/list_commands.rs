@@ -0,0 +1,463 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::vec::IntoIter;
+
+use crate::{array, bulk_arguments, bulk_or_null, error, index_range, integer, modify_list, move_list_element, parse_integer, pop_sorted_set, read_list, read_sorted_set, wrong_arguments, Propagation, RedisCommand, RespDatatype, NOT_AN_INTEGER, SYNTAX_ERROR};
+
+// Resolves a possibly negative index, None if it falls outside the list
+fn list_index(index: i64, length: usize) -> Option<usize> {
+    let index = if index < 0 {index + length as i64} else {index};
+    (0..length as i64).contains(&index).then_some(index as usize)
+}
+
+// Which end of the list LEFT and RIGHT refer to, true for the head
+fn parse_side(side: &[u8]) -> Option<bool> {
+    match &side.to_ascii_uppercase()[..] {
+        b"LEFT" => Some(true),
+        b"RIGHT" => Some(false),
+        _ => None,
+    }
+}
+
+pub async fn execute_list_command(command: &[u8], array_iterator: IntoIter<RespDatatype>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let arguments = bulk_arguments(array_iterator);
+    match command {
+        b"LPUSH" | b"RPUSH" => interpret_push(command, arguments).await,
+        b"LPOP" | b"RPOP" => interpret_pop(command, arguments, propagation).await,
+        b"LRANGE" => interpret_lrange(command, arguments).await,
+        b"LLEN" => interpret_llen(command, arguments).await,
+        b"LINDEX" => interpret_lindex(command, arguments).await,
+        b"LSET" => interpret_lset(command, arguments).await,
+        b"LREM" => interpret_lrem(command, arguments, propagation).await,
+        b"LTRIM" => interpret_ltrim(command, arguments).await,
+        b"LINSERT" => interpret_linsert(command, arguments, propagation).await,
+        b"LMOVE" => interpret_lmove(command, arguments, propagation).await,
+        b"BLPOP" | b"BRPOP" => interpret_blocking_pop(command, arguments, propagation).await,
+        b"BLMOVE" => interpret_blmove(command, arguments, propagation).await,
+        b"LMPOP" | b"BLMPOP" => interpret_lmpop(command, arguments, propagation).await,
+        _ => None,
+    }
+}
+
+async fn interpret_push(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, elements) = match arguments.split_first() {
+        Some((key, elements)) if !elements.is_empty() => (key, elements),
+        _ => return wrong_arguments(command),
+    };
+    let to_front = command == b"LPUSH";
+    let length = modify_list(key, true, |list| {
+        for element in elements {
+            if to_front {list.push_front(element.clone())} else {list.push_back(element.clone())}
+        }
+        list.len()
+    }).await;
+    match length {
+        Ok(length) => integer(length.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_pop(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, count) = match &arguments[..] {
+        [key] => (key, None),
+        [key, count] => match parse_integer(count) {
+            Ok(count) if count >= 0 => (key, Some(count as usize)),
+            Ok(_) => return error("ERR value is out of range, must be positive"),
+            Err(e) => return error(e),
+        },
+        _ => return wrong_arguments(command),
+    };
+    let from_front = command == b"LPOP";
+    let popped = modify_list(key, false, |list| {
+        let amount = count.unwrap_or(1).min(list.len());
+        (0..amount).filter_map(|_| if from_front {list.pop_front()} else {list.pop_back()}).collect::<Vec<Vec<u8>>>()
+    }).await;
+    let popped = match popped {
+        Ok(popped) => popped,
+        Err(e) => return error(e),
+    };
+    if popped.as_ref().is_none_or(Vec::is_empty) {
+        propagation.suppress();
+    }
+    // Without a count the reply is a single element, with one it is an array
+    match (popped, count) {
+        (Some(popped), None) => bulk_or_null(popped.into_iter().next()),
+        (Some(popped), Some(_)) => array(popped),
+        (None, None) => Some(RedisCommand::NullBulkString),
+        (None, Some(_)) => Some(RedisCommand::RespDatatype(RespDatatype::NullArray)),
+    }
+}
+
+async fn interpret_lrange(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, start, stop] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let (start, stop) = match (parse_integer(start), parse_integer(stop)) {
+        (Ok(start), Ok(stop)) => (start, stop),
+        _ => return error(NOT_AN_INTEGER),
+    };
+    let elements = read_list(key, |list| match index_range(start, stop, list.len()) {
+        Some((start, stop)) => list.range(start..=stop).cloned().collect(),
+        None => Vec::new(),
+    }).await;
+    match elements {
+        Ok(elements) => array(elements.unwrap_or_default()),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_llen(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_list(key, VecDeque::len).await {
+        Ok(length) => integer(length.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_lindex(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, index] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let index = match parse_integer(index) {
+        Ok(index) => index,
+        Err(e) => return error(e),
+    };
+    let element = read_list(key, |list| list_index(index, list.len()).map(|index| list[index].clone())).await;
+    match element {
+        Ok(element) => bulk_or_null(element.flatten()),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_lset(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, index, element] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let index = match parse_integer(index) {
+        Ok(index) => index,
+        Err(e) => return error(e),
+    };
+    let set = modify_list(key, false, |list| match list_index(index, list.len()) {
+        Some(index) => {
+            list[index] = element.clone();
+            true
+        },
+        None => false,
+    }).await;
+    match set {
+        Ok(Some(true)) => Some(RedisCommand::Ok),
+        Ok(Some(false)) => error("ERR index out of range"),
+        Ok(None) => error("ERR no such key"),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_lrem(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [key, count, element] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let count = match parse_integer(count) {
+        Ok(count) => count,
+        Err(e) => return error(e),
+    };
+    // A negative count removes from the tail, zero removes every occurrence
+    let limit = if count == 0 {usize::MAX} else {count.unsigned_abs() as usize};
+    let removed = modify_list(key, false, |list| {
+        let mut removed = 0;
+        let mut kept = VecDeque::with_capacity(list.len());
+        if count >= 0 {
+            for item in list.drain(..) {
+                if removed < limit && item == *element {
+                    removed += 1;
+                } else {
+                    kept.push_back(item);
+                }
+            }
+        } else {
+            for item in list.drain(..).rev() {
+                if removed < limit && item == *element {
+                    removed += 1;
+                } else {
+                    kept.push_front(item);
+                }
+            }
+        }
+        *list = kept;
+        removed
+    }).await;
+    match removed {
+        Ok(removed) => {
+            let removed = removed.unwrap_or(0);
+            if removed == 0 {
+                propagation.suppress();
+            }
+            integer(removed)
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_ltrim(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, start, stop] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let (start, stop) = match (parse_integer(start), parse_integer(stop)) {
+        (Ok(start), Ok(stop)) => (start, stop),
+        _ => return error(NOT_AN_INTEGER),
+    };
+    let trimmed = modify_list(key, false, |list| match index_range(start, stop, list.len()) {
+        Some((start, stop)) => {
+            list.truncate(stop + 1);
+            list.drain(..start);
+        },
+        None => list.clear(),
+    }).await;
+    match trimmed {
+        Ok(_) => Some(RedisCommand::Ok),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_linsert(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [key, position, pivot, element] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let after = match &position.to_ascii_uppercase()[..] {
+        b"BEFORE" => false,
+        b"AFTER" => true,
+        _ => return error(SYNTAX_ERROR),
+    };
+    let inserted = modify_list(key, false, |list| {
+        let index = list.iter().position(|item| item == pivot)?;
+        list.insert(if after {index + 1} else {index}, element.clone());
+        Some(list.len())
+    }).await;
+    // The new length, -1 when the pivot wasn't found and 0 when the key doesn't exist
+    match inserted {
+        Ok(Some(Some(length))) => integer(length),
+        Ok(Some(None)) => {
+            propagation.suppress();
+            Some(RedisCommand::RespDatatype(RespDatatype::Integer(-1)))
+        },
+        Ok(None) => {
+            propagation.suppress();
+            integer(0)
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_lmove(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [source, destination, from, to] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let (Some(from_front), Some(to_front)) = (parse_side(from), parse_side(to)) else {
+        return error(SYNTAX_ERROR);
+    };
+    match move_list_element(source, destination, from_front, to_front).await {
+        Ok(Some(element)) => Some(RedisCommand::BulkString(element)),
+        Ok(None) => {
+            propagation.suppress();
+            Some(RedisCommand::NullBulkString)
+        },
+        Err(e) => error(e),
+    }
+}
+
+// The pop a blocked client is waiting to run, once one of its keys holds a list, or a sorted set for BZPOPMIN
+#[derive(Debug, Clone)]
+pub enum BlockedPop {
+    Pop {from_front: bool},
+    MultiPop {from_front: bool, count: usize},
+    Move {destination: Vec<u8>, from_front: bool, to_front: bool},
+    SortedSetPop {max: bool},
+}
+
+// A blocking command that found all its keys empty, the client waits for one of them
+#[derive(Debug)]
+pub struct BlockRequest {
+    pub keys: Vec<Vec<u8>>,
+    pub operation: BlockedPop,
+    // None blocks forever
+    pub timeout: Option<Duration>,
+}
+
+impl BlockedPop {
+    // Pops from key if it holds elements. The replicas get the equivalent non-blocking command
+    pub async fn pop(&self, key: &[u8], propagation: &mut Propagation) -> Option<RedisCommand> {
+        match self {
+            BlockedPop::Pop {from_front} => {
+                let popped = modify_list(key, false, |list| if *from_front {list.pop_front()} else {list.pop_back()}).await;
+                match popped {
+                    Ok(Some(Some(element))) => {
+                        propagation.rewrite(vec![pop_command(*from_front), key.to_vec()]);
+                        array(vec![key.to_vec(), element])
+                    },
+                    Ok(_) => None,
+                    Err(e) => error(e),
+                }
+            },
+            BlockedPop::MultiPop {from_front, count} => {
+                let popped = modify_list(key, false, |list| {
+                    let amount = (*count).min(list.len());
+                    (0..amount).filter_map(|_| if *from_front {list.pop_front()} else {list.pop_back()}).collect::<Vec<Vec<u8>>>()
+                }).await;
+                match popped {
+                    Ok(Some(popped)) if !popped.is_empty() => {
+                        propagation.rewrite(vec![pop_command(*from_front), key.to_vec(), popped.len().to_string().into_bytes()]);
+                        Some(RedisCommand::RespDatatype(RespDatatype::Array(vec![
+                            RespDatatype::BulkString(key.to_vec()),
+                            RespDatatype::Array(popped.into_iter().map(RespDatatype::BulkString).collect()),
+                        ])))
+                    },
+                    Ok(_) => None,
+                    Err(e) => error(e),
+                }
+            },
+            BlockedPop::Move {destination, from_front, to_front} => {
+                match move_list_element(key, destination, *from_front, *to_front).await {
+                    Ok(Some(element)) => {
+                        propagation.rewrite(vec![b"LMOVE".to_vec(), key.to_vec(), destination.clone(), side(*from_front), side(*to_front)]);
+                        Some(RedisCommand::BulkString(element))
+                    },
+                    Ok(None) => None,
+                    Err(e) => error(e),
+                }
+            },
+            BlockedPop::SortedSetPop {max} => match pop_sorted_set(key, *max, 1).await {
+                Ok(popped) => {
+                    let (member, score) = popped.into_iter().next()?;
+                    let command = if *max {b"ZPOPMAX".to_vec()} else {b"ZPOPMIN".to_vec()};
+                    propagation.rewrite(vec![command, key.to_vec()]);
+                    array(vec![key.to_vec(), member, score.to_string().into_bytes()])
+                },
+                Err(e) => error(e),
+            },
+        }
+    }
+
+    // Whether key holds the type this pop waits for
+    pub async fn can_pop_from(&self, key: &[u8]) -> bool {
+        match self {
+            BlockedPop::SortedSetPop {..} => matches!(read_sorted_set(key, |_| ()).await, Ok(Some(()))),
+            _ => matches!(read_list(key, |_| ()).await, Ok(Some(()))),
+        }
+    }
+
+    // What the client gets when the timeout elapses, or when the command runs inside MULTI
+    pub fn timeout_reply(&self) -> RedisCommand {
+        match self {
+            BlockedPop::Move {..} => RedisCommand::NullBulkString,
+            _ => RedisCommand::RespDatatype(RespDatatype::NullArray),
+        }
+    }
+}
+
+fn pop_command(from_front: bool) -> Vec<u8> {
+    if from_front {b"LPOP".to_vec()} else {b"RPOP".to_vec()}
+}
+
+fn side(front: bool) -> Vec<u8> {
+    if front {b"LEFT".to_vec()} else {b"RIGHT".to_vec()}
+}
+
+// Timeouts are in seconds with decimals allowed, 0 blocks forever
+pub fn parse_timeout(timeout: &[u8]) -> Result<Option<Duration>, &'static str> {
+    let timeout = String::from_utf8_lossy(timeout).parse::<f64>()
+        .ok()
+        .filter(|timeout| timeout.is_finite())
+        .ok_or("ERR timeout is not a float or out of range")?;
+    if timeout < 0.0 {
+        return Err("ERR timeout is negative");
+    }
+    if timeout == 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(timeout).map(Some).map_err(|_| "ERR timeout is out of range")
+}
+
+// Tries the keys in order and pops from the first non-empty one, otherwise the client has to block
+pub async fn pop_or_block(keys: Vec<Vec<u8>>, operation: BlockedPop, timeout: Option<Duration>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    for key in keys.iter() {
+        if let Some(redis_command) = operation.pop(key, propagation).await {
+            return Some(redis_command);
+        }
+    }
+    Some(RedisCommand::WouldBlock(BlockRequest {keys, operation, timeout}))
+}
+
+async fn interpret_blocking_pop(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (timeout, keys) = match arguments.split_last() {
+        Some((timeout, keys)) if !keys.is_empty() => (timeout, keys),
+        _ => return wrong_arguments(command),
+    };
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return error(e),
+    };
+    let operation = BlockedPop::Pop {from_front: command == b"BLPOP"};
+    pop_or_block(keys.to_vec(), operation, timeout, propagation).await
+}
+
+async fn interpret_blmove(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [source, destination, from, to, timeout] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let (Some(from_front), Some(to_front)) = (parse_side(from), parse_side(to)) else {
+        return error(SYNTAX_ERROR);
+    };
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return error(e),
+    };
+    let operation = BlockedPop::Move {destination: destination.clone(), from_front, to_front};
+    pop_or_block(vec![source.clone()], operation, timeout, propagation).await
+}
+
+// LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count], BLMPOP takes a timeout first
+async fn interpret_lmpop(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let blocking = command == b"BLMPOP";
+    let mut arguments = arguments.into_iter();
+    let timeout = match blocking.then(|| arguments.next()) {
+        Some(Some(timeout)) => match parse_timeout(&timeout) {
+            Ok(timeout) => timeout,
+            Err(e) => return error(e),
+        },
+        Some(None) => return wrong_arguments(command),
+        None => None,
+    };
+    let numkeys = match arguments.next().map(|numkeys| parse_integer(&numkeys)) {
+        Some(Ok(numkeys)) if numkeys > 0 => numkeys as usize,
+        Some(Ok(_)) => return error("ERR numkeys should be greater than 0"),
+        Some(Err(e)) => return error(e),
+        None => return wrong_arguments(command),
+    };
+    let keys: Vec<Vec<u8>> = arguments.by_ref().take(numkeys).collect();
+    if keys.len() < numkeys {
+        return wrong_arguments(command);
+    }
+    let Some(from_front) = arguments.next().and_then(|side| parse_side(&side)) else {
+        return error(SYNTAX_ERROR);
+    };
+    let count = match (arguments.next(), arguments.next(), arguments.next()) {
+        (None, _, _) => 1,
+        (Some(option), Some(count), None) if option.eq_ignore_ascii_case(b"COUNT") => match parse_integer(&count) {
+            Ok(count) if count > 0 => count as usize,
+            _ => return error("ERR count should be greater than 0"),
+        },
+        _ => return error(SYNTAX_ERROR),
+    };
+    let operation = BlockedPop::MultiPop {from_front, count};
+    if blocking {
+        return pop_or_block(keys, operation, timeout, propagation).await;
+    }
+    for key in keys.iter() {
+        if let Some(redis_command) = operation.pop(key, propagation).await {
+            return Some(redis_command);
+        }
+    }
+    propagation.suppress();
+    Some(operation.timeout_reply())
+}
@@ -4,6 +4,9 @@ use resp_handler::*;
 mod command_interpreter;
 use command_interpreter::*;
 
+mod command_table;
+use command_table::*;
+
 mod command_responder;
 use command_responder::*;
 
@@ -19,6 +22,45 @@ use replica_handshake::*;
 mod replicas;
 use replicas::*;
 
+mod rdb;
+use rdb::*;
+
+mod config;
+use config::*;
+
+mod failover;
+use failover::*;
+
+mod pubsub;
+use pubsub::*;
+
+mod sentinel;
+use sentinel::*;
+
+mod command_helpers;
+use command_helpers::*;
+
+mod list_commands;
+use list_commands::*;
+
+mod hash_commands;
+use hash_commands::*;
+
+mod set_commands;
+use set_commands::*;
+
+mod sorted_set;
+use sorted_set::*;
+
+mod sorted_set_commands;
+use sorted_set_commands::*;
+
+mod blocking;
+use blocking::*;
+
+mod transaction;
+use transaction::*;
+
 use tokio::net::{TcpListener, TcpStream};
 use std::{env, path::Path};
 
@@ -29,6 +71,7 @@ const INCORRECT_FORMAT_PORT: &str = "Incorrect format for --port flag. Required
 const INCORRECT_FORMAT_REPLICAOF: &str = "Incorrect format for --replicaof flag. Required format \"--replicaof <MASTER_HOST MASTER_PORT>\"";
 const INCORRECT_FORMAT_DIR: &str = "Incorrect format for --dir flag. Required format \"--replicaof <path>\"";
 const INCORRECT_FORMAT_DBFILENAME: &str = "Incorrect format --dbfilename flag. Required \"--dbfilename <name>.rdb\"";
+const INCORRECT_FORMAT_SENTINEL: &str = "Incorrect format for --sentinel flag. Required format \"--sentinel <sentinel.conf>\"";
 
 #[tokio::main]
 async fn main() {
@@ -36,12 +79,15 @@ async fn main() {
     println!("Logs from your program will appear here!");
 
     let mut port = String::from("6379");
+    let mut port_given = false;
+    let mut sentinel_config: Option<String> = None;
     let mut role: &[u8] = b"master";
     let mut master_host = String::from("localhost");
     let mut master_port = String::from("6379");
     let mut args = env::args();
     let mut dir = String::from("./");
     let mut dbfilename = String::from("rdbfilename");
+    let mut tunable_configs: Vec<(String, String)> = Vec::new();
     
     args.next();
     while let Some(flag) = args.next() {
@@ -49,6 +95,7 @@ async fn main() {
             "--port" => {
                 port = args.next().expect(INCORRECT_FORMAT_PORT);
                 port.parse::<u16>().expect("Invalid port given");
+                port_given = true;
             },
             "--replicaof" => {
                 role = b"slave";
@@ -59,7 +106,6 @@ async fn main() {
                 if master_args.next() != None {
                     panic!("{}", INCORRECT_FORMAT_REPLICAOF);
                 }
-                send_handshake(&master_host, &master_port, &port).await.expect("Handshake failed");
             },
             "--dir" => {
                 let test_dir = args.next().expect(INCORRECT_FORMAT_DIR);
@@ -75,26 +121,57 @@ async fn main() {
                     dbfilename.push_str(".rdb");
                 }
             }
+            "--sentinel" => {
+                sentinel_config = Some(args.next().expect(INCORRECT_FORMAT_SENTINEL));
+            },
+            flag if flag.starts_with("--") && is_tunable_config(&flag[2..]) => {
+                let value = args.next().unwrap_or_else(|| panic!("Missing value for {flag} flag"));
+                tunable_configs.push((flag[2..].to_string(), value));
+            },
             flag => panic!("Unknown flag: \"{flag}\""),
         }
     }
     
+    // A sentinel only monitors other servers, the port from its config file applies unless --port was given
+    if let Some(sentinel_config) = sentinel_config {
+        run_sentinel(&sentinel_config, port_given.then_some(port)).await;
+        return;
+    }
+
     let listener = TcpListener::bind(format!("127.0.0.1:{port}")).await.expect("Couldn't start the server");
     
     let mut config = CONFIG.lock().await;
     config.insert(b"port".to_vec(), port.into_bytes());
     config.insert(b"role".to_vec(), role.to_vec());
-    config.insert(b"master_port".to_vec(), master_port.into_bytes());
-    config.insert(b"master_host".to_vec(), master_host.into_bytes());
-    config.insert(b"dir".to_vec(), dir.into_bytes());
-    config.insert(b"dbfilename".to_vec(), dbfilename.into_bytes());
-    if role == b"master" {
-        config.insert(b"master_replid".to_vec(), generate_master_replid());
-        config.insert(b"master_repl_offset".to_vec(), vec![b'0']);
-        start_replicas();
-    }
+    config.insert(b"master_port".to_vec(), master_port.clone().into_bytes());
+    config.insert(b"master_host".to_vec(), master_host.clone().into_bytes());
+    config.insert(b"dir".to_vec(), dir.clone().into_bytes());
+    config.insert(b"dbfilename".to_vec(), dbfilename.clone().into_bytes());
+    // A replica replaces it with its master's id after the first sync
+    config.insert(b"master_replid".to_vec(), generate_master_replid());
+    config.insert(b"master_replid2".to_vec(), vec![b'0'; 40]);
+    config.insert(b"second_repl_offset".to_vec(), b"-1".to_vec());
     drop(config);
 
+    init_tunable_configs().await;
+    for (name, value) in tunable_configs {
+        if let Err(e) = set_tunable_config(name.as_bytes(), value.as_bytes()).await {
+            panic!("{e}");
+        }
+    }
+
+    match load_rdb_file(&dir, &dbfilename).await {
+        Ok(keys_loaded) => println!("Loaded {keys_loaded} keys from the RDB file"),
+        Err(e) => panic!("Failed to load the RDB file: {e}"),
+    }
+
+    tokio::spawn(active_expire_cycle());
+
+    // The master's snapshot replaces what was loaded from disk
+    if role == b"slave" {
+        replicate_from(master_host, master_port).await;
+    }
+
     loop {
         let stream = listener.accept().await;
         
@@ -113,6 +190,7 @@ async fn handle_client(stream: TcpStream) {
     println!("Accepted new connection! Handling client");
     let mut resp_stream_handler = RespStreamHandler::new(stream);
     let mut replica_identifier: ReplicaIdentifier = ReplicaIdentifier::init();
+    let mut transaction = Transaction::default();
     loop {
         if resp_stream_handler.is_shutdown().await {
             break;
@@ -123,20 +201,37 @@ async fn handle_client(stream: TcpStream) {
         .expect("Failed to deserialize RESP object: ");
 
         println!("Interpreting");
-        let redis_command = interpret(resp_object, &collected)
+        let redis_command = transaction.interpret(resp_object, &collected)
         .await
         .expect("Failed to interpret Redis command");
+
+        // A blocked client gets its reply once it is served or timed out
+        let redis_command = match redis_command {
+            RedisCommand::Block(blocked_client) => match wait_until_served(blocked_client, &resp_stream_handler.stream).await {
+                Some(redis_command) => redis_command,
+                None => return,
+            },
+            redis_command => redis_command,
+        };
     
         println!("Responding");
         respond(&mut resp_stream_handler, &redis_command).await;
 
+        if let RedisCommand::Subscribe(channels) = redis_command {
+            match handle_subscriber(resp_stream_handler, channels).await {
+                Some(unsubscribed) => resp_stream_handler = unsubscribed,
+                None => return,
+            }
+            continue;
+        }
+
         if replica_identifier.is_replica(&redis_command) {
             break;
         }
     }
     
     if replica_identifier.is_synced() {
-        handle_replica(resp_stream_handler).await;
+        handle_replica(resp_stream_handler, replica_identifier).await;
     }
 }
 
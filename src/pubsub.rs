@@ -0,0 +1,160 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::vec::IntoIter;
+
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::Mutex;
+
+use crate::{serialize, RedisCommand, RespDatatype, RespStreamHandler};
+
+lazy_static! {
+    // Channel name to the connections subscribed to it
+    static ref CHANNELS: Mutex<HashMap<Vec<u8>, Vec<Subscriber>>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_SUBSCRIBER_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct Subscriber {
+    id: usize,
+    sender: UnboundedSender<Vec<u8>>,
+}
+
+// Sends the message to every subscriber of the channel and returns how many received it
+pub async fn publish(channel: &[u8], message: &[u8]) -> usize {
+    let channels = CHANNELS.lock().await;
+    let subscribers = match channels.get(channel) {
+        Some(subscribers) => subscribers,
+        None => return 0,
+    };
+    let message = serialize(&RespDatatype::Array(vec![
+        RespDatatype::BulkString(b"message".to_vec()),
+        RespDatatype::BulkString(channel.to_vec()),
+        RespDatatype::BulkString(message.to_vec()),
+    ]));
+    subscribers.iter()
+        .filter(|subscriber| subscriber.sender.send(message.clone()).is_ok())
+        .count()
+}
+
+pub fn interpret_publish(mut array_iterator: IntoIter<RespDatatype>) -> Result<(Vec<u8>, Vec<u8>), String> {
+    match (array_iterator.next(), array_iterator.next(), array_iterator.next()) {
+        (Some(RespDatatype::BulkString(channel)), Some(RespDatatype::BulkString(message)), None) => Ok((channel, message)),
+        _ => Err(String::from("ERR wrong number of arguments for 'publish' command")),
+    }
+}
+
+pub fn interpret_subscribe(array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
+    let mut channels = Vec::new();
+    for argument in array_iterator {
+        match argument {
+            RespDatatype::BulkString(channel) => channels.push(channel),
+            _ => return Some(RedisCommand::Error(String::from("ERR invalid channel name"))),
+        }
+    }
+    if channels.is_empty() {
+        return Some(RedisCommand::Error(String::from("ERR wrong number of arguments for 'subscribe' command")));
+    }
+    Some(RedisCommand::Subscribe(channels))
+}
+
+fn subscription_reply(kind: &[u8], channel: Option<Vec<u8>>, count: usize) -> Vec<u8> {
+    serialize(&RespDatatype::Array(vec![
+        RespDatatype::BulkString(kind.to_vec()),
+        channel.map(RespDatatype::BulkString).unwrap_or(RespDatatype::NullBulkString),
+        RespDatatype::Integer(count as i64),
+    ]))
+}
+
+// The connection stays in subscribed mode, taking only (UN)SUBSCRIBE and PING, until it unsubscribes from every channel.
+// The connection is handed back then, None if it was closed
+pub async fn handle_subscriber(resp_stream_handler: RespStreamHandler, channels: Vec<Vec<u8>>) -> Option<RespStreamHandler> {
+    let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+    let (mut reader, mut writer) = resp_stream_handler.into_split();
+    let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+    let mut subscribed: HashSet<Vec<u8>> = HashSet::new();
+
+    let subscriber_writer = async {
+        while let Some(reply) = receiver.recv().await {
+            if writer.write_all(&reply).await.is_err() {
+                break;
+            }
+        }
+    };
+    let subscriber_reader = async {
+        subscribe(id, &sender, &mut subscribed, channels).await;
+        let still_open = loop {
+            let argv = match reader.deserialize().await {
+                Ok((RespDatatype::Array(array), _)) => array,
+                Ok(_) => continue,
+                Err(_) => break false,
+            };
+            let mut array_iterator = argv.into_iter();
+            let command = match array_iterator.next() {
+                Some(RespDatatype::BulkString(command)) => command.to_ascii_uppercase(),
+                _ => continue,
+            };
+            let arguments: Vec<Vec<u8>> = array_iterator
+                .filter_map(|argument| match argument {
+                    RespDatatype::BulkString(argument) => Some(argument),
+                    _ => None,
+                })
+                .collect();
+            match &command[..] {
+                b"SUBSCRIBE" if !arguments.is_empty() => subscribe(id, &sender, &mut subscribed, arguments).await,
+                b"UNSUBSCRIBE" => {
+                    let channels = if arguments.is_empty() {subscribed.iter().cloned().collect()} else {arguments};
+                    for channel in channels {
+                        subscribed.remove(&channel);
+                        remove_subscriber(id, &channel).await;
+                        let _ = sender.send(subscription_reply(b"unsubscribe", Some(channel), subscribed.len()));
+                    }
+                    if subscribed.is_empty() {
+                        break true;
+                    }
+                },
+                b"PING" => {
+                    let _ = sender.send(serialize(&RespDatatype::Array(vec![
+                        RespDatatype::BulkString(b"pong".to_vec()),
+                        RespDatatype::BulkString(arguments.into_iter().next().unwrap_or_default()),
+                    ])));
+                },
+                _ => {
+                    let error = format!("ERR Can't execute '{}': only (UN)SUBSCRIBE and PING are allowed in this context", String::from_utf8_lossy(&command).to_ascii_lowercase());
+                    let _ = sender.send(serialize(&RespDatatype::SimpleError(error)));
+                },
+            }
+        };
+        for channel in subscribed.iter() {
+            remove_subscriber(id, channel).await;
+        }
+        // The writer finishes once it flushed what was queued
+        drop(sender);
+        still_open
+    };
+    let ((), still_open) = tokio::join!(subscriber_writer, subscriber_reader);
+    if !still_open {
+        return None;
+    }
+    reader.reunite(writer).ok()
+}
+
+async fn subscribe(id: usize, sender: &UnboundedSender<Vec<u8>>, subscribed: &mut HashSet<Vec<u8>>, channels: Vec<Vec<u8>>) {
+    let mut all_channels = CHANNELS.lock().await;
+    for channel in channels {
+        if subscribed.insert(channel.clone()) {
+            all_channels.entry(channel.clone()).or_default().push(Subscriber {id, sender: sender.clone()});
+        }
+        let _ = sender.send(subscription_reply(b"subscribe", Some(channel), subscribed.len()));
+    }
+}
+
+async fn remove_subscriber(id: usize, channel: &[u8]) {
+    let mut channels = CHANNELS.lock().await;
+    if let Some(subscribers) = channels.get_mut(channel) {
+        subscribers.retain(|subscriber| subscriber.id != id);
+        if subscribers.is_empty() {
+            channels.remove(channel);
+        }
+    }
+}
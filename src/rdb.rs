@@ -0,0 +1,1010 @@
+use std::{collections::{HashMap, VecDeque}, error::Error, io, path::{Path, PathBuf}};
+use anyhow::anyhow;
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{changes_since_last_save, get_config, load_database, mark_saved, snapshot_database, unix_time_millis, Entry, Hash, HashField, RedisSet, SortedSet, Value};
+
+const RDB_MAGIC: &[u8] = b"REDIS";
+// What redis 7.2 writes, and 7.4 which added the hash field expiry types. Older readers reject newer versions,
+// so the newer one is only used for snapshots that need it
+const RDB_VERSION: &[u8] = b"0011";
+const RDB_VERSION_HASH_METADATA: &[u8] = b"0012";
+
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+// Scores as strings, ZSET_2 stores them as binary doubles
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+// Hashes with field expiry times, both start with the earliest expiry time
+const RDB_TYPE_HASH_METADATA: u8 = 24;
+const RDB_TYPE_HASH_LISTPACK_EX: u8 = 25;
+
+// Quicklist nodes hold either a single large element or a listpack of them
+const QUICKLIST_NODE_CONTAINER_PLAIN: usize = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED: usize = 2;
+
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+lazy_static! {
+    static ref SAVE_STATE: Mutex<SaveState> = Mutex::new(SaveState::new());
+}
+
+struct SaveState {
+    // Unix time in seconds of the last successful save
+    last_save: u64,
+    bgsave_started: Option<Instant>,
+    last_bgsave_ok: bool,
+    last_bgsave_time_sec: i64,
+}
+
+impl SaveState {
+    fn new() -> Self {
+        SaveState {
+            last_save: unix_time_millis() / 1000,
+            bgsave_started: None,
+            last_bgsave_ok: true,
+            last_bgsave_time_sec: -1,
+        }
+    }
+}
+
+// CRC-64/Jones as used by Redis, reflected polynomial
+const CRC64_POLY: u64 = 0x95ac9329ac4bc9b5;
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ CRC64_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for &byte in bytes {
+        crc = CRC64_TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+enum Length {
+    Length(usize),
+    Encoded(u8),
+}
+
+struct RdbReader<'a> {
+    bytes: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        RdbReader {bytes, cursor: 0}
+    }
+
+    // Advances internal cursor
+    fn read_n(&mut self, amount: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let end = match self.cursor.checked_add(amount) {
+            Some(end) if end <= self.bytes.len() => end,
+            _ => return Err(Box::from(anyhow!("Unexpected end of RDB file"))),
+        };
+        let slice = &self.bytes[self.cursor..end];
+        self.cursor = end;
+        Ok(slice)
+    }
+
+    // Lengths come from the file, a corrupt one mustn't allocate more than the rest of the file could hold
+    fn capacity_hint(&self, length: usize) -> usize {
+        length.min(self.bytes.len() - self.cursor)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.read_n(1)?[0])
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<Length, Box<dyn Error>> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0b00 => Ok(Length::Length((first & 0x3f) as usize)),
+            0b01 => {
+                let second = self.read_u8()?;
+                Ok(Length::Length((((first & 0x3f) as usize) << 8) | second as usize))
+            },
+            0b10 => match first {
+                0x80 => Ok(Length::Length(u32::from_be_bytes(self.read_n(4)?.try_into()?) as usize)),
+                0x81 => Ok(Length::Length(u64::from_be_bytes(self.read_n(8)?.try_into()?) as usize)),
+                _ => Err(Box::from(anyhow!("Invalid length encoding {first:#x}"))),
+            },
+            _ => Ok(Length::Encoded(first & 0x3f)),
+        }
+    }
+
+    fn read_length(&mut self) -> Result<usize, Box<dyn Error>> {
+        match self.read_length_or_encoding()? {
+            Length::Length(length) => Ok(length),
+            Length::Encoded(_) => Err(Box::from(anyhow!("Expected a length, found a string encoding"))),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self.read_length_or_encoding()? {
+            Length::Length(length) => Ok(self.read_n(length)?.to_vec()),
+            Length::Encoded(RDB_ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_INT16) => Ok(i16::from_le_bytes(self.read_n(2)?.try_into()?).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_INT32) => Ok(i32::from_le_bytes(self.read_n(4)?.try_into()?).to_string().into_bytes()),
+            Length::Encoded(RDB_ENC_LZF) => {
+                let compressed_length = self.read_length()?;
+                let length = self.read_length()?;
+                lzf_decompress(self.read_n(compressed_length)?, length)
+            },
+            Length::Encoded(encoding) => Err(Box::from(anyhow!("Unknown string encoding {encoding}"))),
+        }
+    }
+}
+
+fn lzf_decompress(input: &[u8], length: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    // A 3 byte back reference expands to at most 264 bytes
+    let mut output: Vec<u8> = Vec::with_capacity(length.min(input.len() * 88));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let run = ctrl + 1;
+            if i + run > input.len() {
+                return Err(Box::from(anyhow!("Corrupt LZF literal run")));
+            }
+            output.extend_from_slice(&input[i..i + run]);
+            i += run;
+        } else {
+            // Back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or(anyhow!("Corrupt LZF back reference"))? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or(anyhow!("Corrupt LZF back reference"))? as usize;
+            i += 1;
+            let distance = ((ctrl & 0x1f) << 8) + low + 1;
+            if distance > output.len() {
+                return Err(Box::from(anyhow!("Corrupt LZF back reference")));
+            }
+            let start = output.len() - distance;
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+    }
+    if output.len() != length {
+        return Err(Box::from(anyhow!("LZF decompressed to {} bytes, expected {length}", output.len())));
+    }
+    Ok(output)
+}
+
+// Listpacks pack small collections into one string: a 6 byte header, the entries each followed by their
+// length for backwards traversal, and an 0xFF terminator. Integers are returned in their decimal form
+fn decode_listpack(bytes: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut reader = RdbReader::new(bytes);
+    reader.read_n(6)?;
+    let mut elements = Vec::new();
+    loop {
+        let encoding = reader.read_u8()?;
+        // The entry size covers the encoding byte(s) and the data, it is repeated as the back length
+        let (element, entry_size) = match encoding {
+            0xFF => break,
+            _ if encoding & 0x80 == 0 => ((encoding & 0x7F).to_string().into_bytes(), 1),
+            _ if encoding & 0xC0 == 0x80 => {
+                let length = (encoding & 0x3F) as usize;
+                (reader.read_n(length)?.to_vec(), 1 + length)
+            },
+            _ if encoding & 0xE0 == 0xC0 => {
+                let value = ((encoding as i64 & 0x1F) << 8) | reader.read_u8()? as i64;
+                let value = if value >= 1 << 12 {value - (1 << 13)} else {value};
+                (value.to_string().into_bytes(), 2)
+            },
+            _ if encoding & 0xF0 == 0xE0 => {
+                let length = ((encoding as usize & 0x0F) << 8) | reader.read_u8()? as usize;
+                (reader.read_n(length)?.to_vec(), 2 + length)
+            },
+            0xF0 => {
+                let length = u32::from_le_bytes(reader.read_n(4)?.try_into()?) as usize;
+                (reader.read_n(length)?.to_vec(), 5 + length)
+            },
+            0xF1 => (i16::from_le_bytes(reader.read_n(2)?.try_into()?).to_string().into_bytes(), 3),
+            0xF2 => {
+                let mut value = [0u8; 4];
+                value[1..].copy_from_slice(reader.read_n(3)?);
+                ((i32::from_le_bytes(value) >> 8).to_string().into_bytes(), 4)
+            },
+            0xF3 => (i32::from_le_bytes(reader.read_n(4)?.try_into()?).to_string().into_bytes(), 5),
+            0xF4 => (i64::from_le_bytes(reader.read_n(8)?.try_into()?).to_string().into_bytes(), 9),
+            encoding => return Err(Box::from(anyhow!("Invalid listpack entry encoding {encoding:#x}"))),
+        };
+        let back_length_size = match entry_size {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        reader.read_n(back_length_size)?;
+        elements.push(element);
+    }
+    Ok(elements)
+}
+
+// Intsets are a 4 byte integer width, a 4 byte count and the sorted integers, all little endian
+fn decode_intset(bytes: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut reader = RdbReader::new(bytes);
+    let width = u32::from_le_bytes(reader.read_n(4)?.try_into()?) as usize;
+    let length = u32::from_le_bytes(reader.read_n(4)?.try_into()?) as usize;
+    let mut members = Vec::with_capacity(reader.capacity_hint(length));
+    for _ in 0..length {
+        let integer = match width {
+            2 => i16::from_le_bytes(reader.read_n(2)?.try_into()?) as i64,
+            4 => i32::from_le_bytes(reader.read_n(4)?.try_into()?) as i64,
+            8 => i64::from_le_bytes(reader.read_n(8)?.try_into()?),
+            width => return Err(Box::from(anyhow!("Invalid intset integer width {width}"))),
+        };
+        members.push(integer.to_string().into_bytes());
+    }
+    Ok(members)
+}
+
+fn parse_score(score: &[u8]) -> Result<f64, Box<dyn Error>> {
+    let score = String::from_utf8_lossy(score).parse::<f64>()?;
+    if score.is_nan() {
+        return Err(Box::from(anyhow!("Invalid sorted set score")));
+    }
+    Ok(score)
+}
+
+pub fn decode_rdb(bytes: &[u8]) -> Result<HashMap<Vec<u8>, Entry>, Box<dyn Error>> {
+    let mut reader = RdbReader::new(bytes);
+    if reader.read_n(RDB_MAGIC.len())? != RDB_MAGIC {
+        return Err(Box::from(anyhow!("Not an RDB file")));
+    }
+    let version: u32 = String::from_utf8(reader.read_n(4)?.to_vec())?.parse()?;
+
+    let mut entries: HashMap<Vec<u8>, Entry> = HashMap::new();
+    let mut db: usize = 0;
+    let mut expires_at: Option<u64> = None;
+    loop {
+        let opcode = reader.read_u8()?;
+        match opcode {
+            RDB_OPCODE_AUX => {
+                let name = reader.read_string()?;
+                let value = reader.read_string()?;
+                println!("RDB aux field {:?}: {:?}", String::from_utf8_lossy(&name), String::from_utf8_lossy(&value));
+            },
+            RDB_OPCODE_SELECTDB => db = reader.read_length()?,
+            RDB_OPCODE_RESIZEDB => {
+                let db_size = reader.read_length()?;
+                reader.read_length()?;
+                entries.reserve(reader.capacity_hint(db_size));
+            },
+            RDB_OPCODE_EXPIRETIME_MS => {
+                expires_at = Some(u64::from_le_bytes(reader.read_n(8)?.try_into()?));
+            },
+            RDB_OPCODE_EXPIRETIME => {
+                expires_at = Some(u32::from_le_bytes(reader.read_n(4)?.try_into()?) as u64 * 1000);
+            },
+            RDB_OPCODE_IDLE => {
+                reader.read_length()?;
+            },
+            RDB_OPCODE_FREQ => {
+                reader.read_u8()?;
+            },
+            RDB_OPCODE_MODULE_AUX | RDB_OPCODE_FUNCTION2 => {
+                return Err(Box::from(anyhow!("Modules and functions in RDB files are not supported")));
+            },
+            RDB_OPCODE_EOF => {
+                if version >= 5 {
+                    let checksum_end = reader.cursor;
+                    let expected = u64::from_le_bytes(reader.read_n(8)?.try_into()?);
+                    // A zero checksum means the writer had checksums disabled
+                    if expected != 0 && crc64(0, &bytes[..checksum_end]) != expected {
+                        return Err(Box::from(anyhow!("RDB checksum mismatch")));
+                    }
+                }
+                break;
+            },
+            value_type => {
+                let key = reader.read_string()?;
+                let value = match value_type {
+                    RDB_TYPE_STRING => Value::String(reader.read_string()?),
+                    RDB_TYPE_LIST => {
+                        let length = reader.read_length()?;
+                        let mut list = VecDeque::with_capacity(reader.capacity_hint(length));
+                        for _ in 0..length {
+                            list.push_back(reader.read_string()?);
+                        }
+                        Value::List(list)
+                    },
+                    RDB_TYPE_LIST_QUICKLIST_2 => {
+                        let mut list = VecDeque::new();
+                        for _ in 0..reader.read_length()? {
+                            let container = reader.read_length()?;
+                            let node = reader.read_string()?;
+                            match container {
+                                QUICKLIST_NODE_CONTAINER_PLAIN => list.push_back(node),
+                                QUICKLIST_NODE_CONTAINER_PACKED => list.extend(decode_listpack(&node)?),
+                                container => return Err(Box::from(anyhow!("Unknown quicklist node container {container}"))),
+                            }
+                        }
+                        Value::List(list)
+                    },
+                    RDB_TYPE_SET => {
+                        let length = reader.read_length()?;
+                        let mut members = Vec::with_capacity(reader.capacity_hint(length));
+                        for _ in 0..length {
+                            members.push(reader.read_string()?);
+                        }
+                        Value::Set(RedisSet::from_members(members))
+                    },
+                    RDB_TYPE_SET_INTSET => Value::Set(RedisSet::from_members(decode_intset(&reader.read_string()?)?)),
+                    RDB_TYPE_SET_LISTPACK => Value::Set(RedisSet::from_members(decode_listpack(&reader.read_string()?)?)),
+                    RDB_TYPE_HASH => {
+                        let length = reader.read_length()?;
+                        let mut hash = Hash::with_capacity(reader.capacity_hint(length));
+                        for _ in 0..length {
+                            let field = reader.read_string()?;
+                            hash.insert(field, HashField::new(reader.read_string()?));
+                        }
+                        Value::Hash(hash)
+                    },
+                    RDB_TYPE_HASH_METADATA => {
+                        let min_expires_at = u64::from_le_bytes(reader.read_n(8)?.try_into()?);
+                        let length = reader.read_length()?;
+                        let mut hash = Hash::with_capacity(reader.capacity_hint(length));
+                        for _ in 0..length {
+                            // Relative to the earliest expiry time plus one, 0 when the field doesn't expire
+                            let ttl = reader.read_length()? as u64;
+                            let field = reader.read_string()?;
+                            let value = reader.read_string()?;
+                            let expires_at = match ttl {
+                                0 => None,
+                                ttl => Some(min_expires_at.checked_add(ttl - 1).ok_or(anyhow!("Hash field expiry time out of range"))?),
+                            };
+                            hash.insert(field, HashField {value, expires_at});
+                        }
+                        Value::Hash(hash)
+                    },
+                    RDB_TYPE_HASH_LISTPACK => {
+                        let elements = decode_listpack(&reader.read_string()?)?;
+                        let hash = elements.chunks_exact(2)
+                            .map(|pair| (pair[0].clone(), HashField::new(pair[1].clone())))
+                            .collect();
+                        Value::Hash(hash)
+                    },
+                    RDB_TYPE_HASH_LISTPACK_EX => {
+                        reader.read_n(8)?;
+                        // Field, value and the absolute expiry time, 0 when the field doesn't expire
+                        let elements = decode_listpack(&reader.read_string()?)?;
+                        let mut hash = Hash::with_capacity(elements.len() / 3);
+                        for triple in elements.chunks_exact(3) {
+                            let expires_at = String::from_utf8_lossy(&triple[2]).parse::<u64>()?;
+                            let expires_at = (expires_at != 0).then_some(expires_at);
+                            hash.insert(triple[0].clone(), HashField {value: triple[1].clone(), expires_at});
+                        }
+                        Value::Hash(hash)
+                    },
+                    RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                        let mut sorted_set = SortedSet::default();
+                        for _ in 0..reader.read_length()? {
+                            let member = reader.read_string()?;
+                            let score = match value_type {
+                                RDB_TYPE_ZSET_2 => f64::from_le_bytes(reader.read_n(8)?.try_into()?),
+                                // A length byte, with 253 to 255 standing for nan, inf and -inf
+                                _ => match reader.read_u8()? {
+                                    253 => f64::NAN,
+                                    254 => f64::INFINITY,
+                                    255 => f64::NEG_INFINITY,
+                                    length => parse_score(reader.read_n(length as usize)?)?,
+                                },
+                            };
+                            if score.is_nan() {
+                                return Err(Box::from(anyhow!("Invalid sorted set score")));
+                            }
+                            sorted_set.insert(member, score);
+                        }
+                        Value::SortedSet(sorted_set)
+                    },
+                    // Member and score pairs
+                    RDB_TYPE_ZSET_LISTPACK => {
+                        let mut sorted_set = SortedSet::default();
+                        for pair in decode_listpack(&reader.read_string()?)?.chunks_exact(2) {
+                            sorted_set.insert(pair[0].clone(), parse_score(&pair[1])?);
+                        }
+                        Value::SortedSet(sorted_set)
+                    },
+                    value_type => return Err(Box::from(anyhow!("Unsupported RDB value type {value_type}"))),
+                };
+                // There is a single keyspace, keys of other databases are dropped
+                if db == 0 {
+                    entries.insert(key, Entry::new(value, expires_at));
+                } else {
+                    println!("Skipping key {:?} of database {db}", String::from_utf8_lossy(&key));
+                }
+                expires_at = None;
+            },
+        }
+    }
+    Ok(entries)
+}
+
+// Loads dir/dbfilename into the database, returns the amount of keys loaded
+pub async fn load_rdb_file(dir: &str, dbfilename: &str) -> Result<usize, Box<dyn Error>> {
+    let path = Path::new(dir).join(dbfilename);
+    let bytes = match tokio::fs::read(&path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            println!("No RDB file found at {}, starting with an empty database", path.display());
+            return Ok(0);
+        },
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = decode_rdb(&bytes)?;
+    let now = unix_time_millis();
+    entries.retain(|_, entry| {
+        // A hash whose fields all expired is dropped along with them
+        if let Value::Hash(hash) = &mut entry.value {
+            hash.retain(|_, field| !field.is_expired(now));
+            if hash.is_empty() {
+                return false;
+            }
+        }
+        !entry.is_expired(now)
+    });
+    let keys_loaded = entries.len();
+    load_database(entries).await;
+    Ok(keys_loaded)
+}
+
+fn write_length(bytes: &mut Vec<u8>, length: usize) {
+    if length < 1 << 6 {
+        bytes.push(length as u8);
+    } else if length < 1 << 14 {
+        bytes.push(0x40 | (length >> 8) as u8);
+        bytes.push(length as u8);
+    } else if length <= u32::MAX as usize {
+        bytes.push(0x80);
+        bytes.extend_from_slice(&(length as u32).to_be_bytes());
+    } else {
+        bytes.push(0x81);
+        bytes.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &[u8]) {
+    // Strings holding a canonical integer are stored in the integer encodings
+    if let Some(integer) = std::str::from_utf8(string).ok()
+        .filter(|string| string.len() <= 11)
+        .and_then(|string| string.parse::<i32>().ok())
+        .filter(|integer| integer.to_string().as_bytes() == string) {
+        if let Ok(integer) = i8::try_from(integer) {
+            bytes.push(0xC0 | RDB_ENC_INT8);
+            bytes.push(integer as u8);
+        } else if let Ok(integer) = i16::try_from(integer) {
+            bytes.push(0xC0 | RDB_ENC_INT16);
+            bytes.extend_from_slice(&integer.to_le_bytes());
+        } else {
+            bytes.push(0xC0 | RDB_ENC_INT32);
+            bytes.extend_from_slice(&integer.to_le_bytes());
+        }
+        return;
+    }
+    write_length(bytes, string.len());
+    bytes.extend_from_slice(string);
+}
+
+fn write_aux(bytes: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    bytes.push(RDB_OPCODE_AUX);
+    write_string(bytes, name);
+    write_string(bytes, value);
+}
+
+pub fn encode_rdb(entries: &HashMap<Vec<u8>, Entry>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend_from_slice(RDB_MAGIC);
+    let has_field_ttls = entries.values().any(|entry| matches!(&entry.value, Value::Hash(hash) if hash.values().any(|field| field.expires_at.is_some())));
+    if has_field_ttls {
+        bytes.extend_from_slice(RDB_VERSION_HASH_METADATA);
+        write_aux(&mut bytes, b"redis-ver", b"7.4.0");
+    } else {
+        bytes.extend_from_slice(RDB_VERSION);
+        write_aux(&mut bytes, b"redis-ver", b"7.2.0");
+    }
+    write_aux(&mut bytes, b"redis-bits", b"64");
+    write_aux(&mut bytes, b"ctime", (unix_time_millis() / 1000).to_string().as_bytes());
+
+    bytes.push(RDB_OPCODE_SELECTDB);
+    write_length(&mut bytes, 0);
+    bytes.push(RDB_OPCODE_RESIZEDB);
+    write_length(&mut bytes, entries.len());
+    write_length(&mut bytes, entries.values().filter(|entry| entry.expires_at.is_some()).count());
+
+    for (key, entry) in entries.iter() {
+        if let Some(expires_at) = entry.expires_at {
+            bytes.push(RDB_OPCODE_EXPIRETIME_MS);
+            bytes.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        match &entry.value {
+            Value::String(value) => {
+                bytes.push(RDB_TYPE_STRING);
+                write_string(&mut bytes, key);
+                write_string(&mut bytes, value);
+            },
+            // The plain list encoding, every redis version still loads it
+            Value::List(list) => {
+                bytes.push(RDB_TYPE_LIST);
+                write_string(&mut bytes, key);
+                write_length(&mut bytes, list.len());
+                for element in list {
+                    write_string(&mut bytes, element);
+                }
+            },
+            // Loading picks the compact encoding again
+            Value::Set(set) => {
+                bytes.push(RDB_TYPE_SET);
+                write_string(&mut bytes, key);
+                write_length(&mut bytes, set.len());
+                for member in set.members() {
+                    write_string(&mut bytes, &member);
+                }
+            },
+            Value::SortedSet(sorted_set) => {
+                bytes.push(RDB_TYPE_ZSET_2);
+                write_string(&mut bytes, key);
+                write_length(&mut bytes, sorted_set.len());
+                for (member, score) in sorted_set.iter() {
+                    write_string(&mut bytes, member);
+                    bytes.extend_from_slice(&score.to_le_bytes());
+                }
+            },
+            Value::Hash(hash) => match hash.values().filter_map(|field| field.expires_at).min() {
+                None => {
+                    bytes.push(RDB_TYPE_HASH);
+                    write_string(&mut bytes, key);
+                    write_length(&mut bytes, hash.len());
+                    for (field, HashField {value, ..}) in hash {
+                        write_string(&mut bytes, field);
+                        write_string(&mut bytes, value);
+                    }
+                },
+                Some(min_expires_at) => {
+                    bytes.push(RDB_TYPE_HASH_METADATA);
+                    write_string(&mut bytes, key);
+                    bytes.extend_from_slice(&min_expires_at.to_le_bytes());
+                    write_length(&mut bytes, hash.len());
+                    for (field, HashField {value, expires_at}) in hash {
+                        let ttl = expires_at.map_or(0, |expires_at| expires_at - min_expires_at + 1);
+                        write_length(&mut bytes, ttl as usize);
+                        write_string(&mut bytes, field);
+                        write_string(&mut bytes, value);
+                    }
+                },
+            },
+        }
+    }
+
+    bytes.push(RDB_OPCODE_EOF);
+    let checksum = crc64(0, &bytes);
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+async fn rdb_file_path() -> PathBuf {
+    let dir = get_config(b"dir").await.unwrap_or(b"./".to_vec());
+    let dbfilename = get_config(b"dbfilename").await.unwrap_or(b"dump.rdb".to_vec());
+    Path::new(&String::from_utf8_lossy(&dir).to_string()).join(String::from_utf8_lossy(&dbfilename).to_string())
+}
+
+async fn write_rdb_file(entries: &HashMap<Vec<u8>, Entry>, temp_name: &str) -> Result<(), Box<dyn Error>> {
+    write_rdb_bytes(&encode_rdb(entries), temp_name).await
+}
+
+// Writes to a temporary file first so a crash never leaves a truncated RDB file behind
+async fn write_rdb_bytes(rdb: &[u8], temp_name: &str) -> Result<(), Box<dyn Error>> {
+    let path = rdb_file_path().await;
+    let temp_path = path.with_file_name(temp_name);
+    tokio::fs::write(&temp_path, rdb).await?;
+    if let Err(e) = tokio::fs::rename(&temp_path, &path).await {
+        tokio::fs::remove_file(&temp_path).await.unwrap_or(());
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+pub async fn save() -> Result<(), Box<dyn Error>> {
+    let mut save_state = SAVE_STATE.lock().await;
+    if save_state.bgsave_started.is_some() {
+        return Err(Box::from(anyhow!("Background save already in progress")));
+    }
+    let (entries, dirty) = snapshot_database().await;
+    write_rdb_file(&entries, &format!("temp-{}.rdb", std::process::id())).await?;
+    mark_saved(dirty);
+    save_state.last_save = unix_time_millis() / 1000;
+    Ok(())
+}
+
+pub async fn bgsave() -> Result<(), Box<dyn Error>> {
+    let mut save_state = SAVE_STATE.lock().await;
+    if save_state.bgsave_started.is_some() {
+        return Err(Box::from(anyhow!("Background save already in progress")));
+    }
+    let (entries, dirty) = snapshot_database().await;
+    let started = Instant::now();
+    save_state.bgsave_started = Some(started);
+    drop(save_state);
+
+    tokio::spawn(async move {
+        let result = write_rdb_file(&entries, &format!("temp-bgsave-{}.rdb", std::process::id())).await
+            .map_err(|e| e.to_string());
+        finish_bgsave(started, dirty, &result).await;
+    });
+    Ok(())
+}
+
+async fn finish_bgsave(started: Instant, dirty: u64, result: &Result<(), String>) {
+    let mut save_state = SAVE_STATE.lock().await;
+    save_state.bgsave_started = None;
+    save_state.last_bgsave_time_sec = started.elapsed().as_secs() as i64;
+    match result {
+        Ok(()) => {
+            println!("Background saving terminated with success");
+            mark_saved(dirty);
+            save_state.last_save = unix_time_millis() / 1000;
+            save_state.last_bgsave_ok = true;
+        },
+        Err(e) => {
+            println!("Background saving failed: {e}");
+            save_state.last_bgsave_ok = false;
+        },
+    }
+}
+
+// Disk based full syncs save the snapshot like BGSAVE does and send the replicas the bytes of that file.
+// When a client's BGSAVE is already running both complete, it keeps reporting its own status
+pub async fn bgsave_for_replication(entries: HashMap<Vec<u8>, Entry>, dirty: u64) -> Result<Vec<u8>, String> {
+    let started = Instant::now();
+    let mut save_state = SAVE_STATE.lock().await;
+    let reports_status = save_state.bgsave_started.is_none();
+    if reports_status {
+        save_state.bgsave_started = Some(started);
+    }
+    drop(save_state);
+    let rdb = encode_rdb(&entries);
+    let result = write_rdb_bytes(&rdb, &format!("temp-sync-{}.rdb", std::process::id())).await
+        .map_err(|e| e.to_string());
+    if reports_status {
+        finish_bgsave(started, dirty, &result).await;
+    }
+    result.map(|()| rdb)
+}
+
+pub async fn last_save() -> u64 {
+    SAVE_STATE.lock().await.last_save
+}
+
+pub async fn persistence_info() -> Vec<u8> {
+    let save_state = SAVE_STATE.lock().await;
+    let current_bgsave_time_sec = match save_state.bgsave_started {
+        Some(started) => started.elapsed().as_secs() as i64,
+        None => -1,
+    };
+    format!(
+        "loading:0\r\nrdb_changes_since_last_save:{}\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\nrdb_last_bgsave_time_sec:{}\r\nrdb_current_bgsave_time_sec:{}\r\n",
+        changes_since_last_save(),
+        save_state.bgsave_started.is_some() as u8,
+        save_state.last_save,
+        if save_state.last_bgsave_ok {"ok"} else {"err"},
+        save_state.last_bgsave_time_sec,
+        current_bgsave_time_sec,
+    ).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A version 11 file holding body, with its checksum
+    fn rdb_file(body: &[u8]) -> Vec<u8> {
+        let mut bytes = b"REDIS0011".to_vec();
+        bytes.extend_from_slice(body);
+        bytes.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    // Listpack entries are passed encoded, with their back length
+    fn listpack(entries: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![0; 6];
+        for entry in entries {
+            bytes.extend_from_slice(entry);
+        }
+        bytes.push(0xFF);
+        bytes
+    }
+
+    fn string_value(entries: &HashMap<Vec<u8>, Entry>, key: &[u8]) -> Vec<u8> {
+        match &entries[key].value {
+            Value::String(value) => value.clone(),
+            value => panic!("{key:?} holds {value:?}"),
+        }
+    }
+
+    #[test]
+    fn crc64_matches_redis() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+        assert_eq!(crc64(crc64(0, b"1234"), b"56789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn lzf_literals_and_back_references() {
+        // "abc" then 6 bytes from 3 back
+        assert_eq!(lzf_decompress(&[0x02, b'a', b'b', b'c', 0x80, 0x02], 9).unwrap(), b"abcabcabc");
+        // A run of 7 or more takes an extra length byte
+        assert_eq!(lzf_decompress(&[0x00, b'a', 0xE0, 11, 0x00], 21).unwrap(), vec![b'a'; 21]);
+    }
+
+    #[test]
+    fn lzf_rejects_corrupt_input() {
+        assert!(lzf_decompress(&[0x02, b'a', b'b', b'c'], 4).is_err());
+        assert!(lzf_decompress(&[0x05, b'a'], 6).is_err());
+        assert!(lzf_decompress(&[0x00, b'a', 0x20, 0x05], 4).is_err());
+        assert!(lzf_decompress(&[0x00, b'a', 0xE0], 10).is_err());
+    }
+
+    #[test]
+    fn listpack_entry_encodings() {
+        let mut long_string = vec![0xE0, 100];
+        long_string.extend_from_slice(&[b'x'; 100]);
+        long_string.push(102);
+        let mut int24 = vec![0xF2];
+        int24.extend_from_slice(&(-70000i32).to_le_bytes()[..3]);
+        int24.push(4);
+        let mut int64 = vec![0xF4];
+        int64.extend_from_slice(&i64::MIN.to_le_bytes());
+        int64.push(9);
+        let bytes = listpack(&[
+            &[0x05, 0x01],
+            &[0x82, b'a', b'b', 0x03],
+            &[0xDF, 0xFE, 0x02],
+            &[0xF1, 0xE8, 0x03, 0x03],
+            &int24,
+            &[0xF3, 0x00, 0x00, 0x00, 0x40, 0x05],
+            &int64,
+            &long_string,
+        ]);
+        let expected: Vec<Vec<u8>> = vec![
+            b"5".to_vec(),
+            b"ab".to_vec(),
+            b"-2".to_vec(),
+            b"1000".to_vec(),
+            b"-70000".to_vec(),
+            (1i64 << 30).to_string().into_bytes(),
+            i64::MIN.to_string().into_bytes(),
+            vec![b'x'; 100],
+        ];
+        assert_eq!(decode_listpack(&bytes).unwrap(), expected);
+    }
+
+    #[test]
+    fn listpack_rejects_truncated_input() {
+        assert!(decode_listpack(&[0; 6]).is_err());
+        assert!(decode_listpack(&listpack(&[&[0x85, b'a']])).is_err());
+        assert!(decode_listpack(&listpack(&[&[0xF5, 0x01]])).is_err());
+    }
+
+    #[test]
+    fn intset_widths() {
+        let mut bytes = vec![2, 0, 0, 0, 3, 0, 0, 0];
+        for integer in [-1i16, 2, 300] {
+            bytes.extend_from_slice(&integer.to_le_bytes());
+        }
+        assert_eq!(decode_intset(&bytes).unwrap(), vec![b"-1".to_vec(), b"2".to_vec(), b"300".to_vec()]);
+        let mut bytes = vec![8, 0, 0, 0, 1, 0, 0, 0];
+        bytes.extend_from_slice(&i64::MAX.to_le_bytes());
+        assert_eq!(decode_intset(&bytes).unwrap(), vec![i64::MAX.to_string().into_bytes()]);
+        assert!(decode_intset(&[3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]).is_err());
+        // The count says more integers than there are
+        assert!(decode_intset(&[4, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn decodes_encoded_strings_and_expiry_times() {
+        let mut body = vec![RDB_OPCODE_AUX];
+        write_string(&mut body, b"redis-ver");
+        write_string(&mut body, b"7.2.0");
+        body.extend_from_slice(&[RDB_OPCODE_SELECTDB, 0, RDB_OPCODE_RESIZEDB, 3, 1]);
+        body.push(RDB_OPCODE_EXPIRETIME_MS);
+        body.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        body.extend_from_slice(&[RDB_TYPE_STRING, 0x03, b'i', b'n', b't', 0xC0, 0xFB]);
+        body.extend_from_slice(&[RDB_TYPE_STRING, 0x03, b'l', b'z', b'f', 0xC3, 0x06, 0x09, 0x02, b'a', b'b', b'c', 0x80, 0x02]);
+        body.extend_from_slice(&[RDB_TYPE_STRING, 0x04, b'i', b'n', b't', b'2', 0xC2]);
+        body.extend_from_slice(&100_000i32.to_le_bytes());
+        let entries = decode_rdb(&rdb_file(&body)).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(string_value(&entries, b"int"), b"-5");
+        assert_eq!(entries[&b"int"[..]].expires_at, Some(1_700_000_000_000));
+        assert_eq!(string_value(&entries, b"lzf"), b"abcabcabc");
+        assert_eq!(entries[&b"lzf"[..]].expires_at, None);
+        assert_eq!(string_value(&entries, b"int2"), b"100000");
+    }
+
+    #[test]
+    fn decodes_compact_collection_encodings() {
+        let mut body = vec![RDB_TYPE_SET_INTSET];
+        write_string(&mut body, b"intset");
+        write_string(&mut body, &[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 2, 0]);
+        body.push(RDB_TYPE_LIST_QUICKLIST_2);
+        write_string(&mut body, b"list");
+        body.extend_from_slice(&[2, QUICKLIST_NODE_CONTAINER_PACKED as u8]);
+        write_string(&mut body, &listpack(&[&[0x81, b'a', 0x02], &[0x07, 0x01]]));
+        body.push(QUICKLIST_NODE_CONTAINER_PLAIN as u8);
+        write_string(&mut body, b"plain");
+        body.push(RDB_TYPE_ZSET_LISTPACK);
+        write_string(&mut body, b"zset");
+        write_string(&mut body, &listpack(&[&[0x81, b'm', 0x02], &[0x83, b'1', b'.', b'5', 0x04]]));
+        let entries = decode_rdb(&rdb_file(&body)).unwrap();
+        match &entries[&b"intset"[..]].value {
+            Value::Set(set) => assert_eq!((set.encoding(), set.members()), ("intset", vec![b"1".to_vec(), b"2".to_vec()])),
+            value => panic!("intset holds {value:?}"),
+        }
+        match &entries[&b"list"[..]].value {
+            Value::List(list) => assert_eq!(list, &[b"a".to_vec(), b"7".to_vec(), b"plain".to_vec()]),
+            value => panic!("list holds {value:?}"),
+        }
+        match &entries[&b"zset"[..]].value {
+            Value::SortedSet(sorted_set) => assert_eq!(sorted_set.score(b"m"), Some(1.5)),
+            value => panic!("zset holds {value:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_files() {
+        let mut bytes = rdb_file(&[RDB_TYPE_STRING, 0x01, b'k', 0x01, b'v']);
+        assert!(decode_rdb(&bytes).is_ok());
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(decode_rdb(&bytes).is_err());
+        assert!(decode_rdb(b"RODIS0011").is_err());
+        // A length close to usize::MAX must neither overflow nor be allocated
+        let mut huge_length = vec![RDB_TYPE_STRING, 0x01, b'k', 0x81];
+        huge_length.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(decode_rdb(&rdb_file(&huge_length)).is_err());
+        let mut huge_list = vec![RDB_TYPE_LIST, 0x01, b'k', 0x81];
+        huge_list.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        assert!(decode_rdb(&rdb_file(&huge_list)).is_err());
+        let mut huge_field_ttl = vec![RDB_TYPE_HASH_METADATA, 0x01, b'k'];
+        huge_field_ttl.extend_from_slice(&u64::MAX.to_le_bytes());
+        huge_field_ttl.extend_from_slice(&[0x01, 0x02, 0x01, b'f', 0x01, b'v']);
+        assert!(decode_rdb(&rdb_file(&huge_field_ttl)).is_err());
+        let mut huge_resize = vec![RDB_OPCODE_RESIZEDB, 0x81];
+        huge_resize.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        huge_resize.push(0);
+        assert!(decode_rdb(&rdb_file(&huge_resize)).unwrap().is_empty());
+    }
+
+    // What an entry holds, in an order that doesn't depend on hashing
+    fn contents(entry: &Entry) -> (Option<u64>, &'static str, Vec<Vec<u8>>) {
+        let (kind, mut contents): (_, Vec<Vec<u8>>) = match &entry.value {
+            Value::String(value) => ("string", vec![value.clone()]),
+            Value::List(list) => return (entry.expires_at, "list", list.iter().cloned().collect()),
+            Value::Set(set) => ("set", set.members()),
+            Value::SortedSet(sorted_set) => {
+                let members = sorted_set.iter().map(|(member, score)| format!("{member:?}={score}").into_bytes()).collect();
+                return (entry.expires_at, "zset", members);
+            },
+            Value::Hash(hash) => ("hash", hash.iter().map(|(field, HashField {value, expires_at})| format!("{field:?}={value:?}@{expires_at:?}").into_bytes()).collect()),
+        };
+        contents.sort();
+        (entry.expires_at, kind, contents)
+    }
+
+    fn hash_field(value: &[u8], expires_at: Option<u64>) -> HashField {
+        HashField {value: value.to_vec(), expires_at}
+    }
+
+    fn assert_round_trips(entries: &HashMap<Vec<u8>, Entry>) -> Vec<u8> {
+        let bytes = encode_rdb(entries);
+        let decoded = decode_rdb(&bytes).unwrap();
+        assert_eq!(decoded.len(), entries.len());
+        for (key, entry) in entries {
+            assert_eq!(contents(&decoded[key]), contents(entry), "key {key:?}");
+        }
+        bytes
+    }
+
+    #[test]
+    fn round_trips_every_value_type() {
+        let mut sorted_set = SortedSet::default();
+        for (member, score) in [(&b"a"[..], 1.5), (b"b", -2.0), (b"c", f64::INFINITY), (b"d", 0.0)] {
+            sorted_set.insert(member.to_vec(), score);
+        }
+        let long_value = vec![b'v'; 20_000];
+        let entries = HashMap::from([
+            (b"string".to_vec(), Entry::new(Value::String(b"value".to_vec()), None)),
+            (b"integer".to_vec(), Entry::new(Value::String(b"-12345".to_vec()), None)),
+            (b"empty".to_vec(), Entry::new(Value::String(Vec::new()), None)),
+            (b"long".to_vec(), Entry::new(Value::String(long_value.clone()), None)),
+            (b"volatile".to_vec(), Entry::new(Value::String(b"soon gone".to_vec()), Some(4_102_444_800_000))),
+            (b"list".to_vec(), Entry::new(Value::List(VecDeque::from([b"c".to_vec(), b"a".to_vec(), b"b".to_vec()])), None)),
+            (b"intset".to_vec(), Entry::new(Value::Set(RedisSet::from_members([b"3".to_vec(), b"1".to_vec()])), Some(4_102_444_800_001))),
+            (b"set".to_vec(), Entry::new(Value::Set(RedisSet::from_members([b"x".to_vec(), b"y".to_vec(), long_value])), None)),
+            (b"zset".to_vec(), Entry::new(Value::SortedSet(sorted_set), None)),
+            (b"hash".to_vec(), Entry::new(Value::Hash(Hash::from([(b"f".to_vec(), hash_field(b"1", None)), (b"g".to_vec(), hash_field(b"2", None))])), None)),
+        ]);
+        let bytes = assert_round_trips(&entries);
+        assert_eq!(&bytes[..9], b"REDIS0011");
+    }
+
+    #[test]
+    fn round_trips_hash_field_expiry_times() {
+        let entries = HashMap::from([
+            (b"hash".to_vec(), Entry::new(Value::Hash(Hash::from([
+                (b"permanent".to_vec(), hash_field(b"1", None)),
+                (b"first".to_vec(), hash_field(b"2", Some(4_102_444_800_000))),
+                (b"later".to_vec(), hash_field(b"3", Some(4_102_444_900_000))),
+            ])), Some(4_102_445_000_000))),
+            (b"plain".to_vec(), Entry::new(Value::Hash(Hash::from([(b"f".to_vec(), hash_field(b"v", None))])), None)),
+        ]);
+        let bytes = assert_round_trips(&entries);
+        // Field expiry times need the version that knows the hash metadata types
+        assert_eq!(&bytes[..9], b"REDIS0012");
+    }
+
+    #[test]
+    fn decodes_listpack_hashes_with_expiry_times() {
+        let mut body = vec![RDB_TYPE_HASH_LISTPACK_EX];
+        write_string(&mut body, b"hash");
+        body.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        let expires_at = 1_700_000_000_000i64.to_le_bytes();
+        let mut expiring = vec![0xF4];
+        expiring.extend_from_slice(&expires_at);
+        expiring.push(9);
+        write_string(&mut body, &listpack(&[
+            &[0x81, b'f', 0x02], &[0x81, b'1', 0x02], &expiring,
+            &[0x81, b'g', 0x02], &[0x81, b'2', 0x02], &[0x00, 0x01],
+        ]));
+        let entries = decode_rdb(&rdb_file(&body)).unwrap();
+        let Value::Hash(hash) = &entries[&b"hash"[..]].value else {
+            panic!("hash holds {:?}", entries[&b"hash"[..]].value);
+        };
+        assert_eq!(hash[&b"f"[..]].expires_at, Some(1_700_000_000_000));
+        assert_eq!(hash[&b"g"[..]].expires_at, None);
+        assert_eq!(hash[&b"g"[..]].value, b"2");
+    }
+}
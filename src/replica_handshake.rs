@@ -1,16 +1,184 @@
 use anyhow::anyhow;
+use async_recursion::async_recursion;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep};
 use std::str::Split;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::vec::IntoIter;
 use format_bytes::format_bytes;
 
-use crate::{get_config, is_valid_master_replid, serialize, set_config, set_value, set_value_expiry, show, RedisCommand, RespDatatype, RespStreamHandler, OK_STRING, PONG_STRING};
+use crate::{decode_rdb, get_bool_config, serve_blocked_clients, unblock_all_clients, disconnect_replicas, execute_command, generate_master_replid, is_write_command, lock_propagation, Propagation, get_config, is_valid_master_replid, load_database, master_repl_offset, parse_vec_u8, proxy_to_replicas, unix_time_millis, reset_master_repl_offset, serialize, set_config, show, RedisCommand, RespDatatype, RespStreamHandler, OK_STRING, PONG_STRING};
 
 lazy_static! {  
     static ref PING_COMMAND: Vec<u8> = serialize(&RespDatatype::Array(vec![RespDatatype::BulkString(b"PING".to_vec())]));
+    // Task running the handshake and then following the master, aborted when the role changes
+    static ref MASTER_LINK: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
 }
 
-pub async fn send_handshake(master_host: &String, master_port: &String, slave_port: &String) -> Result<(), Box<dyn std::error::Error>> {
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
+// States of the link to the master, as ROLE names them
+const LINK_STATE_CONNECT: u8 = 0;
+const LINK_STATE_CONNECTING: u8 = 1;
+const LINK_STATE_SYNC: u8 = 2;
+const LINK_STATE_CONNECTED: u8 = 3;
+
+// Whether master_replid and the offset describe a history the master may continue from
+static CACHED_MASTER: AtomicBool = AtomicBool::new(false);
+static MASTER_LINK_STATE: AtomicU8 = AtomicU8::new(LINK_STATE_CONNECT);
+// Unix time in ms, 0 when it never happened
+static MASTER_LAST_IO: AtomicU64 = AtomicU64::new(0);
+static MASTER_LINK_DOWN_SINCE: AtomicU64 = AtomicU64::new(0);
+
+// REPLICAOF host port, the sub-replicas are dropped since this server's history is replaced
+pub async fn replicate_from(master_host: String, master_port: String) {
+    let mut master_link = MASTER_LINK.lock().await;
+    if let Some(link) = master_link.take() {
+        link.abort();
+    }
+    // A demoted master tries to continue its own history with the new master
+    if get_config(b"role").await.as_deref() == Some(b"master") {
+        CACHED_MASTER.store(true, Ordering::Relaxed);
+    }
+    MASTER_LINK_STATE.store(LINK_STATE_CONNECT, Ordering::Relaxed);
+    MASTER_LAST_IO.store(0, Ordering::Relaxed);
+    MASTER_LINK_DOWN_SINCE.store(0, Ordering::Relaxed);
+    set_config(b"role", b"slave").await;
+    set_config(b"master_host", master_host.as_bytes()).await;
+    set_config(b"master_port", master_port.as_bytes()).await;
+    disconnect_replicas().await;
+    unblock_all_clients().await;
+    *master_link = Some(tokio::spawn(follow_master(master_host, master_port, None)));
+}
+
+// FAILOVER, asks the target to take over with PSYNC FAILOVER and only becomes its replica once it accepted
+pub async fn failover_to(master_host: String, master_port: String) -> Result<(), String> {
+    let mut master_link = MASTER_LINK.lock().await;
+    let slave_port = String::from_utf8(get_config(b"port").await.unwrap_or_default()).unwrap_or_default();
+    CACHED_MASTER.store(true, Ordering::Relaxed);
+    let resp_stream_handler = send_handshake(&master_host, &master_port, &slave_port, true).await.map_err(|e| e.to_string())?;
+    MASTER_LINK_STATE.store(LINK_STATE_CONNECT, Ordering::Relaxed);
+    MASTER_LINK_DOWN_SINCE.store(0, Ordering::Relaxed);
+    set_config(b"role", b"slave").await;
+    set_config(b"master_host", master_host.as_bytes()).await;
+    set_config(b"master_port", master_port.as_bytes()).await;
+    disconnect_replicas().await;
+    unblock_all_clients().await;
+    *master_link = Some(tokio::spawn(follow_master(master_host, master_port, Some(resp_stream_handler))));
+    Ok(())
+}
+
+// Keeps the link to the master alive, reconnecting with exponential backoff.
+// Boxed because commands from the master can reach replicate_from again
+#[async_recursion]
+async fn follow_master(master_host: String, master_port: String, mut established: Option<RespStreamHandler>) {
+    let slave_port = String::from_utf8(get_config(b"port").await.unwrap_or_default()).unwrap_or_default();
+    let mut delay = RECONNECT_MIN_DELAY;
+    loop {
+        MASTER_LINK_STATE.store(LINK_STATE_CONNECTING, Ordering::Relaxed);
+        let handshake = match established.take() {
+            Some(resp_stream_handler) => Ok(resp_stream_handler),
+            None => send_handshake(&master_host, &master_port, &slave_port, false).await.map_err(|e| e.to_string()),
+        };
+        match handshake {
+            Ok(resp_stream_handler) => {
+                delay = RECONNECT_MIN_DELAY;
+                MASTER_LINK_STATE.store(LINK_STATE_CONNECTED, Ordering::Relaxed);
+                handle_master(resp_stream_handler).await;
+                MASTER_LINK_DOWN_SINCE.store(unix_time_millis(), Ordering::Relaxed);
+                println!("Lost connection to master {master_host}:{master_port}");
+            },
+            Err(e) => println!("Handshake with {master_host}:{master_port} failed: {e}"),
+        }
+        MASTER_LINK_STATE.store(LINK_STATE_CONNECT, Ordering::Relaxed);
+        println!("Reconnecting to master in {} ms", delay.as_millis());
+        sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+}
+
+pub fn is_master_link_up() -> bool {
+    MASTER_LINK_STATE.load(Ordering::Relaxed) == LINK_STATE_CONNECTED
+}
+
+fn link_state_name() -> &'static str {
+    match MASTER_LINK_STATE.load(Ordering::Relaxed) {
+        LINK_STATE_CONNECTING => "connecting",
+        LINK_STATE_SYNC => "sync",
+        LINK_STATE_CONNECTED => "connected",
+        _ => "connect",
+    }
+}
+
+// Replica specific lines of INFO replication, they follow the role line
+pub async fn master_link_info() -> Vec<u8> {
+    let now = unix_time_millis();
+    let seconds_since = |since: u64| if since == 0 {-1} else {(now.saturating_sub(since) / 1000) as i64};
+    let master_host = String::from_utf8(get_config(b"master_host").await.unwrap_or_default()).unwrap_or_default();
+    let master_port = String::from_utf8(get_config(b"master_port").await.unwrap_or_default()).unwrap_or_default();
+    let slave_repl_offset = master_repl_offset().await;
+    let mut info = format!(
+        "master_host:{master_host}\r\nmaster_port:{master_port}\r\nmaster_link_status:{}\r\nmaster_last_io_seconds_ago:{}\r\nmaster_sync_in_progress:{}\r\nslave_read_repl_offset:{slave_repl_offset}\r\nslave_repl_offset:{slave_repl_offset}\r\n",
+        if is_master_link_up() {"up"} else {"down"},
+        if is_master_link_up() {seconds_since(MASTER_LAST_IO.load(Ordering::Relaxed))} else {-1},
+        (MASTER_LINK_STATE.load(Ordering::Relaxed) == LINK_STATE_SYNC) as u8,
+    );
+    if !is_master_link_up() {
+        info.push_str(&format!("master_link_down_since_seconds:{}\r\n", seconds_since(MASTER_LINK_DOWN_SINCE.load(Ordering::Relaxed))));
+    }
+    info.push_str(&format!("slave_read_only:{}\r\n", get_bool_config(b"replica-read-only").await as u8));
+    info.into_bytes()
+}
+
+// ROLE reply of a replica: master host, master port, link state and processed offset
+pub async fn master_link_role() -> Vec<RespDatatype> {
+    let master_host = get_config(b"master_host").await.unwrap_or_default();
+    let master_port = parse_vec_u8::<i64>(get_config(b"master_port").await.unwrap_or_default()).unwrap_or(0);
+    let offset = if is_master_link_up() {master_repl_offset().await as i64} else {-1};
+    vec![
+        RespDatatype::BulkString(b"slave".to_vec()),
+        RespDatatype::BulkString(master_host),
+        RespDatatype::Integer(master_port),
+        RespDatatype::BulkString(link_state_name().as_bytes().to_vec()),
+        RespDatatype::Integer(offset),
+    ]
+}
+
+// The new id takes over, the previous one stays valid up to the current offset
+async fn shift_replication_id(new_replid: Vec<u8>) {
+    let second_repl_offset = master_repl_offset().await + 1;
+    if let Some(master_replid) = get_config(b"master_replid").await {
+        set_config(b"master_replid2", &master_replid).await;
+    }
+    set_config(b"second_repl_offset", second_repl_offset.to_string().as_bytes()).await;
+    set_config(b"master_replid", &new_replid).await;
+}
+
+// REPLICAOF NO ONE, the previous replication id stays valid for the history this server already has
+pub async fn promote_to_master() {
+    if let Some(link) = MASTER_LINK.lock().await.take() {
+        link.abort();
+    }
+    let _propagation_guard = lock_propagation().await;
+    if get_config(b"role").await.as_deref() == Some(b"master") {
+        return;
+    }
+    MASTER_LINK_STATE.store(LINK_STATE_CONNECT, Ordering::Relaxed);
+    CACHED_MASTER.store(true, Ordering::Relaxed);
+    shift_replication_id(generate_master_replid()).await;
+    set_config(b"role", b"master").await;
+    // Sub-replicas reconnect with the old id and continue under the new one
+    disconnect_replicas().await;
+}
+
+pub async fn send_handshake(master_host: &String, master_port: &String, slave_port: &String, failover: bool) -> Result<RespStreamHandler, Box<dyn std::error::Error>> {
     let stream = TcpStream::connect(format!("{master_host}:{master_port}")).await?;
     let mut resp_stream_handler = RespStreamHandler::new(stream);
 
@@ -40,6 +208,8 @@ pub async fn send_handshake(master_host: &String, master_port: &String, slave_po
             vec![
                 RespDatatype::BulkString(b"REPLCONF".to_vec()),
                 RespDatatype::BulkString(b"capa".to_vec()),
+                RespDatatype::BulkString(b"eof".to_vec()),
+                RespDatatype::BulkString(b"capa".to_vec()),
                 RespDatatype::BulkString(b"psync2".to_vec())
             ]
         )
@@ -50,51 +220,93 @@ pub async fn send_handshake(master_host: &String, master_port: &String, slave_po
         return Err(Box::from(anyhow!("Didn't receive OK response")));
     }
 
-    let psync_command = serialize(
-        &RespDatatype::Array(
-            vec![
-                RespDatatype::BulkString(b"PSYNC".to_vec()),
-                RespDatatype::BulkString(b"?".to_vec()),
-                RespDatatype::BulkString(b"-1".to_vec())
-            ]
-        )
-    );
+    // Ask to continue from the next byte when this server already followed a master
+    let (psync_replid, psync_offset) = match get_config(b"master_replid").await {
+        Some(master_replid) if CACHED_MASTER.load(Ordering::Relaxed) => (master_replid, (master_repl_offset().await + 1).to_string().into_bytes()),
+        _ => (b"?".to_vec(), b"-1".to_vec()),
+    };
+    let mut psync_arguments = vec![
+        RespDatatype::BulkString(b"PSYNC".to_vec()),
+        RespDatatype::BulkString(psync_replid),
+        RespDatatype::BulkString(psync_offset)
+    ];
+    // The target promotes itself before answering
+    if failover {
+        psync_arguments.push(RespDatatype::BulkString(b"FAILOVER".to_vec()));
+    }
+    let psync_command = serialize(&RespDatatype::Array(psync_arguments));
     resp_stream_handler.write_all(&psync_command).await?;
     let (resp_object, buf) = resp_stream_handler.deserialize().await?;
 
     // println!("PSYNC");
 
-    let replica_data = ReplicaData::new();
-    match replica_interpret(resp_object, &buf, &replica_data).await {
+    match replica_interpret(resp_object, &buf).await {
         Some(RedisCommand::FullResync(master_replid, master_repl_offset)) => {
             set_config(b"master_replid", &master_replid).await;
-            set_config(b"master_repl_offset", &master_repl_offset).await;
+            let master_repl_offset = parse_vec_u8::<u64>(master_repl_offset)?;
+            reset_master_repl_offset(master_repl_offset).await;
+        },
+        Some(RedisCommand::Continue(master_replid)) => {
+            println!("Partial resynchronization accepted by the master");
+            if !master_replid.is_empty() && get_config(b"master_replid").await.as_ref() != Some(&master_replid) {
+                shift_replication_id(master_replid).await;
+                disconnect_replicas().await;
+            }
+            CACHED_MASTER.store(true, Ordering::Relaxed);
+            MASTER_LAST_IO.store(unix_time_millis(), Ordering::Relaxed);
+            return Ok(resp_stream_handler);
         },
         _ => return Err(Box::from(anyhow!("Couldn't deserialize response to PSYNC: {}", show(&buf[..])))),
     };
 
     // println!("RDB");
-    resp_stream_handler.get_rdb().await?;
+    MASTER_LINK_STATE.store(LINK_STATE_SYNC, Ordering::Relaxed);
+    let rdb = resp_stream_handler.get_rdb().await?;
     // println!("RDB received");
+    // The snapshot replaces whatever this replica held before
+    let entries = decode_rdb(&rdb)?;
+    println!("Loaded {} keys from the master snapshot", entries.len());
+    load_database(entries).await;
+    // Sub-replicas synced with the history that was just replaced
+    disconnect_replicas().await;
+    CACHED_MASTER.store(true, Ordering::Relaxed);
+    MASTER_LAST_IO.store(unix_time_millis(), Ordering::Relaxed);
 
-    tokio::spawn(async move {handle_master(resp_stream_handler, replica_data).await});
-
-    return Ok(());
+    Ok(resp_stream_handler)
 }
 
-#[derive(Default)]
-struct ReplicaData {
-    bytes_processed: usize,
+async fn handle_master(resp_stream_handler: RespStreamHandler) {
+    println!("Listening to master commands");
+    let (mut resp_stream_reader, writer) = resp_stream_handler.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+    // Both stop together, also when the master link is aborted
+    tokio::select! {
+        _ = send_heartbeats(writer.clone()) => (),
+        _ = read_from_master(&mut resp_stream_reader, &writer) => (),
+    }
 }
 
-impl ReplicaData {
-    fn new() -> Self {
-        Default::default()
+// Lets the master know how far this replica got even when it doesn't ask for it
+async fn send_heartbeats(writer: Arc<Mutex<OwnedWriteHalf>>) {
+    let mut ticks = interval(Duration::from_secs(1));
+    loop {
+        ticks.tick().await;
+        let ack = replica_formulate_response(&RedisCommand::ReplconfAck(master_repl_offset().await.to_string().into_bytes()));
+        for response in ack.unwrap_or_default() {
+            if writer.lock().await.write_all(&response).await.is_err() {
+                return;
+            }
+        }
     }
 }
 
-async fn handle_master(mut resp_stream_reader: RespStreamHandler, mut replica_data: ReplicaData) {
-    println!("Listening to master commands");
+fn is_command(resp_object: &RespDatatype, name: &[u8]) -> bool {
+    matches!(resp_object, RespDatatype::Array(array) if matches!(array.first(), Some(RespDatatype::BulkString(command)) if command.eq_ignore_ascii_case(name)))
+}
+
+async fn read_from_master(resp_stream_reader: &mut RespStreamHandler<OwnedReadHalf>, writer: &Mutex<OwnedWriteHalf>) {
+    // The commands of a transaction from the master and its bytes, held back until its EXEC arrives
+    let mut transaction: Option<(Vec<RespDatatype>, Vec<u8>)> = None;
     loop {
         if resp_stream_reader.is_shutdown().await {
             println!("Stream closed");
@@ -102,22 +314,51 @@ async fn handle_master(mut resp_stream_reader: RespStreamHandler, mut replica_da
         }
     
         println!("Deserializing");
-        let (resp_object, collected) = resp_stream_reader.deserialize().await
-        .expect("Failed to deserialize RESP object");
+        let (resp_object, collected) = match resp_stream_reader.deserialize().await {
+            Ok(deserialized) => deserialized,
+            Err(e) => {
+                println!("Failed to deserialize RESP object from master: {e}");
+                break;
+            },
+        };
+        MASTER_LAST_IO.store(unix_time_millis(), Ordering::Relaxed);
+
+        // A transaction is applied whole like on the master, no client sees it half done
+        let (resp_objects, collected) = match transaction.as_mut() {
+            None if is_command(&resp_object, b"MULTI") => {
+                transaction = Some((Vec::new(), collected));
+                continue;
+            },
+            None => (vec![resp_object], collected),
+            Some((queued, bytes)) => {
+                bytes.extend(collected);
+                if !is_command(&resp_object, b"EXEC") {
+                    queued.push(resp_object);
+                    continue;
+                }
+                transaction.take().unwrap_or_default()
+            },
+        };
 
         println!("Interpreting");
-        let redis_command = replica_interpret(resp_object, &collected, &replica_data)
-        .await
-        .expect("Failed to interpret Redis command");
-        
-        replica_respond(&mut resp_stream_reader, &redis_command).await;
+        // Sub-replicas syncing from this replica see the keyspace and the offset move together
+        let _propagation_guard = lock_propagation().await;
+        for resp_object in resp_objects {
+            if let Some(redis_command) = replica_interpret(resp_object, &collected).await {
+                if !replica_respond(writer, &redis_command).await {
+                    return;
+                }
+            }
+        }
 
-        replica_data.bytes_processed += collected.len();
+        // Clients blocked on this replica are served by the master's pushes too
+        serve_blocked_clients().await;
+        proxy_to_replicas(collected).await;
     }
 }
 
 #[allow(unused)]
-async fn replica_interpret(resp_object: RespDatatype, buf: &Vec<u8>, replica_data: &ReplicaData) -> Option<RedisCommand> {
+async fn replica_interpret(resp_object: RespDatatype, buf: &[u8]) -> Option<RedisCommand> {
     match resp_object {
         RespDatatype::Array(array) => {
             let mut array_iterator = array.into_iter();
@@ -127,9 +368,11 @@ async fn replica_interpret(resp_object: RespDatatype, buf: &Vec<u8>, replica_dat
             };
             match &command[..] {
                 b"PING" => Some(RedisCommand::Pong),
-                b"SET" => interpret_set(array_iterator).await,
                 b"INFO" => interpret_info(array_iterator).await,
-                b"REPLCONF" => interpret_replconf(array_iterator, &replica_data).await,
+                b"REPLCONF" => interpret_replconf(array_iterator).await,
+                // Writes from the master are applied as they are, they were already rewritten to be deterministic
+                b"SELECT" => execute_command(&command, array_iterator, &mut Propagation::default()).await,
+                command if is_write_command(command) => execute_command(command, array_iterator, &mut Propagation::default()).await,
                 _ => return make_error_command(format!("Unknown command received {:?}", command)),
             }
         },
@@ -137,6 +380,8 @@ async fn replica_interpret(resp_object: RespDatatype, buf: &Vec<u8>, replica_dat
             let mut split: Split<&str> = string.trim().split(" ");
             match split.next() {
                 Some("FULLRESYNC") => interpret_fullresync(split).await,
+                // Masters before PSYNC2 don't send their replication id
+                Some("CONTINUE") => Some(RedisCommand::Continue(split.next().unwrap_or_default().as_bytes().to_vec())),
                 Some(command) => make_error_command(format!("Unknown command received: {:?}", command)),
                 _ => make_error_command(format!("Unknown command received."),)
             }
@@ -145,53 +390,6 @@ async fn replica_interpret(resp_object: RespDatatype, buf: &Vec<u8>, replica_dat
     }
 }
 
-async fn interpret_set(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
-    let key = match array_iterator.next() {
-        Some(RespDatatype::BulkString(key)) => key,
-        _ => return None,
-    };
-    let value = match array_iterator.next() {
-        Some(RespDatatype::BulkString(value)) => value,
-        _ => return None,
-    };
-    let mut expiry: Option<u64> = None;
-    while let Some(argument) = array_iterator.next() {
-        match argument {
-            RespDatatype::BulkString(argument) => {
-                match &argument.to_ascii_uppercase()[..] {
-                    b"PX" => {
-                        expiry = match array_iterator.next() {
-                            Some(RespDatatype::Integer(integer)) => {
-                                if integer >= 0 {
-                                    Some(integer.try_into().unwrap())
-                                } else {
-                                    return make_error_command("Integer argument for PX cannott be negative");
-                                }
-                            },
-                            Some(RespDatatype::BulkString(bulk_string)) => {
-                                let bulk_string = match String::from_utf8(bulk_string) {
-                                    Ok(bulk_string) => bulk_string,
-                                    Err(_) => return make_error_command("Invalid argument given for PX")
-                                };
-                                match bulk_string.parse() {
-                                    Ok(res) => Some(res),
-                                    Err(_) => return make_error_command("Invalid argument given for PX")
-                                }
-                            }
-                            None => return make_error_command("No integer argument given for PX"),
-                            _ => return make_error_command("Invalid argument given for PX"),
-                        }
-                    },
-                    _ => (),
-                }
-            },
-            _ => (),
-        }
-    }
-    if let Some(expiry) = expiry {set_value_expiry(&key, &value, expiry).await} else {set_value(&key, &value).await}; 
-    return Some(RedisCommand::Ok);
-}
-
 async fn interpret_info(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
     let arg = match array_iterator.next() {
         Some(RespDatatype::BulkString(arg)) => arg,
@@ -207,10 +405,7 @@ async fn interpret_info(mut array_iterator: IntoIter<RespDatatype>) -> Option<Re
                 Some(master_replid) => master_replid,
                 None => return make_error_command("Error happened in the Redis. For some reason this server does not have a role.")
             };
-            let master_repl_offset = match get_config(b"master_repl_offset").await {
-                Some(master_repl_offset) => master_repl_offset,
-                None => return make_error_command("Error happened in the Redis. For some reason this server does not have a role.")
-            };
+            let master_repl_offset = master_repl_offset().await.to_string().into_bytes();
             Some(RedisCommand::BulkString(
                 format_bytes!(b"role:{}\r\nmaster_replid:{}\r\nmaster_repl_offset:{}\r\n",
                 role, master_replid, master_repl_offset
@@ -221,7 +416,7 @@ async fn interpret_info(mut array_iterator: IntoIter<RespDatatype>) -> Option<Re
 }
 
 #[allow(unused)]
-async fn interpret_replconf(mut array_iterator: IntoIter<RespDatatype>, replica_data: &ReplicaData) -> Option<RedisCommand> {
+async fn interpret_replconf(mut array_iterator: IntoIter<RespDatatype>) -> Option<RedisCommand> {
     while let Some(argument) = array_iterator.next() {
         match argument {
             RespDatatype::BulkString(argument) => {
@@ -241,13 +436,13 @@ async fn interpret_replconf(mut array_iterator: IntoIter<RespDatatype>, replica_
                             },
                             _ => return make_error_command("Invalid argument for port"),
                         };
-                        return Some(RedisCommand::ReplconfOk1);
+                        return Some(RedisCommand::ReplconfOk1(port));
                     },
                     b"capa" => {
                         let capa = match array_iterator.next() {
                             _ => (),
                         };
-                        return Some(RedisCommand::ReplconfOk2);
+                        return Some(RedisCommand::ReplconfOk2(Vec::new()));
                     },
                     b"getack" => {
                         let getack_arg = match array_iterator.next() {
@@ -255,7 +450,7 @@ async fn interpret_replconf(mut array_iterator: IntoIter<RespDatatype>, replica_
                             _ => return make_error_command("Invalid argument for GETACK"),
                         };
                         if &getack_arg[..] == b"*" {
-                            return Some(RedisCommand::ReplconfAck(replica_data.bytes_processed.to_string().into_bytes()))
+                            return Some(RedisCommand::ReplconfAck(master_repl_offset().await.to_string().into_bytes()))
                         }
                         return make_error_command("Invalid argument for GETACK")
                     }
@@ -292,18 +487,21 @@ async fn interpret_fullresync<'a>(mut array_iterator: Split<'_, &str>) -> Option
     return Some(RedisCommand::FullResync(master_replid, master_repl_offset))
 }
 
-async fn replica_respond(stream: &mut RespStreamHandler, redis_command: &RedisCommand) {
+// Returns false when the connection to the master is broken
+async fn replica_respond(writer: &Mutex<OwnedWriteHalf>, redis_command: &RedisCommand) -> bool {
     match replica_formulate_response(&redis_command) {
         Some(responses) => {
             for response in responses {
-                stream.write_all(&response)
-                    .await
-                    .expect("Failed to respond");
+                if writer.lock().await.write_all(&response).await.is_err() {
+                    println!("Failed to respond to master");
+                    return false;
+                }
                 println!("Response: {:?}", String::from_utf8(response).unwrap_or(String::new()));
             }
         },
         None => (),
     }
+    true
 }
 
 fn replica_formulate_response(redis_command: &RedisCommand) -> Option<Vec<Vec<u8>>> {
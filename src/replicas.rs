@@ -1,15 +1,35 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::{collections::LinkedList, time::Duration};
 
-use tokio::time::sleep;
+use format_bytes::format_bytes;
+use tokio::net::lookup_host;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::{watch, MutexGuard, Notify};
+use tokio::time::{sleep, timeout_at};
 use tokio::{io::AsyncWriteExt, sync::Mutex, time::Instant};
 
-use crate::{deserialize, RedisCommand, RespDatatype, RespStreamHandler};
+use crate::{bgsave_for_replication, encode_rdb, generate_master_replid, serialize_command, get_bool_config, get_config, get_u64_config, unix_time_millis, parse_vec_u8, serialize, snapshot_database, RedisCommand, RespDatatype, RespStreamHandler};
 
 lazy_static! {
-    static ref REPLICAS: Mutex<LinkedList<Replica>> = Mutex::new(LinkedList::new());
-    static ref REPLICA_TASKS: Mutex<LinkedList<ReplicaTask>> = Mutex::new(LinkedList::new());
+    static ref REPLICAS: Mutex<Replicas> = Mutex::new(Replicas {
+        list: LinkedList::new(),
+        output_buffer_limit: OutputBufferLimit {hard: 256 * 1024 * 1024, soft: 64 * 1024 * 1024, soft_seconds: 60},
+    });
+    // Held while a write is applied and propagated, so snapshots never split the two
+    static ref PROPAGATION_LOCK: Mutex<()> = Mutex::new(());
+    static ref BACKLOG: Mutex<ReplicationBacklog> = Mutex::new(ReplicationBacklog::new());
+    // Woken whenever a replica acknowledges an offset, WAIT callers sleep on it
+    static ref ACK_NOTIFY: Notify = Notify::new();
+    // Full sync that didn't start sending yet, replicas asking for one meanwhile share it
+    static ref SYNC_JOB: Mutex<Option<Arc<FullSyncJob>>> = Mutex::new(None);
 }
 
+static NEXT_REPLICA_ID: AtomicUsize = AtomicUsize::new(0);
+// Whether the replication stream already selected the database, reset on every full sync
+static STREAM_DB_SELECTED: AtomicBool = AtomicBool::new(false);
+
 #[derive(PartialEq)]
 enum ReplicaState {
     Null,
@@ -21,11 +41,16 @@ enum ReplicaState {
 
 pub struct ReplicaIdentifier {
     slave_state: ReplicaState,
+    listening_port: String,
+    // Replication id and offset the replica asked for in PSYNC
+    psync_request: Option<(Vec<u8>, Vec<u8>)>,
+    // The replica can read a snapshot of unknown size, ended by an EOF mark
+    capa_eof: bool,
 }
 
 impl ReplicaIdentifier {
     pub fn init() -> Self {
-        ReplicaIdentifier {slave_state: ReplicaState::Null}
+        ReplicaIdentifier {slave_state: ReplicaState::Null, listening_port: String::new(), psync_request: None, capa_eof: false}
     }
 
     pub fn is_replica(&mut self, redis_command: &RedisCommand) -> bool {
@@ -34,17 +59,20 @@ impl ReplicaIdentifier {
                 println!("Slave Ponged");
                 self.slave_state = ReplicaState::Ponged
             },
-            (RedisCommand::ReplconfOk1, ReplicaState::Ponged) => {
+            (RedisCommand::ReplconfOk1(listening_port), ReplicaState::Ponged) => {
                 println!("Slave Replconf1");
+                self.listening_port = listening_port.clone();
                 self.slave_state = ReplicaState::Replconf1
             },
-            (RedisCommand::ReplconfOk2, ReplicaState::Replconf1) => {
+            (RedisCommand::ReplconfOk2(capabilities), ReplicaState::Replconf1) => {
                 println!("Slave Replconf2");
+                self.capa_eof = capabilities.iter().any(|capability| capability == b"eof");
                 self.slave_state = ReplicaState::Replconf2
             },
-            (RedisCommand::FullResync(_,_), ReplicaState::Replconf2) => {
+            (RedisCommand::Psync(repl_id, repl_offset), ReplicaState::Replconf2) => {
                 println!("Slave Full Synced");
                 self.slave_state = ReplicaState::FullSynced;
+                self.psync_request = Some((repl_id.clone(), repl_offset.clone()));
                 return true
             },
             _ => self.slave_state = ReplicaState::Null,
@@ -55,214 +83,637 @@ impl ReplicaIdentifier {
     pub fn is_synced(&self) -> bool {
         self.slave_state == ReplicaState::FullSynced
     }
+
+}
+
+// Circular buffer holding the most recent bytes of the replication stream,
+// so a replica that reconnects can continue where it left off
+struct ReplicationBacklog {
+    // Allocated when the first replica connects
+    buffer: Vec<u8>,
+    // Index in buffer where the next byte is written
+    write_index: usize,
+    histlen: usize,
+    master_repl_offset: u64,
+}
+
+impl ReplicationBacklog {
+    fn new() -> Self {
+        ReplicationBacklog {buffer: Vec::new(), write_index: 0, histlen: 0, master_repl_offset: 0}
+    }
+
+    fn is_active(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    fn activate(&mut self, size: usize) {
+        if !self.is_active() {
+            self.buffer = vec![0; size];
+            self.write_index = 0;
+            self.histlen = 0;
+        }
+    }
+
+    // Every propagated byte advances the offset, even before the backlog is allocated
+    fn feed(&mut self, bytes: &[u8]) {
+        self.master_repl_offset += bytes.len() as u64;
+        if !self.is_active() {
+            return;
+        }
+        let size = self.buffer.len();
+        let bytes = &bytes[bytes.len().saturating_sub(size)..];
+        let first_part = bytes.len().min(size - self.write_index);
+        self.buffer[self.write_index..self.write_index + first_part].copy_from_slice(&bytes[..first_part]);
+        self.buffer[..bytes.len() - first_part].copy_from_slice(&bytes[first_part..]);
+        self.write_index = (self.write_index + bytes.len()) % size;
+        self.histlen = (self.histlen + bytes.len()).min(size);
+    }
+
+    fn first_byte_offset(&self) -> u64 {
+        self.master_repl_offset + 1 - self.histlen as u64
+    }
+
+    // Bytes of the stream starting at offset, if the backlog still holds them
+    fn bytes_from(&self, offset: u64) -> Option<Vec<u8>> {
+        if !self.is_active() || offset < self.first_byte_offset() || offset > self.master_repl_offset + 1 {
+            return None;
+        }
+        let size = self.buffer.len();
+        let skip = (offset - self.first_byte_offset()) as usize;
+        let start = (self.write_index + size - self.histlen + skip) % size;
+        let length = self.histlen - skip;
+        let mut bytes = Vec::with_capacity(length);
+        bytes.extend_from_slice(&self.buffer[start..size.min(start + length)]);
+        bytes.extend_from_slice(&self.buffer[..length - bytes.len()]);
+        Some(bytes)
+    }
+
+    fn resize(&mut self, size: usize) {
+        if !self.is_active() || self.buffer.len() == size {
+            return;
+        }
+        let history = self.bytes_from(self.first_byte_offset()).unwrap_or_default();
+        let kept = &history[history.len().saturating_sub(size)..];
+        self.buffer = vec![0; size];
+        self.buffer[..kept.len()].copy_from_slice(kept);
+        self.write_index = kept.len() % size;
+        self.histlen = kept.len();
+    }
+}
+
+type SyncPayload = Option<Result<Arc<Vec<u8>>, String>>;
+
+// A snapshot taken at `offset` and the bytes sent for it, an RDB file or an EOF marked stream
+struct FullSyncJob {
+    offset: u64,
+    diskless: bool,
+    payload: watch::Receiver<SyncPayload>,
+}
+
+impl FullSyncJob {
+    async fn payload(&self) -> Result<Arc<Vec<u8>>, String> {
+        let mut payload = self.payload.clone();
+        loop {
+            let current = payload.borrow_and_update().clone();
+            if let Some(result) = current {
+                return result;
+            }
+            if payload.changed().await.is_err() {
+                return Err(String::from("Full sync was abandoned"));
+            }
+        }
+    }
+}
+
+// Called with the propagation lock held, so the snapshot matches `offset`
+async fn start_full_sync_job(offset: u64, diskless: bool) -> Arc<FullSyncJob> {
+    let (entries, dirty) = snapshot_database().await;
+    let (sender, payload) = watch::channel(None);
+    let job = Arc::new(FullSyncJob {offset, diskless, payload});
+    let started_job = job.clone();
+    tokio::spawn(async move {
+        let payload = if diskless {
+            // Waits for more replicas to arrive before streaming to the sockets
+            sleep(Duration::from_secs(get_u64_config(b"repl-diskless-sync-delay").await)).await;
+            detach_full_sync_job(&started_job).await;
+            let mark = generate_master_replid();
+            Ok(format_bytes!(b"$EOF:{}\r\n{}{}", &mark[..], encode_rdb(&entries), &mark[..]))
+        } else {
+            let result = bgsave_for_replication(entries, dirty).await;
+            detach_full_sync_job(&started_job).await;
+            result.map(|rdb| serialize(&RespDatatype::RDBFile(rdb)))
+        };
+        let _ = sender.send(Some(payload.map(Arc::new)));
+    });
+    job
+}
+
+async fn detach_full_sync_job(job: &Arc<FullSyncJob>) {
+    let mut sync_job = SYNC_JOB.lock().await;
+    if sync_job.as_ref().is_some_and(|current| Arc::ptr_eq(current, job)) {
+        *sync_job = None;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct OutputBufferLimit {
+    hard: usize,
+    soft: usize,
+    soft_seconds: u64,
+}
+
+const REPLICA_STATE_WAIT_BGSAVE: u8 = 0;
+const REPLICA_STATE_SEND_BULK: u8 = 1;
+const REPLICA_STATE_ONLINE: u8 = 2;
+
+// Shared between the replica's entry in REPLICAS and the tasks serving it
+struct ReplicaStatus {
+    state: AtomicU8,
+    // Replication offset the replica is known to have processed, updated by its REPLCONF ACKs
+    ack_offset: AtomicU64,
+    // Unix time in milliseconds of the last REPLCONF ACK
+    last_ack: AtomicU64,
+}
+
+impl ReplicaStatus {
+    fn state_name(&self) -> &'static str {
+        match self.state.load(Ordering::Relaxed) {
+            REPLICA_STATE_WAIT_BGSAVE => "wait_bgsave",
+            REPLICA_STATE_SEND_BULK => "send_bulk",
+            _ => "online",
+        }
+    }
 }
 
-#[derive(Debug)]
 pub struct Replica {
-    stream: RespStreamHandler,
-    offset: usize,
+    id: usize,
+    ip: String,
+    listening_port: String,
+    sender: UnboundedSender<Arc<Vec<u8>>>,
+    // Bytes handed to the replica's writer that weren't written to the socket yet
+    buffered: Arc<AtomicUsize>,
+    soft_limit_reached: Option<Instant>,
+    disconnect: Arc<Notify>,
+    status: Arc<ReplicaStatus>,
 }
 
 impl Replica {
-    async fn new(stream: RespStreamHandler) {
-        let mut replicas = REPLICAS.lock().await;
-        replicas.push_back(Replica {
-            stream, 
-            offset: 0
-        });
+    fn give_task(&mut self, replica_task: &ReplicaTask, limit: OutputBufferLimit) -> bool {
+        let task_command = replica_task.task_command.clone();
+        let buffered = self.buffered.fetch_add(task_command.len(), Ordering::Relaxed) + task_command.len();
+        if self.sender.send(task_command).is_err() {
+            return false;
+        }
+        if limit.hard > 0 && buffered > limit.hard {
+            println!("Replica {} reached the hard output buffer limit ({buffered} bytes)", self.id);
+            return false;
+        }
+        if limit.soft > 0 && buffered > limit.soft {
+            let soft_limit_reached = *self.soft_limit_reached.get_or_insert_with(Instant::now);
+            if soft_limit_reached.elapsed() >= Duration::from_secs(limit.soft_seconds) {
+                println!("Replica {} stayed over the soft output buffer limit for {} seconds", self.id, limit.soft_seconds);
+                return false;
+            }
+        } else {
+            self.soft_limit_reached = None;
+        }
+        true
     }
+}
 
-    async fn give_task(&mut self, replica_task: &ReplicaTask) {
-        let task_command = &replica_task.task_command[..];
-        self.offset += task_command.len();
-        self.stream.write_all(task_command).await.unwrap();
-        self.stream.stream.flush().await.unwrap();
+// Sends every task to every replica, disconnecting the ones that fall too far behind
+fn give_task_to_replicas(replicas: &mut Replicas, replica_task: &ReplicaTask) {
+    let limit = replicas.output_buffer_limit;
+    let mut kept = LinkedList::new();
+    while let Some(mut replica) = replicas.list.pop_front() {
+        if replica.give_task(replica_task, limit) {
+            kept.push_back(replica);
+        } else {
+            replica.disconnect.notify_one();
+        }
     }
+    replicas.list = kept;
 }
 
-pub async fn handle_replica(stream: RespStreamHandler) {
+pub struct Replicas {
+    list: LinkedList<Replica>,
+    output_buffer_limit: OutputBufferLimit,
+}
+
+pub async fn handle_replica(resp_stream_handler: RespStreamHandler, replica_identifier: ReplicaIdentifier) {
     println!("Accepted replica connection.");
-    Replica::new(stream).await;
-    loop {
-        break;
+    let id = NEXT_REPLICA_ID.fetch_add(1, Ordering::Relaxed);
+    let ip = match resp_stream_handler.stream.peer_addr() {
+        Ok(address) => address.ip().to_string(),
+        Err(_) => String::from("?"),
+    };
+    let (requested_replid, requested_offset) = replica_identifier.psync_request.unwrap_or((b"?".to_vec(), b"-1".to_vec()));
+    let requested_offset = parse_vec_u8::<u64>(requested_offset).ok();
+    let backlog_size = parse_vec_u8::<usize>(get_config(b"repl-backlog-size").await.unwrap_or_default()).unwrap_or(1024 * 1024);
+    let (mut reader, mut writer) = resp_stream_handler.into_split();
+    let (sender, mut receiver) = unbounded_channel();
+    let buffered = Arc::new(AtomicUsize::new(0));
+    let disconnect = Arc::new(Notify::new());
+    let status = Arc::new(ReplicaStatus {
+        state: AtomicU8::new(REPLICA_STATE_WAIT_BGSAVE),
+        ack_offset: AtomicU64::new(0),
+        last_ack: AtomicU64::new(unix_time_millis()),
+    });
+
+    // The replica is registered atomically with the snapshot or backlog it starts from,
+    // writes issued after that wait in its channel until the transfer is done
+    let (preamble, snapshot) = {
+        let _propagation_guard = PROPAGATION_LOCK.lock().await;
+        let mut replicas = REPLICAS.lock().await;
+        let mut backlog = BACKLOG.lock().await;
+
+        let master_replid = get_config(b"master_replid").await.unwrap();
+        // A promoted replica still accepts the previous replication id up to the offset where it was promoted
+        let master_replid2 = get_config(b"master_replid2").await.unwrap_or_default();
+        let second_repl_offset = parse_vec_u8::<i64>(get_config(b"second_repl_offset").await.unwrap_or_default()).unwrap_or(-1);
+        let continuation = match requested_offset {
+            Some(offset) if requested_replid == master_replid => backlog.bytes_from(offset),
+            Some(offset) if requested_replid == master_replid2 && offset as i64 <= second_repl_offset => backlog.bytes_from(offset),
+            _ => None,
+        };
+        let replica = Replica {
+            id,
+            ip,
+            listening_port: replica_identifier.listening_port,
+            sender,
+            buffered: buffered.clone(),
+            soft_limit_reached: None,
+            disconnect: disconnect.clone(),
+            status: status.clone(),
+        };
+        match continuation {
+            Some(continuation) => {
+                println!("Partial resynchronization accepted, sending {} bytes of backlog", continuation.len());
+                status.ack_offset.store(requested_offset.unwrap_or(1) - 1, Ordering::Relaxed);
+                status.state.store(REPLICA_STATE_ONLINE, Ordering::Relaxed);
+                replicas.list.push_back(replica);
+                (format_bytes!(b"+CONTINUE {}\r\n{}", master_replid, continuation), None)
+            },
+            None => {
+                let mut sync_job = SYNC_JOB.lock().await;
+                // Joining a pending sync, the backlog has the writes made since its snapshot
+                let joined = sync_job.as_ref()
+                    .filter(|job| !job.diskless || replica_identifier.capa_eof)
+                    .and_then(|job| backlog.bytes_from(job.offset + 1).map(|catch_up| (job.clone(), catch_up)));
+                let (job, catch_up) = match joined {
+                    Some(joined) => joined,
+                    None => {
+                        backlog.activate(backlog_size);
+                        STREAM_DB_SELECTED.store(false, Ordering::Relaxed);
+                        let diskless = replica_identifier.capa_eof && get_bool_config(b"repl-diskless-sync").await;
+                        let job = start_full_sync_job(backlog.master_repl_offset, diskless).await;
+                        *sync_job = Some(job.clone());
+                        (job, Vec::new())
+                    },
+                };
+                status.ack_offset.store(job.offset, Ordering::Relaxed);
+                replicas.list.push_back(replica);
+                let fullresync = format_bytes!(b"FULLRESYNC {} {}", master_replid, job.offset.to_string().into_bytes());
+                (serialize(&RespDatatype::SimpleString(String::from_utf8(fullresync).unwrap())), Some((job, catch_up)))
+            },
+        }
+    };
+
+    tokio::select! {
+        _ = disconnect.notified() => println!("Disconnecting replica {id}"),
+        _ = async {
+            writer.write_all(&preamble).await?;
+            if let Some((job, catch_up)) = snapshot {
+                let payload = job.payload().await.map_err(std::io::Error::other)?;
+                status.state.store(REPLICA_STATE_SEND_BULK, Ordering::Relaxed);
+                writer.write_all(&payload).await?;
+                writer.write_all(&catch_up).await?;
+                status.state.store(REPLICA_STATE_ONLINE, Ordering::Relaxed);
+            }
+            while let Some(task_command) = receiver.recv().await {
+                writer.write_all(&task_command).await?;
+                buffered.fetch_sub(task_command.len(), Ordering::Relaxed);
+            }
+            Ok::<(), std::io::Error>(())
+        } => println!("Lost connection to replica {id}"),
+        _ = async {
+            while let Ok((resp_object, _)) = reader.deserialize().await {
+                if let Some(offset) = parse_replconf_ack(resp_object) {
+                    status.ack_offset.store(offset, Ordering::Relaxed);
+                    status.last_ack.store(unix_time_millis(), Ordering::Relaxed);
+                    ACK_NOTIFY.notify_waiters();
+                }
+            }
+        } => println!("Replica {id} closed the connection"),
     }
+    let mut replicas = REPLICAS.lock().await;
+    replicas.list = std::mem::take(&mut replicas.list).into_iter().filter(|replica| replica.id != id).collect();
+}
+
+pub async fn lock_propagation() -> MutexGuard<'static, ()> {
+    PROPAGATION_LOCK.lock().await
 }
 
 pub struct ReplicaTask {
-    task_command: Vec<u8>,
+    task_command: Arc<Vec<u8>>,
 }
 
 impl ReplicaTask {
     pub fn new(task_command: Vec<u8>) -> Self {
-        ReplicaTask {task_command}
+        ReplicaTask {task_command: Arc::new(task_command)}
     }
 }
 
-pub async fn push_to_replicas(replica_task: ReplicaTask) {
-    // tokio::spawn(async move {
-        let mut slave_tasks = REPLICA_TASKS.lock().await;
-        slave_tasks.push_back(replica_task);
-    // });
+// The tasks enter the stream back to back, a GETACK from WAIT can't land in the middle of a transaction
+pub async fn push_to_replicas(replica_tasks: Vec<ReplicaTask>) {
+    if replica_tasks.is_empty() {
+        return;
+    }
+    let mut replicas = REPLICAS.lock().await;
+    let mut backlog = BACKLOG.lock().await;
+    if !STREAM_DB_SELECTED.swap(true, Ordering::Relaxed) {
+        let select = ReplicaTask::new(serialize_command(vec![b"SELECT".to_vec(), b"0".to_vec()]));
+        backlog.feed(&select.task_command);
+        give_task_to_replicas(&mut replicas, &select);
+    }
+    for replica_task in replica_tasks {
+        backlog.feed(&replica_task.task_command);
+        give_task_to_replicas(&mut replicas, &replica_task);
+    }
 }
 
-pub async fn wait_to_replicas(start: Instant, numreplicas: usize, timeout: usize) -> usize {
-    
-    let timeout: u64 = timeout.try_into().unwrap();
+// Online replicas that acknowledged an offset in the last max_lag seconds
+fn count_good_replicas(replicas: &Replicas, max_lag: u64) -> usize {
+    let now = unix_time_millis();
+    replicas.list.iter()
+        .filter(|replica| replica.status.state.load(Ordering::Relaxed) == REPLICA_STATE_ONLINE)
+        .filter(|replica| now.saturating_sub(replica.status.last_ack.load(Ordering::Relaxed)) / 1000 <= max_lag)
+        .count()
+}
+
+// min-replicas-to-write is enabled when both options are set
+async fn min_replicas_policy() -> Option<(usize, u64)> {
+    let min_replicas = get_u64_config(b"min-replicas-to-write").await;
+    let max_lag = get_u64_config(b"min-replicas-max-lag").await;
+    if min_replicas == 0 || max_lag == 0 {
+        return None;
+    }
+    Some((min_replicas as usize, max_lag))
+}
+
+pub async fn has_enough_good_replicas() -> bool {
+    match min_replicas_policy().await {
+        Some((min_replicas, max_lag)) => count_good_replicas(&*REPLICAS.lock().await, max_lag) >= min_replicas,
+        None => true,
+    }
+}
+
+// "connected_slaves" and "slaveN" lines of INFO replication
+pub async fn replicas_info() -> Vec<u8> {
+    let policy = min_replicas_policy().await;
+    let replicas = REPLICAS.lock().await;
+    let now = unix_time_millis();
+    let mut info = format!("connected_slaves:{}\r\n", replicas.list.len());
+    if let Some((_, max_lag)) = policy {
+        info.push_str(&format!("min_slaves_good_slaves:{}\r\n", count_good_replicas(&replicas, max_lag)));
+    }
+    for (i, replica) in replicas.list.iter().enumerate() {
+        info.push_str(&format!(
+            "slave{i}:ip={},port={},state={},offset={},lag={}\r\n",
+            replica.ip,
+            replica.listening_port,
+            replica.status.state_name(),
+            replica.status.ack_offset.load(Ordering::Relaxed),
+            now.saturating_sub(replica.status.last_ack.load(Ordering::Relaxed)) / 1000,
+        ));
+    }
+    info.into_bytes()
+}
+
+// [ip, port, acknowledged offset] of every replica, for ROLE
+pub async fn replicas_role() -> Vec<RespDatatype> {
+    let replicas = REPLICAS.lock().await;
+    replicas.list.iter()
+        .map(|replica| RespDatatype::Array(vec![
+            RespDatatype::BulkString(replica.ip.as_bytes().to_vec()),
+            RespDatatype::BulkString(replica.listening_port.as_bytes().to_vec()),
+            RespDatatype::BulkString(replica.status.ack_offset.load(Ordering::Relaxed).to_string().into_bytes()),
+        ]))
+        .collect()
+}
+
+pub async fn set_replica_output_buffer_limit(hard: usize, soft: usize, soft_seconds: u64) {
+    REPLICAS.lock().await.output_buffer_limit = OutputBufferLimit {hard, soft, soft_seconds};
+}
+
+pub async fn master_repl_offset() -> u64 {
+    BACKLOG.lock().await.master_repl_offset
+}
+
+// Used by replicas to follow the replication offset of their master, the backlog
+// keeps the master's stream so this replica can serve partial resyncs once promoted
+pub async fn reset_master_repl_offset(offset: u64) {
+    let backlog_size = parse_vec_u8::<usize>(get_config(b"repl-backlog-size").await.unwrap_or_default()).unwrap_or(1024 * 1024);
+    let mut backlog = BACKLOG.lock().await;
+    *backlog = ReplicationBacklog::new();
+    backlog.master_repl_offset = offset;
+    backlog.activate(backlog_size);
+}
+
+// Drops every replica connection when this server's replication history or id changes,
+// they reconnect and learn about it through PSYNC
+pub async fn disconnect_replicas() {
+    let mut replicas = REPLICAS.lock().await;
+    while let Some(replica) = replicas.list.pop_front() {
+        replica.disconnect.notify_one();
+    }
+    // A pending sync was taken at an offset of the previous stream
+    *SYNC_JOB.lock().await = None;
+    // The stream a promoted replica starts producing has to select the database again
+    STREAM_DB_SELECTED.store(false, Ordering::Relaxed);
+}
+
+// A replica forwards its master's stream byte for byte, so its sub-replicas share the master's replid and offsets
+pub async fn proxy_to_replicas(bytes: Vec<u8>) {
     let mut replicas = REPLICAS.lock().await;
-    let mut num_replies = 0;
-    let replconf_getack: &[u8] = b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
-    let mut busy_replicas: Vec<&mut Replica> = Vec::new();
-    for replica in replicas.iter_mut() {
-        if replica.offset == 0 {
-            num_replies += 1;
-            continue
+    let mut backlog = BACKLOG.lock().await;
+    let replica_task = ReplicaTask::new(bytes);
+    backlog.feed(&replica_task.task_command);
+    give_task_to_replicas(&mut replicas, &replica_task);
+}
+
+pub async fn resize_backlog(size: usize) {
+    BACKLOG.lock().await.resize(size);
+}
+
+pub async fn backlog_info() -> Vec<u8> {
+    let backlog = BACKLOG.lock().await;
+    format!(
+        "repl_backlog_active:{}\r\nrepl_backlog_size:{}\r\nrepl_backlog_first_byte_offset:{}\r\nrepl_backlog_histlen:{}\r\n",
+        backlog.is_active() as u8,
+        backlog.buffer.len(),
+        if backlog.is_active() {backlog.first_byte_offset()} else {0},
+        backlog.histlen,
+    ).into_bytes()
+}
+
+fn parse_replconf_ack(resp_object: RespDatatype) -> Option<u64> {
+    let array = match resp_object {
+        RespDatatype::Array(array) if array.len() == 3 => array,
+        _ => return None,
+    };
+    match (&array[0], &array[1], &array[2]) {
+        (RespDatatype::BulkString(replconf), RespDatatype::BulkString(ack), RespDatatype::BulkString(offset))
+            if replconf.eq_ignore_ascii_case(b"REPLCONF") && ack.eq_ignore_ascii_case(b"ACK") => {
+            parse_vec_u8::<u64>(offset.clone()).ok()
+        },
+        _ => None,
+    }
+}
+
+async fn count_acked_replicas(target_offset: u64) -> usize {
+    let replicas = REPLICAS.lock().await;
+    replicas.list.iter()
+        .filter(|replica| replica.status.ack_offset.load(Ordering::Relaxed) >= target_offset)
+        .count()
+}
+
+// Address and state of the replica listening on host:port, if it is connected.
+// The host is resolved first, replicas are known by the ip they connected from
+pub async fn replica_state(host: &str, port: &str) -> Option<(String, &'static str)> {
+    let addresses: Vec<IpAddr> = match port.parse::<u16>() {
+        Ok(port) => lookup_host((host, port)).await.map(|addresses| addresses.map(|address| address.ip().to_canonical()).collect()).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    };
+    let replicas = REPLICAS.lock().await;
+    replicas.list.iter()
+        .filter(|replica| replica.listening_port == port)
+        .find(|replica| replica.ip == host || replica.ip.parse::<IpAddr>().is_ok_and(|ip| addresses.contains(&ip.to_canonical())))
+        .map(|replica| (replica.ip.clone(), replica.status.state_name()))
+}
+
+// Waits until the target, or any replica when there is none, acknowledged the whole stream.
+// Returns the address of that replica, None when the deadline passes first
+pub async fn wait_for_replica_caught_up(target: Option<(String, String)>, deadline: Option<Instant>) -> Option<(String, String)> {
+    loop {
+        let notified = ACK_NOTIFY.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        {
+            let replicas = REPLICAS.lock().await;
+            let master_repl_offset = BACKLOG.lock().await.master_repl_offset;
+            let caught_up = replicas.list.iter()
+                .filter(|replica| target.as_ref().is_none_or(|(host, port)| replica.ip == *host && replica.listening_port == *port))
+                .find(|replica| replica.status.ack_offset.load(Ordering::Relaxed) >= master_repl_offset);
+            if let Some(replica) = caught_up {
+                return Some((replica.ip.clone(), replica.listening_port.clone()));
+            }
         }
-        replica.stream.stream.write_all(&replconf_getack).await.unwrap_or(());
-        busy_replicas.push(replica);
-    }
-    let mut buf: Vec<u8> = Vec::new();
-
-    if num_replies >= numreplicas {
-        return num_replies;
-    }
-    let mut remove_indeces: Vec<bool> = vec![true; busy_replicas.len()];
-    while start.elapsed() < Duration::from_millis(timeout) || timeout == 0 {
-        for i in 0..busy_replicas.len() {
-            if remove_indeces[i] {
-                buf.clear();
-                let replica = &busy_replicas[i];
-                match busy_replicas[i].stream.stream.try_read_buf(&mut buf) {
-                    Ok(0) => continue,
-                    Ok(_) => {
-                        match deserialize(&buf) {
-                            Some(RespDatatype::Array(array)) => {
-                                if array.len() != 3 {
-                                    continue;
-                                }
-                                match &array[0] {
-                                    RespDatatype::BulkString(replconf) => {
-                                        if &replconf[..] != b"REPLCONF" {continue}
-                                    }
-                                    _ => continue,
-                                }
-                                match &array[1] {
-                                    RespDatatype::BulkString(ack) => {
-                                        if &ack[..] != b"ACK" {continue}
-                                    }
-                                    _ => continue,
-                                }
-                                match &array[2] {
-                                    RespDatatype::BulkString(offset) => {
-                                        let offset  = match String::from_utf8(offset.clone()) {
-                                            Ok(offset) => offset,
-                                            _ => continue,
-                                        };
-                                        let offset = match offset.parse::<usize>() {
-                                            Ok(offset) => offset,
-                                            _ => continue,
-                                        };
-                                        if offset >= replica.offset {
-                                            num_replies += 1;
-                                            remove_indeces[i] = false;
-                                        }
-                                    }
-                                    _ => continue,
-                                }
-                            },
-                            _ => (),
-                        }
-                    }
-                    Err(_) => continue,
-                }
-                if num_replies == numreplicas {
-                    break;
+        match deadline {
+            Some(deadline) => {
+                if timeout_at(deadline, notified).await.is_err() {
+                    return None;
                 }
-            }
+            },
+            None => notified.await,
         }
-        sleep(Duration::from_millis(1)).await;
-    }
-
-    return num_replies; 
-}
-
-// pub async fn wait_to_replicas(numreplicas: usize, timeout: usize) -> usize {
-
-//     // let mutex_timeout: Mutex<bool> = Mutex::from(true);
-//     let start = Instant::now();
-//     let mutex_num_replies: Mutex<usize> = Mutex::from(0);
-//     // let timeout: *const Mutex<bool> = &mutex_timeout as *const Mutex<bool>;
-//     // let num_replies: *const Mutex<usize> = &mutex_num_replies as * const Mutex<usize>;
-
-//     let mut replicas = REPLICAS.lock().await;   
-//     let mut wait_futures: Vec<JoinHandle<()>> = Vec::with_capacity(replicas.len());
-//     let replconf_getack: &[u8] = b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
-//     for replica in replicas.iter_mut() {
-//         replica.stream.stream.write_all(replconf_getack).await.unwrap();
-//     }
-//     for replica in replicas.iter_mut() {
-//         let wait_future = tokio::spawn(async {
-//             match replica.stream.deserialize().await {
-//                 Ok((RespDatatype::Array(array), _)) => {
-//                     if array.len() != 3 {
-//                         return;
-//                     }
-//                     match &array[0] {
-//                         RespDatatype::BulkString(replconf) => {
-//                             if &replconf[..] != b"REPLCONF" {return}
-//                         }
-//                         _ => return,
-//                     }
-//                     match &array[1] {
-//                         RespDatatype::BulkString(ack) => {
-//                             if &ack[..] != b"ACL" {return}
-//                         }
-//                         _ => return,
-//                     }
-//                     match &array[2] {
-//                         RespDatatype::BulkString(offset) => {
-//                             let offset  = match String::from_utf8(offset.clone()) {
-//                                 Ok(offset) => offset,
-//                                 _ => return,
-//                             };
-//                             let offset = match offset.parse::<usize>() {
-//                                 Ok(offset) => offset,
-//                                 _ => return,
-//                             };
-
-//                         }
-//                         _ => return,
-//                     }
-//                 },
-//                 _ => (),
-//             }
-//         });
-//         wait_futures.push(wait_future);
-//     }
-
-//     while start.elapsed() < Duration::from_millis(timeout.try_into().unwrap()) || num_replies <= numreplicas {
-
-//     }
-
-//     todo!();
-// }
-
-pub fn start_replicas() {
-    tokio::spawn(async {
-        handle_replicas().await;
-    });
+    }
 }
 
-async fn handle_replicas() {
-    loop {
+pub async fn wait_to_replicas(start: Instant, numreplicas: usize, timeout: usize) -> usize {
+    let deadline = match timeout {
+        0 => None,
+        timeout => Some(start + Duration::from_millis(timeout as u64)),
+    };
+    let replconf_getack = ReplicaTask::new(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n".to_vec());
+    let target_offset = {
         let mut replicas = REPLICAS.lock().await;
-        let replica_task = {REPLICA_TASKS.lock().await.pop_front()};
-        match replica_task {
-            Some(replica_task) => {
-                for slave in replicas.iter_mut() {
-                    slave.give_task(&replica_task).await;
+        let mut backlog = BACKLOG.lock().await;
+        let target_offset = backlog.master_repl_offset;
+        // GETACK is part of the replication stream, so it counts towards the offset
+        if replicas.list.iter().any(|replica| replica.status.ack_offset.load(Ordering::Relaxed) < target_offset) {
+            backlog.feed(&replconf_getack.task_command);
+            give_task_to_replicas(&mut replicas, &replconf_getack);
+        }
+        target_offset
+    };
+
+    loop {
+        // Registered before counting, so an ACK arriving in between still wakes this waiter
+        let notified = ACK_NOTIFY.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        let num_replies = count_acked_replicas(target_offset).await;
+        if num_replies >= numreplicas {
+            return num_replies;
+        }
+        match deadline {
+            Some(deadline) => {
+                if timeout_at(deadline, notified).await.is_err() {
+                    return count_acked_replicas(target_offset).await;
                 }
             },
-            None => (),
+            None => notified.await,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_counts_bytes_before_it_is_allocated() {
+        let mut backlog = ReplicationBacklog::new();
+        backlog.feed(b"abc");
+        assert_eq!(backlog.master_repl_offset, 3);
+        assert_eq!(backlog.bytes_from(1), None);
+        backlog.activate(8);
+        backlog.feed(b"de");
+        assert_eq!(backlog.first_byte_offset(), 4);
+        assert_eq!(backlog.bytes_from(4), Some(b"de".to_vec()));
+        assert_eq!(backlog.bytes_from(3), None);
+    }
+
+    #[test]
+    fn backlog_bytes_from_wraps_around() {
+        let mut backlog = ReplicationBacklog::new();
+        backlog.activate(8);
+        backlog.feed(b"abcdef");
+        backlog.feed(b"ghij");
+        assert_eq!(backlog.histlen, 8);
+        assert_eq!(backlog.first_byte_offset(), 3);
+        assert_eq!(backlog.bytes_from(3), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.bytes_from(7), Some(b"ghij".to_vec()));
+        // The offset right after the last byte is a valid empty continuation
+        assert_eq!(backlog.bytes_from(11), Some(Vec::new()));
+        assert_eq!(backlog.bytes_from(2), None);
+        assert_eq!(backlog.bytes_from(12), None);
+    }
+
+    #[test]
+    fn backlog_keeps_the_tail_of_a_feed_larger_than_itself() {
+        let mut backlog = ReplicationBacklog::new();
+        backlog.activate(4);
+        backlog.feed(b"x");
+        backlog.feed(b"0123456789");
+        assert_eq!(backlog.master_repl_offset, 11);
+        assert_eq!(backlog.bytes_from(backlog.first_byte_offset()), Some(b"6789".to_vec()));
+    }
+
+    #[test]
+    fn backlog_resize_keeps_the_most_recent_bytes() {
+        let mut backlog = ReplicationBacklog::new();
+        backlog.activate(8);
+        backlog.feed(b"abcdefghij");
+        backlog.resize(4);
+        assert_eq!(backlog.first_byte_offset(), 7);
+        assert_eq!(backlog.bytes_from(7), Some(b"ghij".to_vec()));
+        backlog.resize(6);
+        assert_eq!(backlog.bytes_from(7), Some(b"ghij".to_vec()));
+        backlog.feed(b"klm");
+        assert_eq!(backlog.master_repl_offset, 13);
+        assert_eq!(backlog.bytes_from(8), Some(b"hijklm".to_vec()));
+        assert_eq!(backlog.bytes_from(7), None);
+    }
+}
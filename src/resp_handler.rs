@@ -1,9 +1,8 @@
 use std::{error::Error, io};
 use anyhow::anyhow;
 use async_recursion::async_recursion;
-use bytes::BufMut;
 use format_bytes::format_bytes;
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream}};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum RespDatatype {
@@ -33,21 +32,54 @@ pub enum RespDatatype {
 // This should be able to handle concurrent streams of commands
 // This should be able to extract RespDatatypes and RDB files
 #[derive(Debug)]
-pub struct RespStreamHandler {
-    pub stream: TcpStream,
+pub struct RespStreamHandler<S: ReadStream = TcpStream> {
+    pub stream: S,
     buf: Vec<u8>,
     cursor: usize,
 }
 
-impl RespStreamHandler {
-    pub fn new(stream: TcpStream) -> Self {
-        Self {stream, buf: Vec::new(), cursor: 0}
+// Streams RespStreamHandler can read from, the whole TcpStream or its read half
+pub trait ReadStream: AsyncRead + Unpin + Send {
+    fn try_read_buf(&self, buf: &mut Vec<u8>) -> io::Result<usize>;
+}
+
+impl ReadStream for TcpStream {
+    fn try_read_buf(&self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        TcpStream::try_read_buf(self, buf)
+    }
+}
+
+impl ReadStream for OwnedReadHalf {
+    fn try_read_buf(&self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        OwnedReadHalf::try_read_buf(self, buf)
     }
+}
 
+impl RespStreamHandler<TcpStream> {
     pub async fn write_all(&mut self, buf: &[u8]) -> Result<(), Box<dyn Error>> {
         Ok(self.stream.write_all(buf).await?)
     }
 
+    // Splits off the write half, bytes already buffered stay with the reader
+    pub fn into_split(self) -> (RespStreamHandler<OwnedReadHalf>, OwnedWriteHalf) {
+        let (reader, writer) = self.stream.into_split();
+        (RespStreamHandler {stream: reader, buf: self.buf, cursor: self.cursor}, writer)
+    }
+}
+
+impl RespStreamHandler<OwnedReadHalf> {
+    // Puts the connection back together after into_split
+    pub fn reunite(self, writer: OwnedWriteHalf) -> Result<RespStreamHandler<TcpStream>, Box<dyn Error>> {
+        let stream = self.stream.reunite(writer)?;
+        Ok(RespStreamHandler {stream, buf: self.buf, cursor: self.cursor})
+    }
+}
+
+impl<S: ReadStream> RespStreamHandler<S> {
+    pub fn new(stream: S) -> Self {
+        Self {stream, buf: Vec::new(), cursor: 0}
+    }
+
     pub async fn is_shutdown(&mut self) -> bool {
         if self.buf.len() > 0 {
             return false;
@@ -65,7 +97,11 @@ impl RespStreamHandler {
             Err(e) => return Err(e.into()),
         };
         while min_size > self.buf.len() {
-            bytes_filled += self.stream.read_buf(&mut self.buf).await?;
+            // A read of 0 bytes means the peer closed the connection
+            match self.stream.read_buf(&mut self.buf).await? {
+                0 => return Err(Box::from(anyhow!("Connection closed by peer"))),
+                bytes_read => bytes_filled += bytes_read,
+            }
         }
         Ok(bytes_filled)
     }
@@ -123,6 +159,26 @@ impl RespStreamHandler {
 
     pub async fn get_rdb(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
         let slice = self.get_until_crnl().await?;
+        // Diskless syncs don't know the size upfront, the file ends with the 40 byte mark instead
+        if let Some(mark) = slice.strip_prefix(b"$EOF:") {
+            let mark = mark.to_vec();
+            if mark.len() != 40 {
+                return Err(Box::from(anyhow!("Invalid RDB EOF mark")));
+            }
+            let start = self.cursor;
+            let mut searched = start;
+            loop {
+                if let Some(position) = self.buf[searched..].windows(mark.len()).position(|window| window == mark) {
+                    let end = searched + position;
+                    let rdb = self.buf[start..end].to_vec();
+                    self.cursor = end + mark.len();
+                    self.get_drained();
+                    return Ok(rdb);
+                }
+                searched = self.buf.len().saturating_sub(mark.len() - 1).max(start);
+                self.refill(self.buf.len() + 1).await?;
+            }
+        }
         let size = match slice[0] {
             b'$' => String::from_utf8(slice[1..].to_vec())?.parse::<usize>()?,
             _ => return Err(Box::from(anyhow!("Invalid RDB file format"))),
@@ -174,146 +230,6 @@ impl RespStreamHandler {
     }
 }
 
-pub fn deserialize(buf: &Vec<u8>) -> Option<RespDatatype> {
-    let mut splice_array: Vec<&[u8]> = Vec::new();
-    {
-        let mut last_split = 0;
-        for i in 0..buf.len() - 1 {
-            if &buf[i..i + 2] == b"\r\n" {
-                splice_array.push(&buf[last_split..i]);
-                last_split = i + 2;
-            }
-        }
-    }
-
-    return match deserialize_recursive(0, &splice_array) {
-        Some((resp_object, i)) => {
-            if i == splice_array.len() {
-                Some(resp_object)
-            } else {
-                None
-            }
-        }
-        None => None,
-    };
-
-}
-
-fn deserialize_recursive(mut i: usize, splice_array: &Vec<&[u8]>) -> Option<(RespDatatype, usize)> {
-    if i >= splice_array.len() {
-        return None;
-    }
-    let splice = splice_array[i];
-    match splice[0] {
-        b'+' => match String::from_utf8(splice[1..].to_vec()) {
-            Ok(simple_string) => {
-                return Some((RespDatatype::SimpleString(simple_string), i + 1));
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-            }
-        },
-        b'-' => match String::from_utf8(splice[1..].to_vec()) {
-            Ok(simple_error) => {
-                return Some((RespDatatype::SimpleError(simple_error), i + 1));
-            }
-            Err(e) => {
-                println!("Error: {}", e);
-            }
-        },
-        b':' => match String::from_utf8(splice[1..].to_vec()) {
-            Ok(integer_string) => match integer_string.parse::<i64>() {
-                Ok(integer) => {
-                    return Some((RespDatatype::Integer(integer), i + 1));
-                }
-                Err(e) => {
-                    println!("Error: {}", e);
-                }
-            },
-            Err(e) => {
-                println!("Error: {}", e);
-            }
-        },
-        b'$' => {
-            let bulk_length: isize;
-            match String::from_utf8(splice[1..].to_vec()) {
-                Ok(length_string) => match length_string.parse() {
-                    Ok(length) => {
-                        bulk_length = length;
-                    }
-                    Err(e) => {
-                        println!("Error: {}", e);
-                        return None;
-                    }
-                },
-                Err(e) => {
-                    println!("Error: {}", e);
-                    return None;
-                }
-            };
-            if bulk_length < 0 {
-                return Some((RespDatatype::NullBulkString, i + 1));
-            }
-            i += 1;
-            let bulk_length: usize = bulk_length.try_into().unwrap();
-            let mut bulk_string: Vec<u8> = Vec::with_capacity(bulk_length);
-            loop {
-                if i >= splice_array.len() {
-                    return None;
-                }
-                bulk_string.put_slice(splice_array[i]);
-                if bulk_string.len() < bulk_length {
-                    bulk_string.put_slice(b"\r\n");
-                    i += 1;
-                } else {
-                    break;
-                }
-            }
-            if bulk_string.len() == bulk_length {
-                return Some((RespDatatype::BulkString(bulk_string), i + 1));
-            }
-        }
-        b'*' => {
-            let array_length: isize;
-            match String::from_utf8(splice[1..].to_vec()) {
-                Ok(length_string) => match length_string.parse() {
-                    Ok(length) => {
-                        array_length = length;
-                    }
-                    Err(e) => {
-                        println!("Error: {}", e);
-                        return None;
-                    }
-                },
-                Err(e) => {
-                    println!("Error: {}", e);
-                    return None;
-                }
-            };
-            if array_length < 0 {
-                return Some((RespDatatype::NullArray, i + 1));
-            }
-            i += 1;
-            let array_length: usize = array_length.try_into().unwrap();
-            let mut array: Vec<RespDatatype> = Vec::with_capacity(array_length);
-            for _ in 0..array_length {
-                match deserialize_recursive(i, splice_array) {
-                    Some((resp_object, next_i)) => {
-                        array.push(resp_object);
-                        i = next_i;
-                    }
-                    None => return None,
-                }
-            }
-            return Some((RespDatatype::Array(array), i));
-        }
-        _ => (),
-    }
-
-    return None;
-
-}
-
 pub fn serialize(resp_object: &RespDatatype) -> Vec<u8> {
     let mut bytes: Vec<u8> = Vec::new();
     serialize_recursive(&mut bytes, resp_object);
@@ -0,0 +1,700 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use tokio::net::{lookup_host, TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Instant};
+
+use crate::{generate_master_replid, serialize, serialize_command, RespDatatype, RespStreamHandler};
+
+const HELLO_CHANNEL: &[u8] = b"__sentinel__:hello";
+const PING_PERIOD: Duration = Duration::from_secs(1);
+const HELLO_PERIOD: Duration = Duration::from_secs(2);
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(500);
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SENTINEL_PORT: &str = "26379";
+const DEFAULT_DOWN_AFTER: Duration = Duration::from_secs(30);
+const DEFAULT_FAILOVER_TIMEOUT: Duration = Duration::from_secs(180);
+
+lazy_static! {
+    static ref SENTINEL: Mutex<Sentinel> = Mutex::new(Sentinel {
+        run_id: String::new(),
+        ip: String::new(),
+        port: String::new(),
+        current_epoch: 0,
+        masters: Vec::new(),
+    });
+}
+
+struct Sentinel {
+    run_id: String,
+    // Address announced to the other sentinels
+    ip: String,
+    port: String,
+    current_epoch: u64,
+    masters: Vec<MonitoredMaster>,
+}
+
+struct MonitoredMaster {
+    name: String,
+    ip: String,
+    port: String,
+    quorum: usize,
+    down_after: Duration,
+    failover_timeout: Duration,
+    // Epoch of the failover that made this address the master, the highest one wins between sentinels
+    config_epoch: u64,
+    last_ok_ping: Instant,
+    subjectively_down: bool,
+    objectively_down: bool,
+    replicas: Vec<KnownReplica>,
+    // Other sentinels monitoring the master, by run id
+    sentinels: HashMap<String, KnownSentinel>,
+    // The sentinel this one voted for as failover leader, and in which epoch
+    leader: Option<String>,
+    leader_epoch: u64,
+    // Epoch this sentinel asks the others to elect it in, while it tries to fail over
+    election: Option<(u64, Instant)>,
+    // Failovers are retried twice the failover timeout after the last attempt or vote
+    failover_started: Option<Instant>,
+    // Instances whose hello channel this sentinel follows
+    hello_listeners: HashSet<(String, String)>,
+}
+
+struct KnownReplica {
+    ip: String,
+    port: String,
+    last_ok_ping: Option<Instant>,
+    is_master: bool,
+    link_up: bool,
+    repl_offset: u64,
+}
+
+struct KnownSentinel {
+    ip: String,
+    port: String,
+    last_hello: Instant,
+}
+
+impl MonitoredMaster {
+    fn new(name: String, ip: String, port: String, quorum: usize) -> Self {
+        MonitoredMaster {
+            name,
+            ip,
+            port,
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            config_epoch: 0,
+            last_ok_ping: Instant::now(),
+            subjectively_down: false,
+            objectively_down: false,
+            replicas: Vec::new(),
+            sentinels: HashMap::new(),
+            leader: None,
+            leader_epoch: 0,
+            election: None,
+            failover_started: None,
+            hello_listeners: HashSet::new(),
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = String::from("master");
+        if self.subjectively_down {
+            flags.push_str(",s_down");
+        }
+        if self.objectively_down {
+            flags.push_str(",o_down");
+        }
+        if self.election.is_some() {
+            flags.push_str(",failover_in_progress");
+        }
+        flags
+    }
+
+    fn replica_mut(&mut self, ip: &str, port: &str) -> &mut KnownReplica {
+        let position = match self.replicas.iter().position(|replica| replica.ip == ip && replica.port == port) {
+            Some(position) => position,
+            None => {
+                println!("+slave slave {ip}:{port} @ {} {} {}", self.name, self.ip, self.port);
+                self.replicas.push(KnownReplica {ip: ip.to_string(), port: port.to_string(), last_ok_ping: None, is_master: false, link_up: false, repl_offset: 0});
+                self.replicas.len() - 1
+            },
+        };
+        &mut self.replicas[position]
+    }
+
+    // The old master stays known as a replica, it is reconfigured once it comes back
+    fn switch_to(&mut self, ip: String, port: String, config_epoch: u64) {
+        println!("+switch-master {} {} {} {ip} {port}", self.name, self.ip, self.port);
+        let old_ip = std::mem::replace(&mut self.ip, ip);
+        let old_port = std::mem::replace(&mut self.port, port);
+        self.replicas.retain(|replica| replica.ip != self.ip || replica.port != self.port);
+        self.replica_mut(&old_ip, &old_port);
+        self.config_epoch = config_epoch;
+        self.last_ok_ping = Instant::now();
+        self.subjectively_down = false;
+        self.objectively_down = false;
+        self.election = None;
+    }
+
+    // The master and its replicas, hellos go through all of them so sentinels still meet while the master is down
+    fn instances(&self) -> Vec<(String, String)> {
+        let mut instances = vec![(self.ip.clone(), self.port.clone())];
+        instances.extend(self.replicas.iter().map(|replica| (replica.ip.clone(), replica.port.clone())));
+        instances
+    }
+
+    fn fields(&self) -> Vec<RespDatatype> {
+        fields(vec![
+            ("name", self.name.clone()),
+            ("ip", self.ip.clone()),
+            ("port", self.port.clone()),
+            ("flags", self.flags()),
+            ("last-ok-ping-reply", self.last_ok_ping.elapsed().as_millis().to_string()),
+            ("num-slaves", self.replicas.len().to_string()),
+            ("num-other-sentinels", self.sentinels.len().to_string()),
+            ("quorum", self.quorum.to_string()),
+            ("down-after-milliseconds", self.down_after.as_millis().to_string()),
+            ("failover-timeout", self.failover_timeout.as_millis().to_string()),
+            ("config-epoch", self.config_epoch.to_string()),
+        ])
+    }
+}
+
+fn fields(fields: Vec<(&str, String)>) -> Vec<RespDatatype> {
+    fields.into_iter()
+        .flat_map(|(name, value)| [RespDatatype::BulkString(name.as_bytes().to_vec()), RespDatatype::BulkString(value.into_bytes())])
+        .collect()
+}
+
+// Runs the server as a sentinel, monitoring the masters named in the config file instead of holding data
+pub async fn run_sentinel(config_path: &str, port: Option<String>) {
+    let contents = tokio::fs::read_to_string(config_path).await.expect("Couldn't read the sentinel config file");
+    let (config_port, masters) = match parse_sentinel_config(&contents).await {
+        Ok(config) => config,
+        Err(e) => panic!("Failed to load the sentinel config: {e}"),
+    };
+    let port = port.or(config_port).unwrap_or_else(|| DEFAULT_SENTINEL_PORT.to_string());
+    let listener = TcpListener::bind(format!("127.0.0.1:{port}")).await.expect("Couldn't start the sentinel");
+
+    let mut sentinel = SENTINEL.lock().await;
+    sentinel.run_id = String::from_utf8(generate_master_replid()).unwrap();
+    sentinel.ip = String::from("127.0.0.1");
+    sentinel.port = port;
+    println!("Sentinel ID is {}", sentinel.run_id);
+    for master in masters {
+        println!("+monitor master {} {} {} quorum {}", master.name, master.ip, master.port, master.quorum);
+        tokio::spawn(monitor_master(master.name.clone()));
+        sentinel.masters.push(master);
+    }
+    drop(sentinel);
+
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handle_sentinel_client(stream));
+        }
+    }
+}
+
+async fn parse_sentinel_config(contents: &str) -> Result<(Option<String>, Vec<MonitoredMaster>), String> {
+    let mut port = None;
+    let mut masters: Vec<MonitoredMaster> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let invalid = || format!("Invalid directive on line {}: {line}", number + 1);
+        let milliseconds = |value: &str| value.parse::<u64>().map(Duration::from_millis).map_err(|_| invalid());
+        match words[..] {
+            [] => (),
+            [comment, ..] if comment.starts_with('#') => (),
+            ["port", value] => {
+                value.parse::<u16>().map_err(|_| invalid())?;
+                port = Some(value.to_string());
+            },
+            ["sentinel", "monitor", name, host, master_port, quorum] => {
+                let quorum = quorum.parse::<usize>().ok().filter(|quorum| *quorum > 0).ok_or_else(invalid)?;
+                master_port.parse::<u16>().map_err(|_| invalid())?;
+                // Sentinels compare masters by address, so hostnames are resolved once like redis does
+                let ip = lookup_host(format!("{host}:{master_port}")).await.ok()
+                    .and_then(|mut addresses| addresses.find(|address| address.is_ipv4()))
+                    .map(|address| address.ip().to_string())
+                    .ok_or_else(|| format!("Can't resolve master address on line {}: {host}", number + 1))?;
+                masters.push(MonitoredMaster::new(name.to_string(), ip, master_port.to_string(), quorum));
+            },
+            ["sentinel", option, name, value] => {
+                let master = masters.iter_mut().find(|master| master.name == name)
+                    .ok_or_else(|| format!("No such master with specified name on line {}: {name}", number + 1))?;
+                match option {
+                    "down-after-milliseconds" => master.down_after = milliseconds(value)?,
+                    "failover-timeout" => master.failover_timeout = milliseconds(value)?,
+                    _ => return Err(invalid()),
+                }
+            },
+            _ => return Err(invalid()),
+        }
+    }
+    Ok((port, masters))
+}
+
+// Sends one command on a fresh connection, an unreachable instance just doesn't answer in time
+async fn send_command(ip: &str, port: &str, argv: Vec<&[u8]>) -> Result<RespDatatype, String> {
+    let exchange = async {
+        let stream = TcpStream::connect(format!("{ip}:{port}")).await.map_err(|e| e.to_string())?;
+        let mut resp_stream_handler = RespStreamHandler::new(stream);
+        let command = serialize_command(argv.into_iter().map(|argument| argument.to_vec()).collect());
+        resp_stream_handler.write_all(&command).await.map_err(|e| e.to_string())?;
+        let (reply, _) = resp_stream_handler.deserialize().await.map_err(|e| e.to_string())?;
+        Ok(reply)
+    };
+    timeout(COMMAND_TIMEOUT, exchange).await.map_err(|_| String::from("Timed out"))?
+}
+
+// The fields of an INFO reply, as name to value
+async fn fetch_info(ip: &str, port: &str) -> Option<HashMap<String, String>> {
+    match send_command(ip, port, vec![b"INFO", b"replication"]).await {
+        Ok(RespDatatype::BulkString(info)) => Some(String::from_utf8_lossy(&info).lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()),
+        _ => None,
+    }
+}
+
+// "ip=127.0.0.1,port=6380,state=online,..." from a master's slaveN lines
+fn parse_replica_line(line: &str) -> Option<(String, String)> {
+    let fields: HashMap<&str, &str> = line.split(',').filter_map(|field| field.split_once('=')).collect();
+    Some((fields.get("ip")?.to_string(), fields.get("port")?.to_string()))
+}
+
+async fn monitor_master(name: String) {
+    let mut last_hello = Instant::now() - HELLO_PERIOD;
+    loop {
+        sleep(PING_PERIOD).await;
+        let (ip, port, replicas) = {
+            let sentinel = SENTINEL.lock().await;
+            let master = match sentinel.masters.iter().find(|master| master.name == name) {
+                Some(master) => master,
+                None => return,
+            };
+            let replicas: Vec<(String, String)> = master.replicas.iter().map(|replica| (replica.ip.clone(), replica.port.clone())).collect();
+            (master.ip.clone(), master.port.clone(), replicas)
+        };
+
+        // A master that is loading or lost its own master still counts as reachable
+        let ping_ok = match send_command(&ip, &port, vec![b"PING"]).await {
+            Ok(RespDatatype::SimpleString(pong)) => pong == "PONG",
+            Ok(RespDatatype::SimpleError(e)) => e.starts_with("LOADING") || e.starts_with("MASTERDOWN"),
+            _ => false,
+        };
+        let master_info = fetch_info(&ip, &port).await;
+        let mut replica_infos = Vec::new();
+        for (replica_ip, replica_port) in replicas {
+            let info = fetch_info(&replica_ip, &replica_port).await;
+            replica_infos.push((replica_ip, replica_port, info));
+        }
+
+        let mut strays = Vec::new();
+        {
+            let mut sentinel = SENTINEL.lock().await;
+            let master = match sentinel.masters.iter_mut().find(|master| master.name == name) {
+                Some(master) => master,
+                None => return,
+            };
+            // The master moved while this round was running
+            if master.ip != ip || master.port != port {
+                continue;
+            }
+            if ping_ok {
+                master.last_ok_ping = Instant::now();
+            }
+            if let Some(info) = &master_info {
+                let replica_lines = info.iter().filter(|(field, _)| field.starts_with("slave") && field[5..].parse::<usize>().is_ok());
+                for (replica_ip, replica_port) in replica_lines.filter_map(|(_, line)| parse_replica_line(line)) {
+                    master.replica_mut(&replica_ip, &replica_port);
+                }
+            }
+            for (replica_ip, replica_port, info) in replica_infos {
+                let replica = master.replica_mut(&replica_ip, &replica_port);
+                let info = match info {
+                    Some(info) => info,
+                    None => continue,
+                };
+                replica.last_ok_ping = Some(Instant::now());
+                replica.is_master = info.get("role").map(String::as_str) == Some("master");
+                replica.link_up = info.get("master_link_status").map(String::as_str) == Some("up");
+                replica.repl_offset = info.get("slave_repl_offset").and_then(|offset| offset.parse().ok()).unwrap_or(0);
+                if replica.is_master {
+                    strays.push((replica_ip, replica_port));
+                }
+            }
+            let subjectively_down = master.last_ok_ping.elapsed() > master.down_after;
+            if subjectively_down != master.subjectively_down {
+                println!("{}sdown master {} {} {}", if subjectively_down {"+"} else {"-"}, name, ip, port);
+                master.subjectively_down = subjectively_down;
+                if !subjectively_down {
+                    master.objectively_down = false;
+                }
+            }
+            if subjectively_down || master.election.is_some() {
+                strays.clear();
+            }
+        }
+
+        // A returning old master, or any replica promoted outside of a failover, follows the current master again
+        for (stray_ip, stray_port) in strays {
+            println!("+convert-to-slave slave {stray_ip}:{stray_port} @ {name} {ip} {port}");
+            let _ = send_command(&stray_ip, &stray_port, vec![b"REPLICAOF", ip.as_bytes(), port.as_bytes()]).await;
+        }
+        {
+            let mut sentinel = SENTINEL.lock().await;
+            if let Some(master) = sentinel.masters.iter_mut().find(|master| master.name == name) {
+                for instance in master.instances() {
+                    if master.hello_listeners.insert(instance.clone()) {
+                        tokio::spawn(listen_for_hellos(name.clone(), instance.0, instance.1));
+                    }
+                }
+            }
+        }
+        if last_hello.elapsed() >= HELLO_PERIOD {
+            last_hello = Instant::now();
+            send_hello(&name).await;
+        }
+        check_objectively_down(&name).await;
+        if let Some(epoch) = try_failover(&name).await {
+            if let Err(e) = failover(&name, epoch).await {
+                println!("-failover-abort {name}: {e}");
+                // Like redis the election ends with the failover, the next one waits for twice the failover timeout
+                let mut sentinel = SENTINEL.lock().await;
+                if let Some(master) = sentinel.masters.iter_mut().find(|master| master.name == name) {
+                    master.election = None;
+                }
+            }
+        }
+    }
+}
+
+async fn send_hello(name: &str) {
+    let (instances, hello) = {
+        let sentinel = SENTINEL.lock().await;
+        let master = match sentinel.masters.iter().find(|master| master.name == name) {
+            Some(master) => master,
+            None => return,
+        };
+        let hello = format!("{},{},{},{},{},{},{},{}",
+            sentinel.ip, sentinel.port, sentinel.run_id, sentinel.current_epoch,
+            master.name, master.ip, master.port, master.config_epoch);
+        (master.instances(), hello)
+    };
+    for (ip, port) in instances {
+        let _ = send_command(&ip, &port, vec![b"PUBLISH", HELLO_CHANNEL, hello.as_bytes()]).await;
+    }
+}
+
+// Follows the hello channel on the current master to learn about other sentinels and newer configurations
+async fn listen_for_hellos(name: String, ip: String, port: String) {
+    loop {
+        let _ = follow_hello_channel(&ip, &port).await;
+        sleep(PING_PERIOD).await;
+        let mut sentinel = SENTINEL.lock().await;
+        let master = match sentinel.masters.iter_mut().find(|master| master.name == name) {
+            Some(master) => master,
+            None => return,
+        };
+        if !master.instances().contains(&(ip.clone(), port.clone())) {
+            master.hello_listeners.remove(&(ip, port));
+            return;
+        }
+    }
+}
+
+async fn follow_hello_channel(ip: &str, port: &str) -> Result<(), String> {
+    let stream = TcpStream::connect(format!("{ip}:{port}")).await.map_err(|e| e.to_string())?;
+    let mut resp_stream_handler = RespStreamHandler::new(stream);
+    resp_stream_handler.write_all(&serialize_command(vec![b"SUBSCRIBE".to_vec(), HELLO_CHANNEL.to_vec()])).await.map_err(|e| e.to_string())?;
+    loop {
+        let message = match resp_stream_handler.deserialize().await.map_err(|e| e.to_string())? {
+            (RespDatatype::Array(message), _) => message,
+            _ => continue,
+        };
+        if let [RespDatatype::BulkString(kind), _, RespDatatype::BulkString(hello)] = &message[..] {
+            if kind == b"message" {
+                process_hello(&String::from_utf8_lossy(hello)).await;
+            }
+        }
+    }
+}
+
+async fn process_hello(hello: &str) {
+    let parts: Vec<&str> = hello.split(',').collect();
+    let [ip, port, run_id, current_epoch, name, master_ip, master_port, master_config_epoch] = parts[..] else {
+        return;
+    };
+    let (Ok(current_epoch), Ok(master_config_epoch)) = (current_epoch.parse::<u64>(), master_config_epoch.parse::<u64>()) else {
+        return;
+    };
+    let mut sentinel = SENTINEL.lock().await;
+    if run_id == sentinel.run_id {
+        return;
+    }
+    if current_epoch > sentinel.current_epoch {
+        println!("+new-epoch {current_epoch}");
+        sentinel.current_epoch = current_epoch;
+    }
+    let master = match sentinel.masters.iter_mut().find(|master| master.name == name) {
+        Some(master) => master,
+        None => return,
+    };
+    // A restarted sentinel comes back with a new run id on the same address
+    master.sentinels.retain(|known_id, known| known_id == run_id || known.ip != ip || known.port != port);
+    if !master.sentinels.contains_key(run_id) {
+        println!("+sentinel sentinel {run_id} {ip} {port} @ {name} {} {}", master.ip, master.port);
+    }
+    master.sentinels.insert(run_id.to_string(), KnownSentinel {ip: ip.to_string(), port: port.to_string(), last_hello: Instant::now()});
+    if master_config_epoch > master.config_epoch {
+        if master.ip != master_ip || master.port != master_port {
+            println!("+config-update-from sentinel {run_id} {ip} {port} @ {name}");
+            master.switch_to(master_ip.to_string(), master_port.to_string(), master_config_epoch);
+        } else {
+            master.config_epoch = master_config_epoch;
+        }
+    }
+}
+
+// Asks the other sentinels, with the given run id to also request their vote
+async fn ask_sentinels(name: &str, run_id: &str) -> Vec<(bool, String, u64)> {
+    let (ip, port, current_epoch, peers) = {
+        let sentinel = SENTINEL.lock().await;
+        let master = match sentinel.masters.iter().find(|master| master.name == name) {
+            Some(master) => master,
+            None => return Vec::new(),
+        };
+        let peers: Vec<(String, String)> = master.sentinels.values().map(|peer| (peer.ip.clone(), peer.port.clone())).collect();
+        (master.ip.clone(), master.port.clone(), sentinel.current_epoch.to_string(), peers)
+    };
+    let mut replies = Vec::new();
+    for (peer_ip, peer_port) in peers {
+        let argv: Vec<&[u8]> = vec![b"SENTINEL", b"is-master-down-by-addr", ip.as_bytes(), port.as_bytes(), current_epoch.as_bytes(), run_id.as_bytes()];
+        if let Ok(RespDatatype::Array(reply)) = send_command(&peer_ip, &peer_port, argv).await {
+            if let [RespDatatype::Integer(down), RespDatatype::BulkString(leader), RespDatatype::Integer(leader_epoch)] = &reply[..] {
+                replies.push((*down == 1, String::from_utf8_lossy(leader).to_string(), *leader_epoch as u64));
+            }
+        }
+    }
+    replies
+}
+
+async fn check_objectively_down(name: &str) {
+    let subjectively_down = SENTINEL.lock().await.masters.iter().any(|master| master.name == name && master.subjectively_down);
+    if !subjectively_down {
+        return;
+    }
+    let agreeing = ask_sentinels(name, "*").await.iter().filter(|(down, _, _)| *down).count();
+    let mut sentinel = SENTINEL.lock().await;
+    if let Some(master) = sentinel.masters.iter_mut().find(|master| master.name == name) {
+        let objectively_down = master.subjectively_down && agreeing + 1 >= master.quorum;
+        if objectively_down != master.objectively_down {
+            println!("{}odown master {} {} {} #quorum {}/{}", if objectively_down {"+"} else {"-"}, name, master.ip, master.port, agreeing + 1, master.quorum);
+            master.objectively_down = objectively_down;
+        }
+    }
+}
+
+// Runs the leader election for an objectively down master, returns the epoch once this sentinel won it
+async fn try_failover(name: &str) -> Option<u64> {
+    let run_id = {
+        let mut sentinel = SENTINEL.lock().await;
+        let current_epoch = sentinel.current_epoch;
+        let run_id = sentinel.run_id.clone();
+        let master = sentinel.masters.iter_mut().find(|master| master.name == name)?;
+        if !master.objectively_down {
+            master.election = None;
+            return None;
+        }
+        if master.election.is_none() {
+            if master.failover_started.is_some_and(|started| started.elapsed() < master.failover_timeout * 2) {
+                return None;
+            }
+            let epoch = current_epoch + 1;
+            println!("+new-epoch {epoch}");
+            println!("+try-failover master {} {} {}", name, master.ip, master.port);
+            master.election = Some((epoch, Instant::now()));
+            master.failover_started = Some(Instant::now());
+            if master.leader_epoch < epoch {
+                master.leader = Some(run_id.clone());
+                master.leader_epoch = epoch;
+            }
+            sentinel.current_epoch = epoch;
+        }
+        run_id
+    };
+    let replies = ask_sentinels(name, &run_id).await;
+
+    let mut sentinel = SENTINEL.lock().await;
+    let master = sentinel.masters.iter_mut().find(|master| master.name == name)?;
+    let (epoch, started) = master.election?;
+    let own_vote = master.leader.as_deref() == Some(run_id.as_str()) && master.leader_epoch == epoch;
+    let votes = replies.iter().filter(|(_, leader, leader_epoch)| *leader == run_id && *leader_epoch == epoch).count() + own_vote as usize;
+    // A majority of all the sentinels, this one included, and at least the quorum
+    let voters = master.sentinels.len() + 1;
+    let needed = master.quorum.max(voters / 2 + 1);
+    if votes >= needed {
+        println!("+elected-leader master {} {} {} with {votes} votes", name, master.ip, master.port);
+        return Some(epoch);
+    }
+    if started.elapsed() > master.failover_timeout.min(ELECTION_TIMEOUT) {
+        println!("-failover-abort-not-elected master {} {} {}", name, master.ip, master.port);
+        master.election = None;
+    }
+    None
+}
+
+// Promotes the most up to date reachable replica and points the other replicas at it
+async fn failover(name: &str, epoch: u64) -> Result<(), String> {
+    let (candidate, others, failover_timeout) = {
+        let sentinel = SENTINEL.lock().await;
+        let master = sentinel.masters.iter().find(|master| master.name == name).ok_or("Master is no longer monitored")?;
+        let reachable = |replica: &&KnownReplica| replica.last_ok_ping.is_some_and(|last| last.elapsed() < master.down_after) && !replica.is_master;
+        let candidate = master.replicas.iter()
+            .filter(reachable)
+            .max_by(|a, b| a.repl_offset.cmp(&b.repl_offset).then_with(|| (&b.ip, &b.port).cmp(&(&a.ip, &a.port))))
+            .map(|replica| (replica.ip.clone(), replica.port.clone()))
+            .ok_or("No suitable replica to promote")?;
+        let others: Vec<(String, String)> = master.replicas.iter()
+            .filter(|replica| (&replica.ip, &replica.port) != (&candidate.0, &candidate.1))
+            .map(|replica| (replica.ip.clone(), replica.port.clone()))
+            .collect();
+        (candidate, others, master.failover_timeout)
+    };
+    let (ip, port) = candidate;
+    println!("+selected-slave slave {ip}:{port} @ {name}");
+    println!("+failover-state-send-slaveof-noone slave {ip}:{port} @ {name}");
+    send_command(&ip, &port, vec![b"REPLICAOF", b"NO", b"ONE"]).await?;
+    let deadline = Instant::now() + failover_timeout;
+    loop {
+        let role = fetch_info(&ip, &port).await.and_then(|info| info.get("role").cloned());
+        if role.as_deref() == Some("master") {
+            break;
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Replica {ip}:{port} wasn't promoted in time"));
+        }
+        sleep(PING_PERIOD).await;
+    }
+    println!("+promoted-slave slave {ip}:{port} @ {name}");
+    {
+        let mut sentinel = SENTINEL.lock().await;
+        let master = sentinel.masters.iter_mut().find(|master| master.name == name).ok_or("Master is no longer monitored")?;
+        master.switch_to(ip.clone(), port.clone(), epoch);
+    }
+    // The other sentinels pick the new configuration up from the hello channel
+    send_hello(name).await;
+    for (other_ip, other_port) in others {
+        println!("+slave-reconf-sent slave {other_ip}:{other_port} @ {name}");
+        let _ = send_command(&other_ip, &other_port, vec![b"REPLICAOF", ip.as_bytes(), port.as_bytes()]).await;
+    }
+    println!("+failover-end master {name} {ip} {port}");
+    Ok(())
+}
+
+async fn handle_sentinel_client(stream: TcpStream) {
+    let mut resp_stream_handler = RespStreamHandler::new(stream);
+    loop {
+        let resp_object = match resp_stream_handler.deserialize().await {
+            Ok((resp_object, _)) => resp_object,
+            Err(_) => break,
+        };
+        let argv: Vec<Vec<u8>> = match resp_object {
+            RespDatatype::Array(array) => array.into_iter()
+                .filter_map(|argument| match argument {
+                    RespDatatype::BulkString(argument) => Some(argument),
+                    _ => None,
+                })
+                .collect(),
+            _ => continue,
+        };
+        let reply = interpret_sentinel_command(argv).await;
+        if resp_stream_handler.write_all(&serialize(&reply)).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn interpret_sentinel_command(argv: Vec<Vec<u8>>) -> RespDatatype {
+    let argv: Vec<String> = argv.into_iter().map(|argument| String::from_utf8_lossy(&argument).to_string()).collect();
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let command = argv.first().map(|command| command.to_ascii_uppercase()).unwrap_or_default();
+    let subcommand = argv.get(1).map(|subcommand| subcommand.to_ascii_lowercase()).unwrap_or_default();
+    let mut sentinel = SENTINEL.lock().await;
+    let unknown_master = RespDatatype::SimpleError(String::from("ERR No such master with that name"));
+    match (command.as_str(), subcommand.as_str(), &argv[..]) {
+        ("PING", _, _) => RespDatatype::SimpleString(String::from("PONG")),
+        ("SENTINEL", "myid", [_, _]) => RespDatatype::BulkString(sentinel.run_id.clone().into_bytes()),
+        ("SENTINEL", "masters", [_, _]) => RespDatatype::Array(sentinel.masters.iter().map(|master| RespDatatype::Array(master.fields())).collect()),
+        ("SENTINEL", "master", [_, _, name]) => match sentinel.masters.iter().find(|master| master.name == *name) {
+            Some(master) => RespDatatype::Array(master.fields()),
+            None => unknown_master,
+        },
+        ("SENTINEL", "replicas" | "slaves", [_, _, name]) => match sentinel.masters.iter().find(|master| master.name == *name) {
+            Some(master) => RespDatatype::Array(master.replicas.iter().map(|replica| RespDatatype::Array(fields(vec![
+                ("name", format!("{}:{}", replica.ip, replica.port)),
+                ("ip", replica.ip.clone()),
+                ("port", replica.port.clone()),
+                ("flags", String::from(if replica.is_master {"master"} else {"slave"})),
+                ("master-link-status", String::from(if replica.link_up {"ok"} else {"err"})),
+                ("slave-repl-offset", replica.repl_offset.to_string()),
+            ]))).collect()),
+            None => unknown_master,
+        },
+        ("SENTINEL", "sentinels", [_, _, name]) => match sentinel.masters.iter().find(|master| master.name == *name) {
+            Some(master) => RespDatatype::Array(master.sentinels.iter().map(|(run_id, peer)| RespDatatype::Array(fields(vec![
+                ("name", run_id.clone()),
+                ("ip", peer.ip.clone()),
+                ("port", peer.port.clone()),
+                ("runid", run_id.clone()),
+                ("last-hello-message", peer.last_hello.elapsed().as_millis().to_string()),
+            ]))).collect()),
+            None => unknown_master,
+        },
+        ("SENTINEL", "get-master-addr-by-name", [_, _, name]) => match sentinel.masters.iter().find(|master| master.name == *name) {
+            Some(master) => RespDatatype::Array(vec![
+                RespDatatype::BulkString(master.ip.clone().into_bytes()),
+                RespDatatype::BulkString(master.port.clone().into_bytes()),
+            ]),
+            None => RespDatatype::NullArray,
+        },
+        // Another sentinel asks whether the master looks down from here, and for this sentinel's vote when run_id isn't *
+        ("SENTINEL", "is-master-down-by-addr", [_, _, ip, port, epoch, run_id]) => {
+            let Ok(epoch) = epoch.parse::<u64>() else {
+                return RespDatatype::SimpleError(String::from("ERR invalid epoch"));
+            };
+            if *run_id != "*" && epoch > sentinel.current_epoch {
+                println!("+new-epoch {epoch}");
+                sentinel.current_epoch = epoch;
+            }
+            let master = match sentinel.masters.iter_mut().find(|master| master.ip == *ip && master.port == *port) {
+                Some(master) => master,
+                None => return RespDatatype::Array(vec![RespDatatype::Integer(0), RespDatatype::BulkString(b"*".to_vec()), RespDatatype::Integer(0)]),
+            };
+            let down = master.subjectively_down as i64;
+            if *run_id == "*" {
+                return RespDatatype::Array(vec![RespDatatype::Integer(down), RespDatatype::BulkString(b"*".to_vec()), RespDatatype::Integer(0)]);
+            }
+            // One vote per epoch, for the first sentinel that asks
+            if master.leader_epoch < epoch {
+                println!("+vote-for-leader {run_id} {epoch}");
+                master.leader = Some(run_id.to_string());
+                master.leader_epoch = epoch;
+                // Leaves the elected sentinel the time to complete the failover
+                master.failover_started = Some(Instant::now());
+            }
+            RespDatatype::Array(vec![
+                RespDatatype::Integer(down),
+                RespDatatype::BulkString(master.leader.clone().unwrap_or_default().into_bytes()),
+                RespDatatype::Integer(master.leader_epoch as i64),
+            ])
+        },
+        ("SENTINEL", _, _) => RespDatatype::SimpleError(format!("ERR Unknown sentinel subcommand or wrong number of arguments for '{subcommand}'")),
+        _ => RespDatatype::SimpleError(format!("ERR unknown command '{}'", argv.first().unwrap_or(&""))),
+    }
+}
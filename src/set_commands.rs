@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+use std::vec::IntoIter;
+
+use rand::seq::SliceRandom;
+
+use crate::{array, bulk_arguments, bulk_or_null, error, integer, modify_set, parse_cursor, parse_integer, parse_random_count, parse_scan_options, read_set, scan_page, scan_reply, store_value, wrong_arguments, Propagation, RedisCommand, RedisSet, RespDatatype, ScanOptions, Value, SYNTAX_ERROR};
+
+pub async fn execute_set_command(command: &[u8], array_iterator: IntoIter<RespDatatype>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let arguments = bulk_arguments(array_iterator);
+    match command {
+        b"SADD" => interpret_sadd(command, arguments, propagation).await,
+        b"SREM" => interpret_srem(command, arguments, propagation).await,
+        b"SISMEMBER" => interpret_sismember(command, arguments).await,
+        b"SMISMEMBER" => interpret_smismember(command, arguments).await,
+        b"SMEMBERS" => interpret_smembers(command, arguments).await,
+        b"SCARD" => interpret_scard(command, arguments).await,
+        b"SINTER" | b"SUNION" | b"SDIFF" => interpret_set_operation(command, arguments).await,
+        b"SINTERSTORE" | b"SUNIONSTORE" | b"SDIFFSTORE" => interpret_set_operation_store(command, arguments).await,
+        b"SINTERCARD" => interpret_sintercard(command, arguments).await,
+        b"SRANDMEMBER" => interpret_srandmember(command, arguments).await,
+        b"SPOP" => interpret_spop(command, arguments, propagation).await,
+        b"SMOVE" => interpret_smove(command, arguments, propagation).await,
+        b"SSCAN" => interpret_sscan(command, arguments).await,
+        _ => None,
+    }
+}
+
+async fn interpret_sadd(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, members) = match arguments.split_first() {
+        Some((key, members)) if !members.is_empty() => (key, members),
+        _ => return wrong_arguments(command),
+    };
+    let added = modify_set(key, Some(&members[0]), |set| {
+        members.iter().filter(|member| set.insert(member.to_vec())).count()
+    }).await;
+    match added {
+        Ok(added) => {
+            let added = added.unwrap_or(0);
+            if added == 0 {
+                propagation.suppress();
+            }
+            integer(added)
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_srem(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, members) = match arguments.split_first() {
+        Some((key, members)) if !members.is_empty() => (key, members),
+        _ => return wrong_arguments(command),
+    };
+    let removed = modify_set(key, None, |set| members.iter().filter(|member| set.remove(member)).count()).await;
+    match removed {
+        Ok(removed) => {
+            let removed = removed.unwrap_or(0);
+            if removed == 0 {
+                propagation.suppress();
+            }
+            integer(removed)
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_sismember(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, member] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_set(key, |set| set.contains(member)).await {
+        Ok(is_member) => integer(is_member.unwrap_or(false) as usize),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_smismember(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, members) = match arguments.split_first() {
+        Some((key, members)) if !members.is_empty() => (key, members),
+        _ => return wrong_arguments(command),
+    };
+    let is_member = read_set(key, |set| members.iter().map(|member| set.contains(member)).collect::<Vec<bool>>()).await;
+    match is_member {
+        Ok(is_member) => {
+            let is_member = is_member.unwrap_or_else(|| vec![false; members.len()]);
+            let replies = is_member.into_iter().map(|is_member| RespDatatype::Integer(is_member as i64)).collect();
+            Some(RedisCommand::RespDatatype(RespDatatype::Array(replies)))
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_smembers(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_set(key, RedisSet::members).await {
+        Ok(members) => array(members.unwrap_or_default()),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_scard(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_set(key, RedisSet::len).await {
+        Ok(length) => integer(length.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+// The sets stored at keys, a missing key counts as an empty set
+async fn read_sets(keys: &[Vec<u8>]) -> Result<Vec<RedisSet>, String> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        sets.push(read_set(key, RedisSet::clone).await?.unwrap_or_else(|| RedisSet::from_members(Vec::new())));
+    }
+    Ok(sets)
+}
+
+// SINTER, SUNION or SDIFF of the sets, `command` may be the *STORE variant
+fn combine_sets(command: &[u8], sets: &[RedisSet]) -> Vec<Vec<u8>> {
+    let Some((first, others)) = sets.split_first() else {
+        return Vec::new();
+    };
+    match command {
+        b"SINTER" | b"SINTERSTORE" => first.members().into_iter().filter(|member| others.iter().all(|set| set.contains(member))).collect(),
+        b"SDIFF" | b"SDIFFSTORE" => first.members().into_iter().filter(|member| !others.iter().any(|set| set.contains(member))).collect(),
+        _ => {
+            let mut seen = HashSet::new();
+            sets.iter().flat_map(RedisSet::members).filter(|member| seen.insert(member.clone())).collect()
+        },
+    }
+}
+
+async fn interpret_set_operation(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    if arguments.is_empty() {
+        return wrong_arguments(command);
+    }
+    match read_sets(&arguments).await {
+        Ok(sets) => array(combine_sets(command, &sets)),
+        Err(e) => error(e),
+    }
+}
+
+// The result replaces destination whatever it held, an empty result deletes it
+async fn interpret_set_operation_store(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (destination, keys) = match arguments.split_first() {
+        Some((destination, keys)) if !keys.is_empty() => (destination, keys),
+        _ => return wrong_arguments(command),
+    };
+    let members = match read_sets(keys).await {
+        Ok(sets) => combine_sets(command, &sets),
+        Err(e) => return error(e),
+    };
+    let length = members.len();
+    store_value(destination, (length > 0).then(|| Value::Set(RedisSet::from_members(members)))).await;
+    integer(length)
+}
+
+// SINTERCARD numkeys key [key ...] [LIMIT limit], a limit of 0 counts the whole intersection
+async fn interpret_sintercard(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let Some((numkeys, rest)) = arguments.split_first() else {
+        return wrong_arguments(command);
+    };
+    let numkeys = match parse_integer(numkeys) {
+        Ok(numkeys) if numkeys > 0 => numkeys as usize,
+        _ => return error("ERR numkeys should be greater than 0"),
+    };
+    if numkeys > rest.len() {
+        return error("ERR Number of keys can't be greater than number of args");
+    }
+    let (keys, options) = rest.split_at(numkeys);
+    let limit = match options {
+        [] => 0,
+        [option, limit] if option.eq_ignore_ascii_case(b"LIMIT") => match parse_integer(limit) {
+            Ok(limit) if limit >= 0 => limit as usize,
+            Ok(_) => return error("ERR LIMIT can't be negative"),
+            Err(e) => return error(e),
+        },
+        _ => return error(SYNTAX_ERROR),
+    };
+    let sets = match read_sets(keys).await {
+        Ok(sets) => sets,
+        Err(e) => return error(e),
+    };
+    let cardinality = combine_sets(b"SINTER", &sets).len();
+    integer(if limit == 0 {cardinality} else {cardinality.min(limit)})
+}
+
+// SRANDMEMBER key [count], a negative count may return the same member more than once
+async fn interpret_srandmember(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, count) = match &arguments[..] {
+        [key] => (key, None),
+        [key, count] => match parse_random_count(count) {
+            Ok(count) => (key, Some(count)),
+            Err(e) => return error(e),
+        },
+        _ => return wrong_arguments(command),
+    };
+    let picked = read_set(key, |set| {
+        let members = set.members();
+        let rng = &mut rand::thread_rng();
+        match count {
+            None => members.choose(rng).cloned().into_iter().collect(),
+            Some(count) if count >= 0 => members.choose_multiple(rng, count as usize).cloned().collect(),
+            Some(count) => (0..count.unsigned_abs()).filter_map(|_| members.choose(rng).cloned()).collect(),
+        }
+    }).await;
+    let picked: Vec<Vec<u8>> = match picked {
+        Ok(picked) => picked.unwrap_or_default(),
+        Err(e) => return error(e),
+    };
+    match count {
+        None => bulk_or_null(picked.into_iter().next()),
+        Some(_) => array(picked),
+    }
+}
+
+async fn interpret_spop(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, count) = match &arguments[..] {
+        [key] => (key, None),
+        [key, count] => match parse_integer(count) {
+            Ok(count) if count >= 0 => (key, Some(count as usize)),
+            Ok(_) => return error("ERR value is out of range, must be positive"),
+            Err(e) => return error(e),
+        },
+        _ => return wrong_arguments(command),
+    };
+    let popped = modify_set(key, None, |set| {
+        let members = set.members();
+        let popped: Vec<Vec<u8>> = members.choose_multiple(&mut rand::thread_rng(), count.unwrap_or(1)).cloned().collect();
+        for member in popped.iter() {
+            set.remove(member);
+        }
+        popped
+    }).await;
+    let popped = match popped {
+        Ok(popped) => popped.unwrap_or_default(),
+        Err(e) => return error(e),
+    };
+    // The replicas remove the members that were picked here
+    if popped.is_empty() {
+        propagation.suppress();
+    } else {
+        propagation.rewrite([vec![b"SREM".to_vec(), key.clone()], popped.clone()].concat());
+    }
+    match count {
+        None => bulk_or_null(popped.into_iter().next()),
+        Some(_) => array(popped),
+    }
+}
+
+async fn interpret_smove(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [source, destination, member] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    // Both types are checked before anything moves
+    let is_member = match (read_set(source, |set| set.contains(member)).await, read_set(destination, |_| ()).await) {
+        (Ok(is_member), Ok(_)) => is_member.unwrap_or(false),
+        (Err(e), _) | (_, Err(e)) => return error(e),
+    };
+    if !is_member || source == destination {
+        propagation.suppress();
+        return integer(is_member as usize);
+    }
+    let moved = async {
+        modify_set(source, None, |set| set.remove(member)).await?;
+        modify_set(destination, Some(member), |set| set.insert(member.clone())).await
+    };
+    match moved.await {
+        Ok(_) => integer(1),
+        Err(e) => error(e),
+    }
+}
+
+fn scan_members<'a>(members: impl Iterator<Item = &'a [u8]>, cursor: u64, scan_options: &ScanOptions) -> (u64, Vec<Vec<u8>>) {
+    let (next_cursor, page) = scan_page(members.map(|member| (member, ())), cursor, scan_options);
+    (next_cursor, page.into_iter().map(|(member, _)| member.to_vec()).collect())
+}
+
+async fn interpret_sscan(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, cursor, options @ ..] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let cursor = match parse_cursor(cursor) {
+        Ok(cursor) => cursor,
+        Err(e) => return error(e),
+    };
+    let scan_options = match parse_scan_options(options, false) {
+        Ok(scan_options) => scan_options,
+        Err(e) => return error(e),
+    };
+    let scanned = read_set(key, |set| match set {
+        // Only the hash table can be big, the compact encodings are cheap to turn into strings
+        RedisSet::HashTable(members) => scan_members(members.iter().map(|member| &member[..]), cursor, &scan_options),
+        set => scan_members(set.members().iter().map(|member| &member[..]), cursor, &scan_options),
+    }).await;
+    match scanned {
+        Ok(scanned) => {
+            let (next_cursor, members) = scanned.unwrap_or_default();
+            scan_reply(next_cursor, members)
+        },
+        Err(e) => error(e),
+    }
+}
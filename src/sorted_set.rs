@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+
+// zset-max-listpack-entries and zset-max-listpack-value, past them redis switches to a skiplist
+const ZSET_MAX_LISTPACK_ENTRIES: usize = 128;
+const ZSET_MAX_LISTPACK_VALUE: usize = 64;
+
+// Like redis' ZSKIPLIST_MAXLEVEL and ZSKIPLIST_P
+const SKIPLIST_MAX_LEVEL: usize = 32;
+const SKIPLIST_P: f64 = 0.25;
+
+#[derive(Debug, Clone, Default)]
+struct Level {
+    forward: Option<usize>,
+    // Nodes skipped by following forward, what makes ranks O(log N)
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    member: Vec<u8>,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    // Whether the node sorts before score and member, scores first and member bytes on ties
+    fn precedes(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || (self.score == score && self.member[..] < *member)
+    }
+}
+
+// Members ordered by score, ties broken by member bytes, like redis' skiplist plus dict.
+// Nodes live in an arena indexed by position, the header is node 0
+#[derive(Debug, Clone)]
+pub struct SortedSet {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    level: usize,
+    scores: HashMap<Vec<u8>, f64>,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        let header = Node {member: Vec::new(), score: 0.0, backward: None, levels: vec![Level::default(); SKIPLIST_MAX_LEVEL]};
+        SortedSet {nodes: vec![header], free: Vec::new(), tail: None, level: 1, scores: HashMap::new()}
+    }
+}
+
+// Walks the members in score order, from either end
+pub struct Iter<'a> {
+    sorted_set: &'a SortedSet,
+    front: Option<usize>,
+    back: Option<usize>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a [u8], f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.sorted_set.nodes[self.front?];
+        self.front = node.levels[0].forward;
+        self.remaining -= 1;
+        Some((&node.member[..], node.score))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let node = &self.sorted_set.nodes[self.back?];
+        self.back = node.backward;
+        self.remaining -= 1;
+        Some((&node.member[..], node.score))
+    }
+}
+
+// One end of a BYSCORE range, parsed from "1.5", "(1.5", "-inf" or "+inf"
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    pub fn parse(bound: &[u8]) -> Option<Self> {
+        let (exclusive, score) = match bound.strip_prefix(b"(") {
+            Some(score) => (true, score),
+            None => (false, bound),
+        };
+        let score = String::from_utf8_lossy(score).parse::<f64>().ok().filter(|score| !score.is_nan())?;
+        Some(ScoreBound {score, exclusive})
+    }
+
+    fn above_min(&self, score: f64) -> bool {
+        if self.exclusive {score > self.score} else {score >= self.score}
+    }
+
+    fn below_max(&self, score: f64) -> bool {
+        if self.exclusive {score < self.score} else {score <= self.score}
+    }
+}
+
+// One end of a BYLEX range, parsed from "-", "+", "[member" or "(member"
+#[derive(Debug, Clone)]
+pub enum LexBound {
+    Min,
+    Max,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+impl LexBound {
+    pub fn parse(bound: &[u8]) -> Option<Self> {
+        match bound {
+            b"-" => Some(LexBound::Min),
+            b"+" => Some(LexBound::Max),
+            [b'[', member @ ..] => Some(LexBound::Inclusive(member.to_vec())),
+            [b'(', member @ ..] => Some(LexBound::Exclusive(member.to_vec())),
+            _ => None,
+        }
+    }
+
+    fn above_min(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= &bound[..],
+            LexBound::Exclusive(bound) => member > &bound[..],
+        }
+    }
+
+    fn below_max(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= &bound[..],
+            LexBound::Exclusive(bound) => member < &bound[..],
+        }
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    // Adds the member or moves it to score, returns its previous score
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), score);
+        match previous {
+            Some(previous) if previous == score => return Some(previous),
+            Some(previous) => self.delete_node(previous, &member),
+            None => (),
+        }
+        self.insert_node(member, score);
+        previous
+    }
+
+    // Returns whether the member was there
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.delete_node(score, member);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Members from the lowest score to the highest
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {sorted_set: self, front: self.nodes[0].levels[0].forward, back: self.tail, remaining: self.len()}
+    }
+
+    // count members from the one at rank on, rank 0 being the lowest score
+    pub fn range_by_rank(&self, rank: usize, count: usize) -> Iter<'_> {
+        let remaining = count.min(self.len().saturating_sub(rank));
+        let (front, back) = match remaining {
+            0 => (None, None),
+            remaining => (self.node_at_rank(rank), self.node_at_rank(rank + remaining - 1)),
+        };
+        Iter {sorted_set: self, front, back, remaining}
+    }
+
+    // 0 for the member with the lowest score
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        let (mut node, mut traversed) = (0, 0);
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[level].forward {
+                if !self.nodes[next].precedes(score, member) && self.nodes[next].member != member {
+                    break;
+                }
+                traversed += self.nodes[node].levels[level].span;
+                node = next;
+            }
+            if node != 0 && self.nodes[node].member == member {
+                return Some(traversed - 1);
+            }
+        }
+        None
+    }
+
+    // The members with a score between min and max, lowest first.
+    // Both ends are found in O(log N), so the count is known without walking the range
+    pub fn range_by_score(&self, min: &ScoreBound, max: &ScoreBound) -> Iter<'_> {
+        self.range(|node| !min.above_min(node.score), |node| max.below_max(node.score))
+    }
+
+    // The members between min and max in member order, only meaningful when all scores are equal
+    pub fn range_by_lex(&self, min: &LexBound, max: &LexBound) -> Iter<'_> {
+        self.range(|node| !min.above_min(&node.member), |node| max.below_max(&node.member))
+    }
+
+    // The members past the prefix `before` holds for, up to the end of the prefix `within` holds for
+    fn range(&self, before: impl Fn(&Node) -> bool, within: impl Fn(&Node) -> bool) -> Iter<'_> {
+        let (first, skipped) = self.last_of_prefix(before);
+        let (last, included) = self.last_of_prefix(within);
+        Iter {sorted_set: self, front: self.nodes[first].levels[0].forward, back: Some(last), remaining: included.saturating_sub(skipped)}
+    }
+
+    // The last node of the prefix of the members `holds` is true for and the length of that prefix, the header when it is empty
+    fn last_of_prefix(&self, holds: impl Fn(&Node) -> bool) -> (usize, usize) {
+        let (mut node, mut traversed) = (0, 0);
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[level].forward {
+                if !holds(&self.nodes[next]) {
+                    break;
+                }
+                traversed += self.nodes[node].levels[level].span;
+                node = next;
+            }
+        }
+        (node, traversed)
+    }
+
+    fn node_at_rank(&self, rank: usize) -> Option<usize> {
+        // Spans count from 1, the header being rank 0
+        let target = rank + 1;
+        let (mut node, mut traversed) = (0, 0);
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[level].forward {
+                if traversed + self.nodes[node].levels[level].span > target {
+                    break;
+                }
+                traversed += self.nodes[node].levels[level].span;
+                node = next;
+            }
+            if traversed == target {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    fn random_level() -> usize {
+        let mut level = 1;
+        while level < SKIPLIST_MAX_LEVEL && rand::random::<f64>() < SKIPLIST_P {
+            level += 1;
+        }
+        level
+    }
+
+    fn insert_node(&mut self, member: Vec<u8>, score: f64) {
+        // The last node before the new one on every level, and its rank
+        let mut update = [0; SKIPLIST_MAX_LEVEL];
+        let mut rank = [0; SKIPLIST_MAX_LEVEL];
+        let mut node = 0;
+        for level in (0..self.level).rev() {
+            rank[level] = if level == self.level - 1 {0} else {rank[level + 1]};
+            while let Some(next) = self.nodes[node].levels[level].forward {
+                if !self.nodes[next].precedes(score, &member) {
+                    break;
+                }
+                rank[level] += self.nodes[node].levels[level].span;
+                node = next;
+            }
+            update[level] = node;
+        }
+        let new_level = Self::random_level();
+        if new_level > self.level {
+            for level in self.level..new_level {
+                rank[level] = 0;
+                update[level] = 0;
+                self.nodes[0].levels[level].span = self.len() - 1;
+            }
+            self.level = new_level;
+        }
+        let new = Node {member, score, backward: (update[0] != 0).then_some(update[0]), levels: vec![Level::default(); new_level]};
+        let new = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = new;
+                index
+            },
+            None => {
+                self.nodes.push(new);
+                self.nodes.len() - 1
+            },
+        };
+        for level in 0..new_level {
+            let previous = update[level];
+            self.nodes[new].levels[level].forward = self.nodes[previous].levels[level].forward;
+            self.nodes[previous].levels[level].forward = Some(new);
+            self.nodes[new].levels[level].span = self.nodes[previous].levels[level].span - (rank[0] - rank[level]);
+            self.nodes[previous].levels[level].span = rank[0] - rank[level] + 1;
+        }
+        for (level, previous) in update.iter().enumerate().take(self.level).skip(new_level) {
+            self.nodes[*previous].levels[level].span += 1;
+        }
+        match self.nodes[new].levels[0].forward {
+            Some(next) => self.nodes[next].backward = Some(new),
+            None => self.tail = Some(new),
+        }
+    }
+
+    fn delete_node(&mut self, score: f64, member: &[u8]) {
+        let mut update = [0; SKIPLIST_MAX_LEVEL];
+        let mut node = 0;
+        for level in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[level].forward {
+                if !self.nodes[next].precedes(score, member) {
+                    break;
+                }
+                node = next;
+            }
+            update[level] = node;
+        }
+        let Some(target) = self.nodes[node].levels[0].forward else {
+            return;
+        };
+        for (level, previous) in update.iter().enumerate().take(self.level) {
+            if self.nodes[*previous].levels[level].forward == Some(target) {
+                self.nodes[*previous].levels[level].span += self.nodes[target].levels[level].span;
+                self.nodes[*previous].levels[level].span -= 1;
+                self.nodes[*previous].levels[level].forward = self.nodes[target].levels[level].forward;
+            } else {
+                self.nodes[*previous].levels[level].span -= 1;
+            }
+        }
+        match self.nodes[target].levels[0].forward {
+            Some(next) => self.nodes[next].backward = self.nodes[target].backward,
+            None => self.tail = self.nodes[target].backward,
+        }
+        while self.level > 1 && self.nodes[0].levels[self.level - 1].forward.is_none() {
+            self.level -= 1;
+        }
+        // The slot is reused by the next insert
+        self.nodes[target] = Node {member: Vec::new(), score: 0.0, backward: None, levels: Vec::new()};
+        self.free.push(target);
+    }
+
+    // Encodings are not tracked, this is the one redis would have picked for the contents
+    pub fn encoding(&self) -> &'static str {
+        if self.len() > ZSET_MAX_LISTPACK_ENTRIES || self.scores.keys().any(|member| member.len() > ZSET_MAX_LISTPACK_VALUE) {
+            "skiplist"
+        } else {
+            "listpack"
+        }
+    }
+}
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::vec::IntoIter;
+
+use rand::seq::SliceRandom;
+
+use crate::sorted_set::Iter;
+use crate::{array, bulk_arguments, bulk_or_null, error, index_range, integer, modify_sorted_set, parse_integer, parse_random_count, parse_timeout, pop_or_block, read_set, read_sorted_set, store_value, wrong_arguments, BlockedPop, LexBound, Propagation, RedisCommand, RespDatatype, ScoreBound, SortedSet, Value, SYNTAX_ERROR};
+
+const NOT_A_FLOAT: &str = "ERR value is not a valid float";
+const NAN_SCORE: &str = "ERR resulting score is not a number (NaN)";
+
+pub async fn execute_sorted_set_command(command: &[u8], array_iterator: IntoIter<RespDatatype>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let arguments = bulk_arguments(array_iterator);
+    match command {
+        b"ZADD" => interpret_zadd(command, arguments, propagation).await,
+        b"ZINCRBY" => interpret_zincrby(command, arguments).await,
+        b"ZREM" => interpret_zrem(command, arguments, propagation).await,
+        b"ZREMRANGEBYRANK" | b"ZREMRANGEBYSCORE" | b"ZREMRANGEBYLEX" => interpret_zremrange(command, arguments, propagation).await,
+        b"ZCARD" => interpret_zcard(command, arguments).await,
+        b"ZSCORE" => interpret_zscore(command, arguments).await,
+        b"ZRANGE" => interpret_zrange(command, arguments).await,
+        b"ZRANK" | b"ZREVRANK" => interpret_zrank(command, arguments).await,
+        b"ZCOUNT" | b"ZLEXCOUNT" => interpret_zcount(command, arguments).await,
+        b"ZUNIONSTORE" | b"ZINTERSTORE" => interpret_zstore(command, arguments).await,
+        b"ZPOPMIN" | b"ZPOPMAX" => interpret_zpop(command, arguments, propagation).await,
+        b"BZPOPMIN" | b"BZPOPMAX" => interpret_bzpop(command, arguments, propagation).await,
+        b"ZRANDMEMBER" => interpret_zrandmember(command, arguments).await,
+        _ => None,
+    }
+}
+
+// Scores may be infinite but never NaN
+fn parse_score(argument: &[u8]) -> Option<f64> {
+    String::from_utf8_lossy(argument).parse::<f64>().ok().filter(|score| !score.is_nan())
+}
+
+fn score_bytes(score: f64) -> Vec<u8> {
+    score.to_string().into_bytes()
+}
+
+// Members each followed by their score when with_scores is set, the RESP2 way of replying with scores
+fn scored_array(members: Vec<(Vec<u8>, f64)>, with_scores: bool) -> Option<RedisCommand> {
+    let mut elements = Vec::new();
+    for (member, score) in members {
+        elements.push(member);
+        if with_scores {
+            elements.push(score_bytes(score));
+        }
+    }
+    array(elements)
+}
+
+#[derive(Default)]
+struct ZaddOptions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+// ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]
+async fn interpret_zadd(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, mut rest) = match arguments.split_first() {
+        Some((key, rest)) if rest.len() >= 2 => (key, rest),
+        _ => return wrong_arguments(command),
+    };
+    let mut options = ZaddOptions::default();
+    while let Some((option, remaining)) = rest.split_first() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"NX" => options.nx = true,
+            b"XX" => options.xx = true,
+            b"GT" => options.gt = true,
+            b"LT" => options.lt = true,
+            b"CH" => options.ch = true,
+            b"INCR" => options.incr = true,
+            _ => break,
+        }
+        rest = remaining;
+    }
+    if rest.is_empty() {
+        return wrong_arguments(command);
+    }
+    if rest.len() % 2 != 0 {
+        return error(SYNTAX_ERROR);
+    }
+    if options.nx && options.xx {
+        return error("ERR XX and NX options at the same time are not compatible");
+    }
+    if [options.gt, options.lt, options.nx].iter().filter(|set| **set).count() > 1 {
+        return error("ERR GT, LT, and/or NX options at the same time are not compatible");
+    }
+    if options.incr && rest.len() > 2 {
+        return error("ERR INCR option supports a single increment-element pair");
+    }
+    let mut pairs = Vec::with_capacity(rest.len() / 2);
+    for pair in rest.chunks_exact(2) {
+        let Some(score) = parse_score(&pair[0]) else {
+            return error(NOT_A_FLOAT);
+        };
+        pairs.push((score, pair[1].clone()));
+    }
+    // Added and updated members, and the score of the last member for INCR, None if an option skipped it
+    let changed = modify_sorted_set(key, !options.xx, |sorted_set| {
+        let (mut added, mut updated, mut incremented) = (0, 0, None);
+        for (score, member) in pairs {
+            incremented = None;
+            match sorted_set.score(&member) {
+                Some(_) if options.nx => continue,
+                Some(current) => {
+                    let score = if options.incr {current + score} else {score};
+                    if score.is_nan() {
+                        return Err(NAN_SCORE);
+                    }
+                    if (options.gt && score <= current) || (options.lt && score >= current) {
+                        continue;
+                    }
+                    if score != current {
+                        sorted_set.insert(member, score);
+                        updated += 1;
+                    }
+                    incremented = Some(score);
+                },
+                None if options.xx => continue,
+                None => {
+                    sorted_set.insert(member, score);
+                    added += 1;
+                    incremented = Some(score);
+                },
+            }
+        }
+        Ok((added, updated, incremented))
+    }).await;
+    let (added, updated, incremented) = match changed {
+        Ok(Some(Ok(changed))) => changed,
+        Ok(Some(Err(e))) => return error(e),
+        Ok(None) => (0, 0, None),
+        Err(e) => return error(e),
+    };
+    if added + updated == 0 {
+        propagation.suppress();
+    }
+    if options.incr {
+        return bulk_or_null(incremented.map(score_bytes));
+    }
+    integer(if options.ch {added + updated} else {added})
+}
+
+async fn interpret_zincrby(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, increment, member] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let Some(increment) = parse_score(increment) else {
+        return error(NOT_A_FLOAT);
+    };
+    let score = modify_sorted_set(key, true, |sorted_set| {
+        let score = sorted_set.score(member).unwrap_or(0.0) + increment;
+        if score.is_nan() {
+            return Err(NAN_SCORE);
+        }
+        sorted_set.insert(member.clone(), score);
+        Ok(score)
+    }).await;
+    match score {
+        Ok(Some(Ok(score))) => Some(RedisCommand::BulkString(score_bytes(score))),
+        Ok(Some(Err(e))) => error(e),
+        Ok(None) => error("ERR no such key"),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_zrem(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, members) = match arguments.split_first() {
+        Some((key, members)) if !members.is_empty() => (key, members),
+        _ => return wrong_arguments(command),
+    };
+    let removed = modify_sorted_set(key, false, |sorted_set| members.iter().filter(|member| sorted_set.remove(member)).count()).await;
+    match removed {
+        Ok(removed) => {
+            let removed = removed.unwrap_or(0);
+            if removed == 0 {
+                propagation.suppress();
+            }
+            integer(removed)
+        },
+        Err(e) => error(e),
+    }
+}
+
+// What ZRANGE and friends select, by rank, by score or by member
+enum Range {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RangeKind {
+    Rank,
+    Score,
+    Lex,
+}
+
+impl Range {
+    // start and stop are min and max, except for REV ranges by score or member where the caller swaps them
+    fn parse(kind: RangeKind, start: &[u8], stop: &[u8]) -> Result<Self, &'static str> {
+        match kind {
+            RangeKind::Rank => Ok(Range::Rank(parse_integer(start)?, parse_integer(stop)?)),
+            RangeKind::Score => match (ScoreBound::parse(start), ScoreBound::parse(stop)) {
+                (Some(min), Some(max)) => Ok(Range::Score(min, max)),
+                _ => Err("ERR min or max is not a float"),
+            },
+            RangeKind::Lex => match (LexBound::parse(start), LexBound::parse(stop)) {
+                (Some(min), Some(max)) => Ok(Range::Lex(min, max)),
+                _ => Err("ERR min or max not valid string range item"),
+            },
+        }
+    }
+
+    // The selected members in score order. With rev ranks count from the highest score
+    fn members<'a>(&self, sorted_set: &'a SortedSet, rev: bool) -> Iter<'a> {
+        match self {
+            Range::Rank(start, stop) => match index_range(*start, *stop, sorted_set.len()) {
+                Some((start, stop)) if rev => sorted_set.range_by_rank(sorted_set.len() - 1 - stop, stop - start + 1),
+                Some((start, stop)) => sorted_set.range_by_rank(start, stop - start + 1),
+                None => sorted_set.range_by_rank(0, 0),
+            },
+            Range::Score(min, max) => sorted_set.range_by_score(min, max),
+            Range::Lex(min, max) => sorted_set.range_by_lex(min, max),
+        }
+    }
+
+    // The selected members, from the highest score down when rev is set
+    fn select<'a>(&self, sorted_set: &'a SortedSet, rev: bool) -> Box<dyn Iterator<Item = (&'a [u8], f64)> + 'a> {
+        let members = self.members(sorted_set, rev);
+        if rev {Box::new(members.rev())} else {Box::new(members)}
+    }
+}
+
+fn owned(members: Vec<(&[u8], f64)>) -> Vec<(Vec<u8>, f64)> {
+    members.into_iter().map(|(member, score)| (member.to_vec(), score)).collect()
+}
+
+// ZRANGE key start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+async fn interpret_zrange(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, start, stop, options @ ..] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let (mut kind, mut rev, mut limit, mut with_scores) = (RangeKind::Rank, false, None, false);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"BYSCORE" => kind = RangeKind::Score,
+            b"BYLEX" => kind = RangeKind::Lex,
+            b"REV" => rev = true,
+            b"WITHSCORES" => with_scores = true,
+            b"LIMIT" => match (options.next().map(|offset| parse_integer(offset)), options.next().map(|count| parse_integer(count))) {
+                (Some(Ok(offset)), Some(Ok(count))) => limit = Some((offset, count)),
+                (Some(Err(e)), _) | (_, Some(Err(e))) => return error(e),
+                _ => return error(SYNTAX_ERROR),
+            },
+            _ => return error(SYNTAX_ERROR),
+        }
+    }
+    if limit.is_some() && kind == RangeKind::Rank {
+        return error("ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX");
+    }
+    if with_scores && kind == RangeKind::Lex {
+        return error("ERR syntax error, WITHSCORES not supported in combination with BYLEX");
+    }
+    let (min, max) = if rev && kind != RangeKind::Rank {(stop, start)} else {(start, stop)};
+    let range = match Range::parse(kind, min, max) {
+        Ok(range) => range,
+        Err(e) => return error(e),
+    };
+    let selected = read_sorted_set(key, |sorted_set| {
+        let selected = range.select(sorted_set, rev);
+        // A negative offset selects nothing and a negative count everything past the offset
+        owned(match limit {
+            Some((offset, _)) if offset < 0 => Vec::new(),
+            Some((offset, count)) if count >= 0 => selected.skip(offset as usize).take(count as usize).collect(),
+            Some((offset, _)) => selected.skip(offset as usize).collect(),
+            None => selected.collect(),
+        })
+    }).await;
+    match selected {
+        Ok(selected) => scored_array(selected.unwrap_or_default(), with_scores),
+        Err(e) => error(e),
+    }
+}
+
+// ZREMRANGEBYRANK key start stop, ZREMRANGEBYSCORE key min max and ZREMRANGEBYLEX key min max
+async fn interpret_zremrange(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let [key, start, stop] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let kind = match command {
+        b"ZREMRANGEBYRANK" => RangeKind::Rank,
+        b"ZREMRANGEBYSCORE" => RangeKind::Score,
+        _ => RangeKind::Lex,
+    };
+    let range = match Range::parse(kind, start, stop) {
+        Ok(range) => range,
+        Err(e) => return error(e),
+    };
+    let removed = modify_sorted_set(key, false, |sorted_set| {
+        let selected = owned(range.members(sorted_set, false).collect());
+        for (member, _) in selected.iter() {
+            sorted_set.remove(member);
+        }
+        selected.len()
+    }).await;
+    match removed {
+        Ok(removed) => {
+            let removed = removed.unwrap_or(0);
+            if removed == 0 {
+                propagation.suppress();
+            }
+            integer(removed)
+        },
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_zcard(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_sorted_set(key, SortedSet::len).await {
+        Ok(length) => integer(length.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+async fn interpret_zscore(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, member] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    match read_sorted_set(key, |sorted_set| sorted_set.score(member)).await {
+        Ok(score) => bulk_or_null(score.flatten().map(score_bytes)),
+        Err(e) => error(e),
+    }
+}
+
+// ZRANK key member [WITHSCORE], ZREVRANK counts from the highest score
+async fn interpret_zrank(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, member, with_score) = match &arguments[..] {
+        [key, member] => (key, member, false),
+        [key, member, option] if option.eq_ignore_ascii_case(b"WITHSCORE") => (key, member, true),
+        [_, _, _] => return error(SYNTAX_ERROR),
+        _ => return wrong_arguments(command),
+    };
+    let ranked = read_sorted_set(key, |sorted_set| {
+        let rank = sorted_set.rank(member)?;
+        let rank = if command == b"ZREVRANK" {sorted_set.len() - 1 - rank} else {rank};
+        Some((rank, sorted_set.score(member)?))
+    }).await;
+    match ranked.map(Option::flatten) {
+        Ok(Some((rank, score))) if with_score => Some(RedisCommand::RespDatatype(RespDatatype::Array(vec![
+            RespDatatype::Integer(rank as i64),
+            RespDatatype::BulkString(score_bytes(score)),
+        ]))),
+        Ok(Some((rank, _))) => integer(rank),
+        Ok(None) if with_score => Some(RedisCommand::RespDatatype(RespDatatype::NullArray)),
+        Ok(None) => Some(RedisCommand::NullBulkString),
+        Err(e) => error(e),
+    }
+}
+
+// ZCOUNT key min max and ZLEXCOUNT key min max
+async fn interpret_zcount(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [key, min, max] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let kind = if command == b"ZCOUNT" {RangeKind::Score} else {RangeKind::Lex};
+    let range = match Range::parse(kind, min, max) {
+        Ok(range) => range,
+        Err(e) => return error(e),
+    };
+    match read_sorted_set(key, |sorted_set| range.members(sorted_set, false).len()).await {
+        Ok(count) => integer(count.unwrap_or(0)),
+        Err(e) => error(e),
+    }
+}
+
+// The members of a sorted set or a plain set, whose members all score 1 for ZUNIONSTORE and ZINTERSTORE
+async fn read_scored_members(key: &[u8]) -> Result<Option<Vec<(Vec<u8>, f64)>>, String> {
+    match read_sorted_set(key, |sorted_set| owned(sorted_set.iter().collect())).await {
+        Err(_) => read_set(key, |set| set.members().into_iter().map(|member| (member, 1.0)).collect()).await,
+        scored => scored,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    // inf - inf counts as 0, like in redis
+    fn apply(self, a: f64, b: f64) -> f64 {
+        match self {
+            Aggregate::Sum => Some(a + b).filter(|sum| !sum.is_nan()).unwrap_or(0.0),
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        }
+    }
+}
+
+// ZUNIONSTORE|ZINTERSTORE destination numkeys key [key ...] [WEIGHTS weight [weight ...]] [AGGREGATE SUM|MIN|MAX]
+async fn interpret_zstore(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let [destination, numkeys, rest @ ..] = &arguments[..] else {
+        return wrong_arguments(command);
+    };
+    let numkeys = match parse_integer(numkeys) {
+        Ok(numkeys) if numkeys > 0 => numkeys as usize,
+        Ok(_) => return error(format!("ERR at least 1 input key is needed for '{}' command", String::from_utf8_lossy(command).to_lowercase())),
+        Err(e) => return error(e),
+    };
+    if numkeys > rest.len() {
+        return error(SYNTAX_ERROR);
+    }
+    let (keys, options) = rest.split_at(numkeys);
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_uppercase().as_slice() {
+            b"WEIGHTS" => for weight in weights.iter_mut() {
+                match options.next().map(|argument| parse_score(argument)) {
+                    Some(Some(parsed)) => *weight = parsed,
+                    Some(None) => return error("ERR weight value is not a float"),
+                    None => return error(SYNTAX_ERROR),
+                }
+            },
+            b"AGGREGATE" => match options.next().map(|argument| argument.to_ascii_uppercase()).as_deref() {
+                Some(b"SUM") => aggregate = Aggregate::Sum,
+                Some(b"MIN") => aggregate = Aggregate::Min,
+                Some(b"MAX") => aggregate = Aggregate::Max,
+                _ => return error(SYNTAX_ERROR),
+            },
+            _ => return error(SYNTAX_ERROR),
+        }
+    }
+    let mut sources = Vec::with_capacity(numkeys);
+    for (key, weight) in keys.iter().zip(weights) {
+        match read_scored_members(key).await {
+            // 0 * inf counts as 0 too
+            Ok(members) => sources.push(members.unwrap_or_default().into_iter()
+                .map(|(member, score)| (member, Some(score * weight).filter(|score| !score.is_nan()).unwrap_or(0.0)))
+                .collect::<HashMap<Vec<u8>, f64>>()),
+            Err(e) => return error(e),
+        }
+    }
+    let mut result = SortedSet::default();
+    if command == b"ZUNIONSTORE" {
+        for source in sources {
+            for (member, score) in source {
+                let score = match result.score(&member) {
+                    Some(current) => aggregate.apply(current, score),
+                    None => score,
+                };
+                result.insert(member, score);
+            }
+        }
+    } else if let Some((first, others)) = sources.split_first() {
+        for (member, score) in first {
+            let scores: Option<Vec<f64>> = others.iter().map(|source| source.get(member).copied()).collect();
+            if let Some(scores) = scores {
+                result.insert(member.clone(), scores.into_iter().fold(*score, |total, score| aggregate.apply(total, score)));
+            }
+        }
+    }
+    let length = result.len();
+    store_value(destination, (length > 0).then_some(Value::SortedSet(result))).await;
+    integer(length)
+}
+
+// Pops the count members with the lowest scores, or the highest ones when max is set
+pub async fn pop_sorted_set(key: &[u8], max: bool, count: usize) -> Result<Vec<(Vec<u8>, f64)>, String> {
+    let popped = modify_sorted_set(key, false, |sorted_set| {
+        let popped = if max {
+            owned(sorted_set.iter().rev().take(count).collect())
+        } else {
+            owned(sorted_set.iter().take(count).collect())
+        };
+        for (member, _) in popped.iter() {
+            sorted_set.remove(member);
+        }
+        popped
+    }).await?;
+    Ok(popped.unwrap_or_default())
+}
+
+// ZPOPMIN|ZPOPMAX key [count]
+async fn interpret_zpop(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (key, count) = match &arguments[..] {
+        [key] => (key, 1),
+        [key, count] => match parse_integer(count) {
+            Ok(count) if count >= 0 => (key, count as usize),
+            Ok(_) => return error("ERR value is out of range, must be positive"),
+            Err(e) => return error(e),
+        },
+        _ => return wrong_arguments(command),
+    };
+    match pop_sorted_set(key, command == b"ZPOPMAX", count).await {
+        Ok(popped) => {
+            if popped.is_empty() {
+                propagation.suppress();
+            }
+            scored_array(popped, true)
+        },
+        Err(e) => error(e),
+    }
+}
+
+// BZPOPMIN|BZPOPMAX key [key ...] timeout
+async fn interpret_bzpop(command: &[u8], arguments: Vec<Vec<u8>>, propagation: &mut Propagation) -> Option<RedisCommand> {
+    let (timeout, keys) = match arguments.split_last() {
+        Some((timeout, keys)) if !keys.is_empty() => (timeout, keys),
+        _ => return wrong_arguments(command),
+    };
+    let timeout = match parse_timeout(timeout) {
+        Ok(timeout) => timeout,
+        Err(e) => return error(e),
+    };
+    let operation = BlockedPop::SortedSetPop {max: command == b"BZPOPMAX"};
+    pop_or_block(keys.to_vec(), operation, timeout, propagation).await
+}
+
+// ZRANDMEMBER key [count [WITHSCORES]], a negative count may return the same member more than once
+async fn interpret_zrandmember(command: &[u8], arguments: Vec<Vec<u8>>) -> Option<RedisCommand> {
+    let (key, count, with_scores) = match &arguments[..] {
+        [key] => (key, None, false),
+        [key, count] => (key, Some(count), false),
+        [key, count, option] if option.eq_ignore_ascii_case(b"WITHSCORES") => (key, Some(count), true),
+        [_, _, _] => return error(SYNTAX_ERROR),
+        _ => return wrong_arguments(command),
+    };
+    let count = match count.map(|count| parse_random_count(count)) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(e)) => return error(e),
+        None => None,
+    };
+    let picked = read_sorted_set(key, |sorted_set| {
+        let members: Vec<(&[u8], f64)> = sorted_set.iter().collect();
+        let rng = &mut rand::thread_rng();
+        owned(match count {
+            None => members.choose(rng).copied().into_iter().collect(),
+            Some(count) if count >= 0 => members.choose_multiple(rng, count as usize).copied().collect(),
+            Some(count) => (0..count.unsigned_abs()).filter_map(|_| members.choose(rng).copied()).collect(),
+        })
+    }).await;
+    let picked = match picked {
+        Ok(picked) => picked.unwrap_or_default(),
+        Err(e) => return error(e),
+    };
+    if count.is_none() {
+        return bulk_or_null(picked.into_iter().next().map(|(member, _)| member));
+    }
+    scored_array(picked, with_scores)
+}
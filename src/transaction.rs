@@ -0,0 +1,60 @@
+use crate::{command_flags, execute_transaction, interpret, is_known_command, RedisCommand, RespDatatype, CMD_NO_MULTI};
+
+// The MULTI state of a connection
+#[derive(Default)]
+pub struct Transaction {
+    // The commands queued since MULTI, None outside a transaction
+    queued: Option<Vec<(RespDatatype, Vec<u8>)>>,
+    // A command was refused while queueing, EXEC discards the transaction
+    aborted: bool,
+}
+
+impl Transaction {
+    pub async fn interpret(&mut self, resp_object: RespDatatype, buf: &Vec<u8>) -> Option<RedisCommand> {
+        let command = match &resp_object {
+            RespDatatype::Array(array) => match array.first() {
+                Some(RespDatatype::BulkString(command)) => command.to_ascii_uppercase(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let Some(queued) = self.queued.as_mut() else {
+            return match &command[..] {
+                b"MULTI" => {
+                    self.queued = Some(Vec::new());
+                    self.aborted = false;
+                    Some(RedisCommand::Ok)
+                },
+                b"EXEC" => Some(RedisCommand::Error(String::from("ERR EXEC without MULTI"))),
+                b"DISCARD" => Some(RedisCommand::Error(String::from("ERR DISCARD without MULTI"))),
+                _ => interpret(resp_object, buf).await,
+            };
+        };
+        match &command[..] {
+            b"MULTI" => Some(RedisCommand::Error(String::from("ERR MULTI calls can not be nested"))),
+            b"DISCARD" => {
+                self.queued = None;
+                Some(RedisCommand::Ok)
+            },
+            b"EXEC" => {
+                let queued = self.queued.take().unwrap_or_default();
+                if self.aborted {
+                    return Some(RedisCommand::Error(String::from("EXECABORT Transaction discarded because of previous errors.")));
+                }
+                execute_transaction(queued).await
+            },
+            _ if !is_known_command(&command) => {
+                self.aborted = true;
+                Some(RedisCommand::Error(format!("ERR unknown command '{}'", String::from_utf8_lossy(&command).to_ascii_lowercase())))
+            },
+            _ if command_flags(&command) & CMD_NO_MULTI != 0 => {
+                self.aborted = true;
+                Some(RedisCommand::Error(String::from("ERR Command not allowed inside a transaction")))
+            },
+            _ => {
+                queued.push((resp_object, buf.clone()));
+                Some(RedisCommand::SimpleString(b"QUEUED".to_vec()))
+            },
+        }
+    }
+}
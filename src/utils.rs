@@ -1,4 +1,4 @@
-use std::{ascii::escape_default, error::Error, str::FromStr};
+use std::{ascii::escape_default, error::Error, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
 
 use anyhow::anyhow;
 use rand::seq::SliceRandom;
@@ -38,4 +38,148 @@ where F: FromStr, <F as FromStr>::Err: std::error::Error {
         Ok(res) => Ok(res),
         Err(_) => Err(Box::from(anyhow!("Couldn't parse"))),
     }
-}
\ No newline at end of file
+}
+
+pub fn unix_time_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is set before the unix epoch")
+        .as_millis() as u64
+}
+
+// Parses memory amounts the way redis.conf does, e.g. "1048576", "64kb" or "1mb"
+pub fn parse_memory(value: &[u8]) -> Option<u64> {
+    let value = String::from_utf8_lossy(value).to_ascii_lowercase();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    amount.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+// Glob-style matching as redis does it for MATCH: *, ?, [abc], [^a-z] and \ escapes.
+// Only the last star is ever retried, so many stars can't make the match exponential
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // The pattern after the last star and the string position that star matched up to
+    let mut star: Option<(usize, usize)> = None;
+    loop {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        match string.get(s) {
+            Some(byte) => if let Some(length) = glob_match_one(&pattern[p..], *byte) {
+                p += length;
+                s += 1;
+                continue;
+            },
+            None if p == pattern.len() => return true,
+            None => (),
+        }
+        // The last star takes one more byte and the rest of the pattern is tried again
+        match star {
+            Some((after_star, matched_up_to)) if matched_up_to < string.len() => {
+                p = after_star;
+                s = matched_up_to + 1;
+                star = Some((after_star, s));
+            },
+            _ => return false,
+        }
+    }
+}
+
+// Matches byte against the pattern element at the start of pattern, returns the length of that element
+fn glob_match_one(pattern: &[u8], byte: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let (negated, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            // An unterminated class ends with the pattern
+            loop {
+                match class {
+                    [] => break,
+                    [b']', rest @ ..] => {
+                        class = rest;
+                        break;
+                    },
+                    [b'\\', escaped, rest @ ..] => {
+                        matched |= *escaped == byte;
+                        class = rest;
+                    },
+                    [start, b'-', end, rest @ ..] if *end != b']' => {
+                        matched |= (*start.min(end)..=*start.max(end)).contains(&byte);
+                        class = rest;
+                    },
+                    [other, rest @ ..] => {
+                        matched |= *other == byte;
+                        class = rest;
+                    },
+                }
+            }
+            (matched != negated).then_some(pattern.len() - class.len())
+        },
+        [b'\\', escaped, ..] => (*escaped == byte).then_some(2),
+        [other, ..] => (*other == byte).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h*o", b"hello"));
+        assert!(glob_match(b"h**o", b"ho"));
+        assert!(!glob_match(b"h*o", b"help"));
+        assert!(glob_match(b"h?llo", b"hallo"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+    }
+
+    #[test]
+    fn glob_match_classes() {
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"[a-c]", b"b"));
+        assert!(glob_match(b"[c-a]", b"b"));
+        assert!(!glob_match(b"[a-c]", b"d"));
+        assert!(glob_match(b"[\\]]", b"]"));
+        // An unterminated class ends with the pattern
+        assert!(glob_match(b"[ab", b"a"));
+    }
+
+    #[test]
+    fn glob_match_escapes() {
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"a\\?", b"a?"));
+    }
+
+    #[test]
+    fn glob_match_many_stars_without_a_match() {
+        let string = [b'a'; 40];
+        let pattern = [&b"*a"[..]; 20].concat();
+        assert!(!glob_match(&[&pattern[..], b"b"].concat(), &string));
+        assert!(glob_match(&[&pattern[..], b"*"].concat(), &string));
+        assert!(glob_match(b"*a*b*c*", b"xxaxxbxxcxx"));
+        assert!(!glob_match(b"*a*b*c*d", b"xxaxxbxxcxx"));
+    }
+}